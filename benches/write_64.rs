@@ -0,0 +1,93 @@
+//! Confirms the single-word store paths - `store_word`, and
+//! `SyncAccess::sync_write_64` for code generic over memories - don't
+//! touch the heap, and gives a rough idea of how fast they are. Through
+//! `Access::write_64` the word isn't put in a Vec any more, but async_trait
+//! still boxes the future, so that's one allocation a store: register
+//! spills wanting none should use the other two.
+use revproc1::utils::memory::{Access, SyncAccess, TreeMemory};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::time::Instant;
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const ITERATIONS: u64 = 100_000;
+
+/// Our futures never actually wait, so one poll does it.
+fn run_now<F: Future>(fut: F) -> F::Output {
+    let mut fut = std::pin::pin!(fut);
+    let mut cx = Context::from_waker(Waker::noop());
+    match fut.as_mut().poll(&mut cx) {
+        Poll::Ready(out) => out,
+        Poll::Pending => panic!("memory futures shouldn't block"),
+    }
+}
+
+fn main() {
+    let mut mem = TreeMemory::new();
+    // Fault the leaf in up front - that allocates, and should.
+    mem.store_word(0, 0).unwrap();
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        mem.store_word(i & 0xfff, i).unwrap();
+    }
+    let elapsed = start.elapsed();
+    let allocs = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!(
+        "store_word: {:?}/op, {} allocations",
+        elapsed / ITERATIONS as u32,
+        allocs
+    );
+    assert_eq!(allocs, 0);
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        mem.sync_write_64(i & 0xfff, i);
+    }
+    let elapsed = start.elapsed();
+    let allocs = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!(
+        "SyncAccess::sync_write_64: {:?}/op, {} allocations",
+        elapsed / ITERATIONS as u32,
+        allocs
+    );
+    assert_eq!(allocs, 0);
+
+    // The boxed future is all that's left through Access.
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        run_now(mem.write_64(i & 0xfff, i));
+    }
+    let elapsed = start.elapsed();
+    let allocs = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!(
+        "Access::write_64: {:?}/op, {} allocations",
+        elapsed / ITERATIONS as u32,
+        allocs
+    );
+    assert_eq!(allocs, ITERATIONS as usize);
+    let last = ITERATIONS - 1;
+    assert_eq!(run_now(mem.read_64(last & 0xfff)), last);
+}
@@ -0,0 +1,46 @@
+//! Drives the async `Access` API with no runtime at all - nothing in the
+//! library needs tokio unless you want `SharedMemory` or `PrefetchCache`.
+//! Build it without tokio to check:
+//! `cargo run --example block_on --no-default-features --features std`
+use revproc1::utils::memory::{copy_between, Access, TreeMemory};
+use revproc1::utils::vector_memory::VectorMemory;
+use std::future::Future;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake};
+use std::thread::{self, Thread};
+
+/// Wakes the thread blocked in block_on.
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Poll fut on this thread until it's done, sleeping while it's pending -
+/// all futures::executor::block_on does, near enough.
+fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = std::pin::pin!(fut);
+    let waker = Arc::new(Unpark(thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match fut.as_mut().poll(&mut cx) {
+            Poll::Ready(out) => return out,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+fn main() {
+    let mut mem = TreeMemory::new();
+    block_on(async {
+        mem.write(0x1000, &[1, 2, 3]).await;
+        mem.set_bit(0x1003, 7).await;
+        println!("read back {:?}", mem.read(0x1000, 4).await);
+
+        let mut copy = VectorMemory::new();
+        copy_between(&mut mem, &mut copy, 0x1000, 0, 4).await;
+        assert_eq!(copy.read(0, 4).await, vec![1, 2, 3, 0x80]);
+    });
+}
@@ -1,3 +1,40 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+extern crate alloc;
+
 pub mod utils {
+    #[cfg(feature = "std")]
+    pub mod backend;
+    #[cfg(feature = "std")]
+    pub mod bounds_tracker;
+    #[cfg(feature = "std")]
+    pub mod byte_swap;
+    #[cfg(feature = "std")]
+    pub mod concat_memory;
+    pub mod elf;
+    #[cfg(feature = "std")]
+    pub mod fault_injector;
+    pub mod flat_memory;
+    #[cfg(feature = "std")]
+    pub mod host_mapped;
+    #[cfg(feature = "std")]
+    pub mod journal;
     pub mod memory;
+    pub mod pod;
+    #[cfg(feature = "tokio")]
+    pub mod prefetch_cache;
+    pub mod register_block;
+    #[cfg(feature = "std")]
+    pub mod revmem;
+    #[cfg(feature = "tokio")]
+    pub mod shared_memory;
+    pub mod snapshot_tree;
+    #[cfg(feature = "std")]
+    pub mod tee_memory;
+    #[cfg(all(test, feature = "std"))]
+    mod temp_file;
+    pub mod vector_memory;
+    #[cfg(feature = "std")]
+    pub mod write_combining;
+    #[cfg(feature = "std")]
+    pub mod write_log;
 }
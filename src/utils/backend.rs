@@ -0,0 +1,68 @@
+use crate::utils::memory::{Access, MemError, TreeConfig, TreeMemory};
+use crate::utils::vector_memory::VectorMemory;
+use std::io;
+use std::path::PathBuf;
+
+/// Which backend `create_memory` makes - for code choosing one from
+/// configuration (a command line flag, say) rather than by type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryKind {
+    /// An empty `TreeMemory` with this configuration.
+    Tree(TreeConfig),
+    /// An empty `VectorMemory`.
+    Vector,
+    /// A `TreeMemory` loaded from a `revmem1` file.
+    File(PathBuf),
+}
+
+/// Make a memory of the given kind. A tree configuration with bad geometry
+/// is an `InvalidInput` error; a file which won't load gives its own.
+pub fn create_memory(kind: MemoryKind) -> io::Result<Box<dyn Access + Send>> {
+    Ok(match kind {
+        MemoryKind::Tree(config) => {
+            Box::new(TreeMemory::with_config(config).map_err(|err: MemError| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("{err:?}"))
+            })?)
+        }
+        MemoryKind::Vector => Box::new(VectorMemory::new()),
+        MemoryKind::File(path) => Box::new(TreeMemory::load(path)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::temp_file::TempFile;
+
+    #[tokio::test]
+    async fn check_create_memory() {
+        let file = TempFile::new("backend.revmem");
+        let path = file.path().to_path_buf();
+        let mut saved = TreeMemory::new();
+        saved.store_word(0x40, 0x1234).unwrap();
+        saved.save(&path).unwrap();
+
+        let kinds = [
+            MemoryKind::Tree(TreeConfig::default()),
+            MemoryKind::Vector,
+            MemoryKind::File(path.clone()),
+        ];
+        for kind in kinds {
+            let file = matches!(kind, MemoryKind::File(_));
+            let mut mem = create_memory(kind).unwrap();
+            assert_eq!(mem.read_64(0x40).await, if file { 0x1234 } else { 0 });
+            mem.write(0x10, &[1, 2, 3]).await;
+            assert_eq!(mem.read(0x10, 3).await, vec![1, 2, 3]);
+            assert!(mem.is_mapped(0x10));
+        }
+        drop(file);
+
+        let config = TreeConfig {
+            mem_bits: 40,
+            ..TreeConfig::default()
+        };
+        let err = create_memory(MemoryKind::Tree(config)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(create_memory(MemoryKind::File(path)).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use crate::utils::memory::{Access, MemError};
+use async_trait::async_trait;
+
+/// Wraps a memory and remembers the lowest and highest addresses accessed
+/// through it - the footprint of a run, to size snapshots by, or to spot a
+/// stack wandering somewhere it shouldn't. Accesses which fault still
+/// count; they were still attempted. It can keep every access too, for
+/// `coverage`.
+pub struct BoundsTracker<T: Access> {
+    inner: T,
+    bounds: Option<(u64, u64)>,
+    /// (loc, length) of each access, oldest first, if we're keeping them.
+    accesses: Option<Vec<(u64, u64)>>,
+}
+
+impl<T: Access + Send> BoundsTracker<T> {
+    pub fn new(inner: T) -> BoundsTracker<T> {
+        BoundsTracker {
+            inner,
+            bounds: None,
+            accesses: None,
+        }
+    }
+
+    /// (lowest, highest) address accessed, if there have been any.
+    pub fn bounds(&self) -> Option<(u64, u64)> {
+        self.bounds
+    }
+
+    /// Keep (or stop keeping, and forget) the (loc, length) of every access
+    /// from now on, reads and writes alike.
+    pub fn record_accesses(&mut self, on: bool) {
+        self.accesses = on.then(Vec::new);
+    }
+
+    /// The accesses kept, oldest first.
+    pub fn accesses(&self) -> &[(u64, u64)] {
+        self.accesses.as_deref().unwrap_or(&[])
+    }
+
+    /// Forget what's been accessed so far.
+    pub fn reset(&mut self) {
+        self.bounds = None;
+        if let Some(accesses) = self.accesses.as_mut() {
+            accesses.clear();
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn note(&mut self, loc: u64, len: usize) {
+        if len == 0 {
+            return;
+        }
+        if let Some(accesses) = self.accesses.as_mut() {
+            accesses.push((loc, len as u64));
+        }
+        let last = loc.saturating_add(len as u64 - 1);
+        self.bounds = Some(match self.bounds {
+            Some((low, high)) => (low.min(loc), high.max(last)),
+            None => (loc, last),
+        });
+    }
+}
+
+#[async_trait]
+impl<T: Access + Send> Access for BoundsTracker<T> {
+    async fn read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        self.note(loc, span as usize);
+        self.inner.read(loc, span).await
+    }
+
+    async fn write(&mut self, loc: u64, contents: &[u64]) {
+        self.note(loc, contents.len());
+        self.inner.write(loc, contents).await
+    }
+
+    async fn read_64(&mut self, loc: u64) -> u64 {
+        self.note(loc, 1);
+        self.inner.read_64(loc).await
+    }
+
+    async fn write_64(&mut self, loc: u64, val: u64) {
+        self.note(loc, 1);
+        self.inner.write_64(loc, val).await
+    }
+
+    fn is_mapped(&self, loc: u64) -> bool {
+        self.inner.is_mapped(loc)
+    }
+
+    async fn try_read_64(&mut self, loc: u64) -> Result<u64, MemError> {
+        self.note(loc, 1);
+        self.inner.try_read_64(loc).await
+    }
+
+    async fn try_write_64(&mut self, loc: u64, val: u64) -> Result<(), MemError> {
+        self.note(loc, 1);
+        self.inner.try_write_64(loc, val).await
+    }
+
+    async fn try_write(&mut self, loc: u64, contents: &[u64]) -> Result<usize, MemError> {
+        self.note(loc, contents.len());
+        self.inner.try_write(loc, contents).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::memory::{Perms, TreeMemory};
+
+    #[tokio::test]
+    async fn check_bounds() {
+        let mut tree = TreeMemory::new();
+        tree.set_permissions(1 << 60, 1, Perms::READ_ONLY);
+        let mut mem = BoundsTracker::new(tree);
+        assert_eq!(mem.bounds(), None);
+        mem.write_64(0x5000, 1).await;
+        assert_eq!(mem.bounds(), Some((0x5000, 0x5000)));
+        mem.read(0x4ffe, 4).await;
+        mem.write(0x8000, &[1, 2, 3]).await;
+        mem.read(0x10, 0).await;
+        assert_eq!(mem.try_read_64(0x7000).await, Ok(0));
+        assert_eq!(mem.bounds(), Some((0x4ffe, 0x8002)));
+        // A fault still counts as touching the address.
+        assert!(mem.try_write_64(1 << 60, 1).await.is_err());
+        assert_eq!(mem.bounds(), Some((0x4ffe, 1 << 60)));
+        mem.reset();
+        mem.read_64(0x20).await;
+        assert_eq!(mem.bounds(), Some((0x20, 0x20)));
+        assert_eq!(mem.accesses(), &[]);
+        mem.record_accesses(true);
+        mem.read(0x30, 2).await;
+        mem.write_64(0x10, 1).await;
+        assert_eq!(mem.accesses(), &[(0x30, 2), (0x10, 1)]);
+        mem.reset();
+        assert_eq!(mem.accesses(), &[]);
+    }
+}
@@ -0,0 +1,87 @@
+use crate::utils::memory::{Access, MemError};
+use async_trait::async_trait;
+
+/// Presents the opposite byte order of the memory underneath: every word
+/// is byte-swapped on the way in and out. Map a big-endian peripheral's
+/// region through one of these and a little-endian core sees it the right
+/// way round.
+pub struct ByteSwapView<T: Access> {
+    inner: T,
+}
+
+impl<T: Access + Send> ByteSwapView<T> {
+    pub fn new(inner: T) -> ByteSwapView<T> {
+        ByteSwapView { inner }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+#[async_trait]
+impl<T: Access + Send> Access for ByteSwapView<T> {
+    async fn read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        let mut words = self.inner.read(loc, span).await;
+        words.iter_mut().for_each(|word| *word = word.swap_bytes());
+        words
+    }
+
+    async fn write(&mut self, loc: u64, contents: &[u64]) {
+        let swapped: Vec<u64> = contents.iter().map(|word| word.swap_bytes()).collect();
+        self.inner.write(loc, &swapped).await
+    }
+
+    async fn read_64(&mut self, loc: u64) -> u64 {
+        self.inner.read_64(loc).await.swap_bytes()
+    }
+
+    async fn write_64(&mut self, loc: u64, val: u64) {
+        self.inner.write_64(loc, val.swap_bytes()).await
+    }
+
+    fn is_mapped(&self, loc: u64) -> bool {
+        self.inner.is_mapped(loc)
+    }
+
+    async fn try_read_64(&mut self, loc: u64) -> Result<u64, MemError> {
+        Ok(self.inner.try_read_64(loc).await?.swap_bytes())
+    }
+
+    async fn try_write_64(&mut self, loc: u64, val: u64) -> Result<(), MemError> {
+        self.inner.try_write_64(loc, val.swap_bytes()).await
+    }
+
+    async fn try_write(&mut self, loc: u64, contents: &[u64]) -> Result<usize, MemError> {
+        let swapped: Vec<u64> = contents.iter().map(|word| word.swap_bytes()).collect();
+        self.inner.try_write(loc, &swapped).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::memory::TreeMemory;
+
+    #[tokio::test]
+    async fn check_swap() {
+        let mut view = ByteSwapView::new(TreeMemory::new());
+        view.write_64(0x10, 0x0102_0304_0506_0708).await;
+        assert_eq!(view.inner_mut().read_64(0x10).await, 0x0807_0605_0403_0201);
+        assert_eq!(view.read_64(0x10).await, 0x0102_0304_0506_0708);
+        view.write(0x20, &[1, 2]).await;
+        assert_eq!(view.inner_mut().read(0x20, 2).await, vec![1 << 56, 2 << 56]);
+        assert_eq!(view.read(0x20, 2).await, vec![1, 2]);
+        // Bit helpers see the swapped view too.
+        view.set_bit(0x30, 0).await;
+        assert_eq!(view.into_inner().read_64(0x30).await, 1 << 56);
+    }
+}
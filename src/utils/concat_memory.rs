@@ -0,0 +1,184 @@
+use crate::utils::memory::{Access, MemError};
+use async_trait::async_trait;
+
+/// Two memories stitched into one address space: [0, split) is `low`, and
+/// [split, ..) is `high`, rebased so `split` is its address 0. A ROM and a
+/// RAM, say, without needing an MMU. Accesses spanning the split go half
+/// to each.
+pub struct ConcatMemory<A: Access, B: Access> {
+    low: A,
+    high: B,
+    split: u64,
+}
+
+impl<A: Access + Send, B: Access + Send> ConcatMemory<A, B> {
+    pub fn new(low: A, high: B, split: u64) -> ConcatMemory<A, B> {
+        ConcatMemory { low, high, split }
+    }
+
+    pub fn split(&self) -> u64 {
+        self.split
+    }
+
+    pub fn low(&self) -> &A {
+        &self.low
+    }
+
+    pub fn low_mut(&mut self) -> &mut A {
+        &mut self.low
+    }
+
+    pub fn high(&self) -> &B {
+        &self.high
+    }
+
+    pub fn high_mut(&mut self) -> &mut B {
+        &mut self.high
+    }
+
+    pub fn into_parts(self) -> (A, B) {
+        (self.low, self.high)
+    }
+
+    /// How many of len words from loc fall below the split.
+    fn low_words(&self, loc: u64, len: usize) -> usize {
+        if loc >= self.split {
+            0
+        } else {
+            (self.split - loc).min(len as u64) as usize
+        }
+    }
+}
+
+#[async_trait]
+impl<A: Access + Send, B: Access + Send> Access for ConcatMemory<A, B> {
+    async fn read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        let below = self.low_words(loc, span as usize);
+        let mut words = if below > 0 {
+            self.low.read(loc, below as u32).await
+        } else {
+            Vec::with_capacity(span as usize)
+        };
+        if below < span as usize {
+            let high_loc = (loc + below as u64) - self.split;
+            let rest = self.high.read(high_loc, span - below as u32).await;
+            words.extend_from_slice(&rest);
+        }
+        words
+    }
+
+    async fn write(&mut self, loc: u64, contents: &[u64]) {
+        let below = self.low_words(loc, contents.len());
+        if below > 0 {
+            self.low.write(loc, &contents[..below]).await;
+        }
+        if below < contents.len() {
+            let high_loc = (loc + below as u64) - self.split;
+            self.high.write(high_loc, &contents[below..]).await;
+        }
+    }
+
+    async fn read_64(&mut self, loc: u64) -> u64 {
+        if loc < self.split {
+            self.low.read_64(loc).await
+        } else {
+            self.high.read_64(loc - self.split).await
+        }
+    }
+
+    async fn write_64(&mut self, loc: u64, val: u64) {
+        if loc < self.split {
+            self.low.write_64(loc, val).await
+        } else {
+            self.high.write_64(loc - self.split, val).await
+        }
+    }
+
+    fn is_mapped(&self, loc: u64) -> bool {
+        if loc < self.split {
+            self.low.is_mapped(loc)
+        } else {
+            self.high.is_mapped(loc - self.split)
+        }
+    }
+
+    async fn try_read_64(&mut self, loc: u64) -> Result<u64, MemError> {
+        if loc < self.split {
+            self.low.try_read_64(loc).await
+        } else {
+            self.high.try_read_64(loc - self.split).await
+        }
+    }
+
+    async fn try_write_64(&mut self, loc: u64, val: u64) -> Result<(), MemError> {
+        if loc < self.split {
+            self.low.try_write_64(loc, val).await
+        } else {
+            self.high.try_write_64(loc - self.split, val).await
+        }
+    }
+
+    /// A short write from the low half stops there, as if the two were one
+    /// memory.
+    async fn try_write(&mut self, loc: u64, contents: &[u64]) -> Result<usize, MemError> {
+        let below = self.low_words(loc, contents.len());
+        if below > 0 {
+            let stored = self.low.try_write(loc, &contents[..below]).await?;
+            if stored < below {
+                return Ok(stored);
+            }
+        }
+        if below < contents.len() {
+            let high_loc = (loc + below as u64) - self.split;
+            match self.high.try_write(high_loc, &contents[below..]).await {
+                Ok(stored) => return Ok(below + stored),
+                Err(err) if below == 0 => return Err(err),
+                Err(_) => return Ok(below),
+            }
+        }
+        Ok(contents.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::memory::{Perms, TreeMemory};
+    use crate::utils::vector_memory::VectorMemory;
+
+    #[tokio::test]
+    async fn check_concat() {
+        let mut mem = ConcatMemory::new(VectorMemory::new(), TreeMemory::new(), 0x100);
+        mem.write(0xfe, &[1, 2, 3, 4]).await;
+        mem.write_64(0x10, 5).await;
+        mem.write_64(0x110, 6).await;
+        assert_eq!(mem.read(0xfd, 5).await, vec![0, 1, 2, 3, 4]);
+        assert_eq!(mem.read_64(0x101).await, 4);
+        assert_eq!(mem.read(0x0f, 2).await, vec![0, 5]);
+        assert_eq!(mem.read(0x110, 1).await, vec![6]);
+        assert_eq!(mem.try_read_64(0x10).await, Ok(5));
+
+        let (mut low, mut high) = mem.into_parts();
+        assert_eq!(low.read(0xfe, 2).await, vec![1, 2]);
+        assert_eq!(low.read_64(0x10).await, 5);
+        assert_eq!(high.read(0, 2).await, vec![3, 4]);
+        assert_eq!(high.read_64(0x10).await, 6);
+        assert!(!high.is_mapped(1 << 40));
+    }
+
+    #[tokio::test]
+    async fn check_concat_short_writes() {
+        let mut ram = TreeMemory::new();
+        ram.set_permissions(1, 1, Perms::READ_ONLY);
+        let mut mem = ConcatMemory::new(TreeMemory::new(), ram, 0x100);
+        // The low half is stored; the high half's leaf has a read-only
+        // word, so none of that is.
+        assert_eq!(mem.try_write(0xff, &[1, 2, 3]).await, Ok(1));
+        assert_eq!(
+            mem.try_write(0x101, &[4]).await,
+            Err(MemError::PermissionDenied)
+        );
+        assert_eq!(mem.try_write_64(0x102, 5).await, Ok(()));
+        assert_eq!(mem.read(0xff, 4).await, vec![1, 0, 0, 5]);
+    }
+}
@@ -0,0 +1,242 @@
+use crate::utils::memory::{MemError, TreeMemory};
+use alloc::vec;
+
+/// Why an ELF image couldn't be loaded. Nothing is written to memory
+/// unless every segment checks out, so apart from `Memory` (a fault
+/// part way through writing) a failed load leaves memory untouched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    /// No ELF magic.
+    NotElf,
+    /// Not a 64-bit image, or an unknown byte order.
+    Unsupported,
+    /// The file is too short for its ELF header.
+    Truncated,
+    /// The program header table isn't inside the file.
+    ProgramHeadersOutOfBounds,
+    /// Segment `index` claims more file bytes than memory bytes.
+    BadSegmentSize { index: usize },
+    /// Segment `index`'s file bytes aren't inside the file.
+    SegmentDataOutOfBounds { index: usize },
+    /// Segment `index` doesn't fit in the memory's address space.
+    SegmentOutOfRange { index: usize },
+    /// The memory faulted while we were writing.
+    Memory(MemError),
+}
+
+impl From<MemError> for LoadError {
+    fn from(err: MemError) -> LoadError {
+        LoadError::Memory(err)
+    }
+}
+
+const PT_LOAD: u32 = 1;
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+/// Just enough of a PT_LOAD program header.
+struct Segment {
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+    memsz: u64,
+}
+
+/// Reads header fields in the image's byte order.
+struct Fields<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl Fields<'_> {
+    fn u16(&self, at: usize) -> u16 {
+        let bytes = [self.data[at], self.data[at + 1]];
+        if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }
+    }
+
+    fn u32(&self, at: usize) -> u32 {
+        let bytes: [u8; 4] = self.data[at..at + 4].try_into().unwrap();
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    fn u64(&self, at: usize) -> u64 {
+        let bytes: [u8; 8] = self.data[at..at + 8].try_into().unwrap();
+        if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        }
+    }
+}
+
+impl TreeMemory {
+    /// Load the PT_LOAD segments of a 64-bit ELF image, treating p_vaddr
+    /// as a byte address (see `load_blob`), and return the entry point.
+    /// Everything is validated before anything is written.
+    pub fn load_elf(&mut self, data: &[u8]) -> Result<u64, LoadError> {
+        if data.len() < 4 || data[0..4] != [0x7f, b'E', b'L', b'F'] {
+            return Err(LoadError::NotElf);
+        }
+        if data.len() < EHDR_SIZE {
+            return Err(LoadError::Truncated);
+        }
+        let big_endian = match data[5] {
+            1 => false,
+            2 => true,
+            _ => return Err(LoadError::Unsupported),
+        };
+        if data[4] != 2 {
+            return Err(LoadError::Unsupported);
+        }
+        let fields = Fields { data, big_endian };
+        let entry = fields.u64(24);
+        let phoff = fields.u64(32);
+        let phentsize = fields.u16(54) as u64;
+        let phnum = fields.u16(56) as u64;
+        if phnum > 0 && (phentsize as usize) < PHDR_SIZE {
+            return Err(LoadError::ProgramHeadersOutOfBounds);
+        }
+        let table_end = phentsize
+            .checked_mul(phnum)
+            .and_then(|size| size.checked_add(phoff));
+        if table_end.is_none_or(|end| end > data.len() as u64) {
+            return Err(LoadError::ProgramHeadersOutOfBounds);
+        }
+
+        let mask = self.config().address_mask();
+        let mut segments = vec![];
+        for index in 0..phnum as usize {
+            let at = (phoff + index as u64 * phentsize) as usize;
+            if fields.u32(at) != PT_LOAD {
+                continue;
+            }
+            let segment = Segment {
+                offset: fields.u64(at + 8),
+                vaddr: fields.u64(at + 16),
+                filesz: fields.u64(at + 32),
+                memsz: fields.u64(at + 40),
+            };
+            if segment.filesz > segment.memsz {
+                return Err(LoadError::BadSegmentSize { index });
+            }
+            let file_end = segment.offset.checked_add(segment.filesz);
+            if file_end.is_none_or(|end| end > data.len() as u64) {
+                return Err(LoadError::SegmentDataOutOfBounds { index });
+            }
+            if segment.memsz > 0 {
+                let last_byte = segment.vaddr.checked_add(segment.memsz - 1);
+                if last_byte.is_none_or(|last| (last >> 3) > mask) {
+                    return Err(LoadError::SegmentOutOfRange { index });
+                }
+            }
+            segments.push(segment);
+        }
+
+        for segment in segments.iter() {
+            let start = segment.offset as usize;
+            self.load_blob(segment.vaddr, &data[start..start + segment.filesz as usize])?;
+            // And zero whatever's left (.bss and friends), in bounded chunks.
+            let mut addr = segment.vaddr + segment.filesz;
+            let mut left = segment.memsz - segment.filesz;
+            let zeros = vec![0u8; 4096.min(left as usize)];
+            while left > 0 {
+                let chunk = left.min(zeros.len() as u64);
+                self.load_blob(addr, &zeros[..chunk as usize])?;
+                addr += chunk;
+                left -= chunk;
+            }
+        }
+        Ok(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// A little-endian ELF64 header plus one PT_LOAD header per segment
+    /// (offset, vaddr, filesz, memsz), followed by payload.
+    fn make_elf(segments: &[(u64, u64, u64, u64)], payload: &[u8]) -> Vec<u8> {
+        let mut elf = vec![0u8; EHDR_SIZE];
+        elf[0..4].copy_from_slice(&[0x7f, b'E', b'L', b'F']);
+        elf[4] = 2;
+        elf[5] = 1;
+        elf[6] = 1;
+        elf[24..32].copy_from_slice(&0x1000u64.to_le_bytes());
+        elf[32..40].copy_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+        elf[54..56].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        elf[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+        for (offset, vaddr, filesz, memsz) in segments {
+            let mut phdr = vec![0u8; PHDR_SIZE];
+            phdr[0..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            phdr[8..16].copy_from_slice(&offset.to_le_bytes());
+            phdr[16..24].copy_from_slice(&vaddr.to_le_bytes());
+            phdr[32..40].copy_from_slice(&filesz.to_le_bytes());
+            phdr[40..48].copy_from_slice(&memsz.to_le_bytes());
+            elf.extend_from_slice(&phdr);
+        }
+        elf.extend_from_slice(payload);
+        elf
+    }
+
+    #[test]
+    fn check_load() {
+        let payload_at = (EHDR_SIZE + PHDR_SIZE) as u64;
+        let elf = make_elf(&[(payload_at, 0x1000, 12, 16)], &[0xaa; 12]);
+        let mut mem = TreeMemory::new();
+        mem.store_word(0x1010 / 8, u64::MAX).unwrap();
+        assert_eq!(mem.load_elf(&elf), Ok(0x1000));
+        assert_eq!(mem.load_word(0x1000 / 8), Ok(0xaaaa_aaaa_aaaa_aaaa));
+        // 4 more file bytes, then .bss zeroes the rest of the word.
+        assert_eq!(mem.load_word(0x1008 / 8), Ok(0xaaaa_aaaa));
+        // Beyond memsz is left alone.
+        assert_eq!(mem.load_word(0x1010 / 8), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn check_bad_segments() {
+        let payload_at = (EHDR_SIZE + 2 * PHDR_SIZE) as u64;
+        let good = (payload_at, 0x1000, 8, 8);
+        let mut mem = TreeMemory::new();
+        // Runs off the top of the address space.
+        let elf = make_elf(&[good, (payload_at, u64::MAX - 4, 8, 16)], &[1; 8]);
+        assert_eq!(
+            mem.load_elf(&elf),
+            Err(LoadError::SegmentOutOfRange { index: 1 })
+        );
+        // File bytes past the end of the file.
+        let elf = make_elf(&[good, (payload_at + 4, 0x2000, 8, 8)], &[1; 8]);
+        assert_eq!(
+            mem.load_elf(&elf),
+            Err(LoadError::SegmentDataOutOfBounds { index: 1 })
+        );
+        let elf = make_elf(&[good, (u64::MAX, 0x2000, 8, 8)], &[1; 8]);
+        assert_eq!(
+            mem.load_elf(&elf),
+            Err(LoadError::SegmentDataOutOfBounds { index: 1 })
+        );
+        let elf = make_elf(&[good, (payload_at, 0x2000, 8, 4)], &[1; 8]);
+        assert_eq!(
+            mem.load_elf(&elf),
+            Err(LoadError::BadSegmentSize { index: 1 })
+        );
+        // The good segment wasn't written either time.
+        assert_eq!(mem.resident_words(), 0);
+
+        assert_eq!(mem.load_elf(b"nope"), Err(LoadError::NotElf));
+        assert_eq!(mem.load_elf(&elf[..40]), Err(LoadError::Truncated));
+        assert_eq!(
+            mem.load_elf(&elf[..EHDR_SIZE + 10]),
+            Err(LoadError::ProgramHeadersOutOfBounds)
+        );
+    }
+}
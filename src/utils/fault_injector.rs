@@ -0,0 +1,242 @@
+use crate::utils::memory::{Access, MemError};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+
+/// What happens to an access a `FaultInjector` picks on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InjectedFault {
+    /// It fails with this error. The `try_` methods return it; the others
+    /// can't, so they panic, as a `TreeMemory` does when an access faults.
+    Error(MemError),
+    /// It goes ahead, but with these bits of the first word read or
+    /// written flipped.
+    Corrupt(u64),
+}
+
+/// Wraps a memory and makes some accesses fail or corrupt a word - the
+/// nth read or write, or at random - to see how an emulator copes with
+/// flaky memory. Everything else is passed straight through.
+///
+/// Reads are read, read_64 and try_read_64; writes are write, write_64,
+/// try_write_64 and try_write. Each counts once, however many words.
+pub struct FaultInjector<T: Access> {
+    inner: T,
+    reads: u64,
+    writes: u64,
+    /// Read number (from 1) => what to do to it.
+    read_faults: BTreeMap<u64, InjectedFault>,
+    /// Likewise for writes.
+    write_faults: BTreeMap<u64, InjectedFault>,
+    /// If set, the chance any access gets the fault.
+    random: Option<(f64, InjectedFault)>,
+    /// xorshift64* state.
+    rng: u64,
+    injected: u64,
+}
+
+impl<T: Access + Send> FaultInjector<T> {
+    pub fn new(inner: T) -> FaultInjector<T> {
+        FaultInjector {
+            inner,
+            reads: 0,
+            writes: 0,
+            read_faults: BTreeMap::new(),
+            write_faults: BTreeMap::new(),
+            random: None,
+            rng: 1,
+            injected: 0,
+        }
+    }
+
+    /// Do fault to the nth read from now on (the first being 1).
+    pub fn fault_read(&mut self, n: u64, fault: InjectedFault) {
+        self.read_faults.insert(self.reads + n, fault);
+    }
+
+    /// Do fault to the nth write from now on (the first being 1).
+    pub fn fault_write(&mut self, n: u64, fault: InjectedFault) {
+        self.write_faults.insert(self.writes + n, fault);
+    }
+
+    /// Do fault to each access with the given probability, picked by an RNG
+    /// seeded with seed, so a failing run can be repeated. Scheduled
+    /// faults still happen as well.
+    pub fn fault_randomly(&mut self, probability: f64, seed: u64, fault: InjectedFault) {
+        self.random = Some((probability, fault));
+        // xorshift gets stuck at 0.
+        self.rng = seed.max(1);
+    }
+
+    pub fn stop_random_faults(&mut self) {
+        self.random = None;
+    }
+
+    /// How many faults we've injected.
+    pub fn injected(&self) -> u64 {
+        self.injected
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn next_random(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let bits = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+        bits as f64 / (1u64 << 53) as f64
+    }
+
+    /// Count an access, and say what to do to it, if anything.
+    fn pick(&mut self, is_write: bool) -> Option<InjectedFault> {
+        let scheduled = if is_write {
+            self.writes += 1;
+            self.write_faults.remove(&self.writes)
+        } else {
+            self.reads += 1;
+            self.read_faults.remove(&self.reads)
+        };
+        let random = self.random;
+        let fault = scheduled.or_else(|| match random {
+            Some((probability, fault)) if self.next_random() < probability => Some(fault),
+            _ => None,
+        });
+        if fault.is_some() {
+            self.injected += 1;
+        }
+        fault
+    }
+}
+
+fn corrupt(words: &mut [u64], fault: Option<InjectedFault>) {
+    if let (Some(InjectedFault::Corrupt(bits)), Some(first)) = (fault, words.first_mut()) {
+        *first ^= bits;
+    }
+}
+
+fn fail(fault: Option<InjectedFault>) -> Result<(), MemError> {
+    match fault {
+        Some(InjectedFault::Error(err)) => Err(err),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl<T: Access + Send> Access for FaultInjector<T> {
+    async fn read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        let fault = self.pick(false);
+        fail(fault).expect("injected read fault");
+        let mut words = self.inner.read(loc, span).await;
+        corrupt(&mut words, fault);
+        words
+    }
+
+    async fn write(&mut self, loc: u64, contents: &[u64]) {
+        let fault = self.pick(true);
+        fail(fault).expect("injected write fault");
+        let mut words = contents.to_vec();
+        corrupt(&mut words, fault);
+        self.inner.write(loc, &words).await
+    }
+
+    async fn read_64(&mut self, loc: u64) -> u64 {
+        let fault = self.pick(false);
+        fail(fault).expect("injected read fault");
+        let mut word = [self.inner.read_64(loc).await];
+        corrupt(&mut word, fault);
+        word[0]
+    }
+
+    async fn write_64(&mut self, loc: u64, val: u64) {
+        let fault = self.pick(true);
+        fail(fault).expect("injected write fault");
+        let mut word = [val];
+        corrupt(&mut word, fault);
+        self.inner.write_64(loc, word[0]).await
+    }
+
+    fn is_mapped(&self, loc: u64) -> bool {
+        self.inner.is_mapped(loc)
+    }
+
+    async fn try_read_64(&mut self, loc: u64) -> Result<u64, MemError> {
+        let fault = self.pick(false);
+        fail(fault)?;
+        let mut word = [self.inner.try_read_64(loc).await?];
+        corrupt(&mut word, fault);
+        Ok(word[0])
+    }
+
+    async fn try_write_64(&mut self, loc: u64, val: u64) -> Result<(), MemError> {
+        let fault = self.pick(true);
+        fail(fault)?;
+        let mut word = [val];
+        corrupt(&mut word, fault);
+        self.inner.try_write_64(loc, word[0]).await
+    }
+
+    async fn try_write(&mut self, loc: u64, contents: &[u64]) -> Result<usize, MemError> {
+        let fault = self.pick(true);
+        fail(fault)?;
+        let mut words = contents.to_vec();
+        corrupt(&mut words, fault);
+        self.inner.try_write(loc, &words).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::memory::TreeMemory;
+
+    #[tokio::test]
+    async fn check_scheduled_faults() {
+        let mut mem = FaultInjector::new(TreeMemory::new());
+        mem.write(0x10, &[1, 2, 3, 4]).await;
+        mem.fault_read(3, InjectedFault::Error(MemError::Unmapped));
+        mem.fault_write(1, InjectedFault::Corrupt(0x100));
+        assert_eq!(mem.try_read_64(0x10).await, Ok(1));
+        assert_eq!(mem.read_64(0x11).await, 2);
+        assert_eq!(mem.try_read_64(0x12).await, Err(MemError::Unmapped));
+        assert_eq!(mem.try_read_64(0x13).await, Ok(4));
+        // Written with a bit flipped; the next write is fine.
+        mem.write(0x20, &[5, 6]).await;
+        mem.write_64(0x22, 7).await;
+        assert_eq!(mem.read(0x20, 3).await, vec![0x105, 6, 7]);
+        assert_eq!(mem.injected(), 2);
+    }
+
+    #[tokio::test]
+    async fn check_random_faults() {
+        async fn failures(seed: u64) -> Vec<u64> {
+            let mut mem = FaultInjector::new(TreeMemory::new());
+            mem.fault_randomly(0.25, seed, InjectedFault::Error(MemError::OutOfRange));
+            let mut failed = Vec::new();
+            for loc in 0..200 {
+                if mem.try_write_64(loc, loc).await.is_err() {
+                    failed.push(loc);
+                }
+            }
+            assert_eq!(mem.injected(), failed.len() as u64);
+            failed
+        }
+        let failed = failures(42).await;
+        // Roughly a quarter, and the same again for the same seed.
+        assert!((30..70).contains(&failed.len()), "{} faults", failed.len());
+        assert_eq!(failures(42).await, failed);
+        assert_ne!(failures(43).await, failed);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "injected read fault")]
+    async fn check_fault_panics() {
+        let mut mem = FaultInjector::new(TreeMemory::new());
+        mem.fault_read(1, InjectedFault::Error(MemError::Unmapped));
+        mem.read(0, 1).await;
+    }
+}
@@ -0,0 +1,317 @@
+use crate::utils::memory::SyncAccess;
+#[cfg(feature = "std")]
+use crate::utils::memory::{Access, MemError};
+use alloc::{vec, vec::Vec};
+#[cfg(feature = "std")]
+use async_trait::async_trait;
+
+/// A fixed-size flat memory for tiny address spaces - handy as a reference
+/// implementation, since there's no tree to get wrong.
+/// Reads past the end return zero, and writes past the end are dropped.
+pub struct FlatMemory<const N: usize> {
+    words: [u64; N],
+}
+
+impl<const N: usize> Default for FlatMemory<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> FlatMemory<N> {
+    pub fn new() -> FlatMemory<N> {
+        FlatMemory { words: [0; N] }
+    }
+
+    /// How many words do we hold?
+    pub fn len(&self) -> usize {
+        N
+    }
+
+    pub fn is_empty(&self) -> bool {
+        N == 0
+    }
+}
+
+// Plain words as memory, the same way round as FlatMemory: zero past
+// the end on reads, and writes past the end dropped.
+fn read_words(words: &[u64], loc: u64, span: u32) -> Vec<u64> {
+    let mut result = vec![0; span as usize];
+    if loc < words.len() as u64 {
+        let src = &words[(loc as usize)..];
+        for (dst_i, src_i) in result.iter_mut().zip(src.iter()) {
+            *dst_i = *src_i;
+        }
+    }
+    result
+}
+
+fn write_words(words: &mut [u64], loc: u64, contents: &[u64]) {
+    if loc < words.len() as u64 {
+        let dst = &mut words[(loc as usize)..];
+        for (dst_i, src_i) in dst.iter_mut().zip(contents.iter()) {
+            *dst_i = *src_i;
+        }
+    }
+}
+
+/// How much of a len word write from loc fits in words words - the rest is
+/// dropped - or Unmapped if none of it does.
+#[cfg(feature = "std")]
+fn stored_count(words: usize, loc: u64, len: usize) -> Result<usize, MemError> {
+    let stored = (words as u64).saturating_sub(loc).min(len as u64) as usize;
+    if stored == 0 && len > 0 {
+        return Err(MemError::Unmapped);
+    }
+    Ok(stored)
+}
+
+fn read_word(words: &[u64], loc: u64) -> u64 {
+    usize::try_from(loc)
+        .ok()
+        .and_then(|loc| words.get(loc).copied())
+        .unwrap_or(0)
+}
+
+fn write_word(words: &mut [u64], loc: u64, val: u64) {
+    if let Some(word) = usize::try_from(loc).ok().and_then(|loc| words.get_mut(loc)) {
+        *word = val;
+    }
+}
+
+impl<const N: usize> SyncAccess for FlatMemory<N> {
+    fn sync_read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        read_words(&self.words, loc, span)
+    }
+
+    fn sync_write(&mut self, loc: u64, contents: &[u64]) {
+        write_words(&mut self.words, loc, contents)
+    }
+
+    fn sync_read_64(&mut self, loc: u64) -> u64 {
+        read_word(&self.words, loc)
+    }
+
+    fn sync_write_64(&mut self, loc: u64, val: u64) {
+        write_word(&mut self.words, loc, val)
+    }
+}
+
+/// A slice of words is a memory too, for tests which don't want to build
+/// one - addresses index the slice, reads past the end are zero and writes
+/// past it are dropped, as for `FlatMemory`.
+///
+/// ```
+/// use revproc1::utils::memory::SyncAccess;
+///
+/// let mut mem = [0u64; 8];
+/// mem.sync_write(6, &[1, 2, 3]);
+/// assert_eq!(mem.sync_read(5, 4), vec![0, 1, 2, 0]);
+/// mem.as_mut_slice().sync_write_64(0, 9);
+/// assert_eq!(mem, [9, 0, 0, 0, 0, 0, 1, 2]);
+/// ```
+impl SyncAccess for [u64] {
+    fn sync_read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        read_words(self, loc, span)
+    }
+
+    fn sync_write(&mut self, loc: u64, contents: &[u64]) {
+        write_words(self, loc, contents)
+    }
+
+    fn sync_read_64(&mut self, loc: u64) -> u64 {
+        read_word(self, loc)
+    }
+
+    fn sync_write_64(&mut self, loc: u64, val: u64) {
+        write_word(self, loc, val)
+    }
+}
+
+impl<const N: usize> SyncAccess for [u64; N] {
+    fn sync_read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        self.as_mut_slice().sync_read(loc, span)
+    }
+
+    fn sync_write(&mut self, loc: u64, contents: &[u64]) {
+        self.as_mut_slice().sync_write(loc, contents)
+    }
+
+    fn sync_read_64(&mut self, loc: u64) -> u64 {
+        self.as_mut_slice().sync_read_64(loc)
+    }
+
+    fn sync_write_64(&mut self, loc: u64, val: u64) {
+        self.as_mut_slice().sync_write_64(loc, val)
+    }
+}
+
+#[cfg(feature = "std")]
+#[async_trait]
+impl<const N: usize> Access for FlatMemory<N> {
+    async fn read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        self.sync_read(loc, span)
+    }
+
+    async fn write(&mut self, loc: u64, contents: &[u64]) {
+        self.sync_write(loc, contents)
+    }
+
+    async fn read_64(&mut self, loc: u64) -> u64 {
+        self.sync_read_64(loc)
+    }
+
+    async fn write_64(&mut self, loc: u64, val: u64) {
+        self.sync_write_64(loc, val)
+    }
+
+    fn is_mapped(&self, loc: u64) -> bool {
+        loc < N as u64
+    }
+
+    /// Words past N are dropped, so they don't count.
+    async fn try_write(&mut self, loc: u64, contents: &[u64]) -> Result<usize, MemError> {
+        self.sync_write(loc, contents);
+        stored_count(N, loc, contents.len())
+    }
+}
+
+// Access wants a sized Self, so it's the borrow which gets it for slices.
+// (async_trait names the elided lifetime, which upsets clippy.)
+#[cfg(feature = "std")]
+#[allow(clippy::needless_lifetimes)]
+#[async_trait]
+impl Access for &mut [u64] {
+    async fn read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        self.sync_read(loc, span)
+    }
+
+    async fn write(&mut self, loc: u64, contents: &[u64]) {
+        self.sync_write(loc, contents)
+    }
+
+    async fn read_64(&mut self, loc: u64) -> u64 {
+        self.sync_read_64(loc)
+    }
+
+    async fn write_64(&mut self, loc: u64, val: u64) {
+        self.sync_write_64(loc, val)
+    }
+
+    fn is_mapped(&self, loc: u64) -> bool {
+        loc < self.len() as u64
+    }
+
+    /// As FlatMemory's: words past the end are dropped, so they don't count.
+    async fn try_write(&mut self, loc: u64, contents: &[u64]) -> Result<usize, MemError> {
+        self.sync_write(loc, contents);
+        stored_count(self.len(), loc, contents.len())
+    }
+}
+
+#[cfg(feature = "std")]
+#[async_trait]
+impl<const N: usize> Access for [u64; N] {
+    async fn read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        self.sync_read(loc, span)
+    }
+
+    async fn write(&mut self, loc: u64, contents: &[u64]) {
+        self.sync_write(loc, contents)
+    }
+
+    async fn read_64(&mut self, loc: u64) -> u64 {
+        self.sync_read_64(loc)
+    }
+
+    async fn write_64(&mut self, loc: u64, val: u64) {
+        self.sync_write_64(loc, val)
+    }
+
+    fn is_mapped(&self, loc: u64) -> bool {
+        loc < N as u64
+    }
+
+    async fn try_write(&mut self, loc: u64, contents: &[u64]) -> Result<usize, MemError> {
+        self.sync_write(loc, contents);
+        stored_count(N, loc, contents.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "std")]
+    use crate::utils::memory::TreeMemory;
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_bounds() {
+        let mut mem = FlatMemory::<256>::new();
+        mem.write_64(255, 7).await;
+        mem.write_64(256, 8).await;
+        assert_eq!(mem.read_64(255).await, 7);
+        assert_eq!(mem.read_64(256).await, 0);
+        mem.write(254, &[1, 2, 3, 4]).await;
+        assert_eq!(mem.read(254, 4).await, vec![1, 2, 0, 0]);
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn compare_with_tree() {
+        let mut flat = FlatMemory::<256>::new();
+        let mut tree = TreeMemory::new();
+        let ops: Vec<(u64, Vec<u64>)> = vec![
+            (0, vec![1, 2, 3]),
+            (17, vec![0x898, 0x12345678]),
+            (1, vec![42]),
+            (200, vec![9; 40]),
+        ];
+        for (loc, data) in ops.iter() {
+            flat.write(*loc, data).await;
+            tree.write(*loc, data).await;
+        }
+        for loc in [0, 1, 2, 16, 17, 18, 199, 200, 239, 240] {
+            assert_eq!(flat.read_64(loc).await, tree.read_64(loc).await);
+        }
+        assert_eq!(flat.read(0, 256).await, tree.read(0, 256).await);
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_plain_words() {
+        // Anything generic over Access should take an array or a slice.
+        async fn bump<A: Access>(mem: &mut A, loc: u64) -> u64 {
+            let val = mem.read_64(loc).await + 1;
+            mem.write_64(loc, val).await;
+            val
+        }
+        let mut words = [0u64; 4];
+        assert_eq!(bump(&mut words, 1).await, 1);
+        assert_eq!(bump(&mut words, 4).await, 1);
+        assert!(words.is_mapped(3) && !words.is_mapped(4));
+        let mut slice = &mut words[1..];
+        assert_eq!(bump(&mut slice, 0).await, 2);
+        slice.write(2, &[7, 8]).await;
+        assert_eq!(slice.read(1, 3).await, vec![0, 7, 0]);
+        assert_eq!(words, [0, 2, 0, 7]);
+
+        // Writes running off the end say how much they stored.
+        let mut words = [0u64; 4];
+        assert_eq!(words.try_write(2, &[1, 2, 3, 4, 5]).await, Ok(2));
+        assert_eq!(words.try_write(4, &[6]).await, Err(MemError::Unmapped));
+        assert_eq!(words.try_write(4, &[]).await, Ok(0));
+        let mut slice = &mut words[1..];
+        assert_eq!(slice.try_write(1, &[7, 8, 9]).await, Ok(2));
+        assert_eq!(slice.try_write(3, &[1]).await, Err(MemError::Unmapped));
+        assert_eq!(words, [0, 0, 7, 8]);
+    }
+
+    #[test]
+    fn check_sync_bounds() {
+        let mut mem = FlatMemory::<16>::new();
+        mem.sync_write(14, &[1, 2, 3]);
+        assert_eq!(mem.sync_read(14, 3), vec![1, 2, 0]);
+        assert_eq!(mem.sync_read_64(100), 0);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::utils::memory::{Access, MemError};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+
+/// A memory with some ranges backed directly by host buffers - a shared
+/// framebuffer, say. Accesses to a mapped range read and write the host
+/// slice; everything else goes to the memory underneath. The slices are
+/// borrowed for as long as the mapping lives, so drop it (or
+/// `into_inner`) to look at them from the host side.
+pub struct HostMappedMemory<'a, T: Access> {
+    inner: T,
+    /// start => slice; never overlapping.
+    mapped: BTreeMap<u64, &'a mut [u64]>,
+}
+
+/// One piece of an access: where it starts, how far into the access that
+/// is, how long it is, and which mapping (by start) covers it, if any.
+type Piece = (u64, usize, usize, Option<u64>);
+
+impl<'a, T: Access + Send> HostMappedMemory<'a, T> {
+    pub fn new(inner: T) -> HostMappedMemory<'a, T> {
+        HostMappedMemory {
+            inner,
+            mapped: BTreeMap::new(),
+        }
+    }
+
+    /// Back slice.len() words from loc with slice. Panics if that overlaps
+    /// a range already mapped, or runs off the top of the address space.
+    pub fn map_host_slice(&mut self, loc: u64, slice: &'a mut [u64]) {
+        if slice.is_empty() {
+            return;
+        }
+        let last = loc
+            .checked_add(slice.len() as u64 - 1)
+            .expect("host slice runs off the top of memory");
+        let clash = self
+            .mapped
+            .range(..=last)
+            .next_back()
+            .is_some_and(|(start, s)| start + (s.len() as u64 - 1) >= loc);
+        assert!(!clash, "host slice at {loc:#x} overlaps another");
+        self.mapped.insert(loc, slice);
+    }
+
+    /// Stop backing the range mapped at loc with a host slice, handing the
+    /// slice back. The memory underneath shows through again.
+    pub fn unmap_host_slice(&mut self, loc: u64) -> Option<&'a mut [u64]> {
+        self.mapped.remove(&loc)
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Split len words from loc into runs which are wholly in one mapping
+    /// or wholly outside them all.
+    fn pieces(&self, loc: u64, len: usize) -> Vec<Piece> {
+        let mut pieces = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let addr = loc.wrapping_add(done as u64);
+            let left = len - done;
+            let covering = self
+                .mapped
+                .range(..=addr)
+                .next_back()
+                .filter(|(start, s)| addr - *start < s.len() as u64);
+            let (count, key) = match covering {
+                Some((start, s)) => (s.len() - (addr - start) as usize, Some(*start)),
+                // Up to the next mapping, if it's before we're done.
+                None => match self.mapped.range(addr..).next() {
+                    Some((start, _)) => (((start - addr) as usize), None),
+                    None => (left, None),
+                },
+            };
+            let count = count.min(left);
+            pieces.push((addr, done, count, key));
+            done += count;
+        }
+        pieces
+    }
+
+    /// The host words for a piece.
+    fn host(&mut self, key: u64, addr: u64, count: usize) -> &mut [u64] {
+        let offset = (addr - key) as usize;
+        &mut self.mapped.get_mut(&key).unwrap()[offset..offset + count]
+    }
+}
+
+#[async_trait]
+impl<'a, T: Access + Send> Access for HostMappedMemory<'a, T> {
+    async fn read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        let mut words = vec![0; span as usize];
+        for (addr, at, count, key) in self.pieces(loc, span as usize) {
+            match key {
+                Some(key) => words[at..at + count].copy_from_slice(self.host(key, addr, count)),
+                None => {
+                    let got = self.inner.read(addr, count as u32).await;
+                    words[at..at + count].copy_from_slice(&got);
+                }
+            }
+        }
+        words
+    }
+
+    async fn write(&mut self, loc: u64, contents: &[u64]) {
+        for (addr, at, count, key) in self.pieces(loc, contents.len()) {
+            match key {
+                Some(key) => self
+                    .host(key, addr, count)
+                    .copy_from_slice(&contents[at..at + count]),
+                None => self.inner.write(addr, &contents[at..at + count]).await,
+            }
+        }
+    }
+
+    async fn read_64(&mut self, loc: u64) -> u64 {
+        self.read(loc, 1).await[0]
+    }
+
+    async fn write_64(&mut self, loc: u64, val: u64) {
+        self.write(loc, &[val]).await
+    }
+
+    fn is_mapped(&self, loc: u64) -> bool {
+        self.pieces(loc, 1)[0].3.is_some() || self.inner.is_mapped(loc)
+    }
+
+    async fn try_read_64(&mut self, loc: u64) -> Result<u64, MemError> {
+        match self.pieces(loc, 1)[0] {
+            (_, _, _, Some(key)) => Ok(self.host(key, loc, 1)[0]),
+            _ => self.inner.try_read_64(loc).await,
+        }
+    }
+
+    async fn try_write_64(&mut self, loc: u64, val: u64) -> Result<(), MemError> {
+        match self.pieces(loc, 1)[0] {
+            (_, _, _, Some(key)) => {
+                self.host(key, loc, 1)[0] = val;
+                Ok(())
+            }
+            _ => self.inner.try_write_64(loc, val).await,
+        }
+    }
+
+    /// Host slices can't fault, so a short write is down to the memory
+    /// underneath.
+    async fn try_write(&mut self, loc: u64, contents: &[u64]) -> Result<usize, MemError> {
+        for (addr, at, count, key) in self.pieces(loc, contents.len()) {
+            let stored = match key {
+                Some(key) => {
+                    let src = &contents[at..at + count];
+                    self.host(key, addr, count).copy_from_slice(src);
+                    count
+                }
+                None => match self.inner.try_write(addr, &contents[at..at + count]).await {
+                    Ok(stored) => stored,
+                    Err(err) if at == 0 => return Err(err),
+                    Err(_) => 0,
+                },
+            };
+            if stored < count {
+                return Ok(at + stored);
+            }
+        }
+        Ok(contents.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::memory::TreeMemory;
+
+    #[tokio::test]
+    async fn check_host_slice() {
+        let mut framebuffer = [0u64; 16];
+        let mut other = [7u64; 2];
+        let mut mem = HostMappedMemory::new(TreeMemory::new());
+        mem.map_host_slice(0x1000, &mut framebuffer);
+        mem.map_host_slice(0x1010, &mut other);
+        mem.write_64(0x1003, 0xabcd).await;
+        // Straddling guest memory, the framebuffer and the other slice.
+        mem.write(0x0ffe, &[1, 2, 3]).await;
+        mem.write(0x100f, &[4, 5, 6]).await;
+        assert_eq!(mem.read(0x0ffe, 4).await, vec![1, 2, 3, 0]);
+        assert_eq!(mem.read(0x1010, 3).await, vec![5, 6, 0]);
+        assert_eq!(mem.try_read_64(0x1003).await, Ok(0xabcd));
+        assert!(mem.is_mapped(0x1005));
+        assert!(!mem.is_mapped(0x5_0000));
+
+        // The guest memory only got what was outside the slices.
+        let mut tree = mem.into_inner();
+        assert_eq!(tree.read(0x0ffe, 3).await, vec![1, 2, 0]);
+        assert_eq!(tree.read_64(0x1003).await, 0);
+        assert_eq!(framebuffer[..4], [3, 0, 0, 0xabcd]);
+        assert_eq!(framebuffer[15], 4);
+        assert_eq!(other, [5, 6]);
+    }
+
+    #[tokio::test]
+    async fn check_unmap_host_slice() {
+        let mut buf = [1u64; 4];
+        let mut mem = HostMappedMemory::new(TreeMemory::new());
+        mem.map_host_slice(0x20, &mut buf);
+        assert_eq!(mem.read_64(0x21).await, 1);
+        let slice = mem.unmap_host_slice(0x20).unwrap();
+        slice[1] = 9;
+        assert_eq!(mem.read_64(0x21).await, 0);
+        assert!(mem.unmap_host_slice(0x20).is_none());
+        assert_eq!(buf[1], 9);
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn check_overlapping_slices() {
+        let (mut a, mut b) = ([0u64; 4], [0u64; 4]);
+        let mut mem = HostMappedMemory::new(TreeMemory::new());
+        mem.map_host_slice(0x20, &mut a);
+        mem.map_host_slice(0x1e, &mut b);
+    }
+
+    #[tokio::test]
+    async fn check_slice_at_top() {
+        let mut buf = [0u64; 4];
+        let mut mem = HostMappedMemory::new([0u64; 4]);
+        mem.map_host_slice(u64::MAX - 3, &mut buf);
+        mem.write_64(u64::MAX, 5).await;
+        mem.write(u64::MAX - 4, &[1, 2]).await;
+        assert_eq!(mem.read(u64::MAX - 4, 5).await, vec![0, 2, 0, 0, 5]);
+        assert_eq!(mem.read_64(u64::MAX).await, 5);
+        assert!(mem.is_mapped(u64::MAX));
+        drop(mem);
+        assert_eq!(buf, [2, 0, 0, 5]);
+    }
+}
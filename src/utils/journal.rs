@@ -0,0 +1,678 @@
+use crate::utils::memory::{Access, MemError};
+use async_trait::async_trait;
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::pin::Pin;
+
+/// One write, as the journal remembers it: what was at loc before, and
+/// what got written over it. stamp is a logical clock - it goes up by one
+/// for every write journalled, and isn't wound back by undoing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoRecord {
+    pub loc: u64,
+    pub old: Vec<u64>,
+    pub new: Vec<u64>,
+    pub stamp: u64,
+}
+
+impl UndoRecord {
+    /// (loc, length) of the write, for `coverage`.
+    pub fn span(&self) -> (u64, u64) {
+        (self.loc, self.new.len() as u64)
+    }
+}
+
+/// Wraps a memory and journals every write so it can be undone - the
+/// discard stack of a reversible machine, more or less.
+///
+/// Writes between `begin_instruction` and `end_instruction` make up one
+/// step, which `undo_last` undoes as a whole; any other write is a step by
+/// itself.
+pub struct JournalingMemory<T: Access> {
+    inner: T,
+    journal: Vec<UndoRecord>,
+    /// Where each step starts in the journal, oldest first.
+    steps: Vec<usize>,
+    /// Where the instruction in progress started, if we're in one.
+    instruction: Option<usize>,
+    /// Don't journal writes which store what's already there.
+    skip_noop_writes: bool,
+    /// The next write's stamp.
+    clock: u64,
+    /// What each transaction whose body is running has changed so far,
+    /// innermost last.
+    open: Vec<TxnLog>,
+}
+
+/// A running transaction body's effects, as it goes.
+#[derive(Debug)]
+struct TxnLog {
+    /// Journal length when the body started.
+    mark: usize,
+    /// Journal records from before the body which it undid, oldest first.
+    popped: Vec<UndoRecord>,
+    /// (loc, what was there) for every write the body made to the inner
+    /// memory, undoing included, oldest first.
+    images: Vec<(u64, Vec<u64>)>,
+}
+
+impl<T: Access + Send> JournalingMemory<T> {
+    pub fn new(inner: T) -> JournalingMemory<T> {
+        JournalingMemory {
+            inner,
+            journal: Vec::new(),
+            steps: Vec::new(),
+            instruction: None,
+            skip_noop_writes: false,
+            clock: 0,
+            open: Vec::new(),
+        }
+    }
+
+    /// If on, a write storing exactly what's already there isn't
+    /// journalled (it still happens), since undoing it would change
+    /// nothing. Off by default, so the journal has one record per write.
+    pub fn set_skip_noop_writes(&mut self, skip: bool) {
+        self.skip_noop_writes = skip;
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// The writes we could undo, oldest first.
+    pub fn journal(&self) -> impl Iterator<Item = &UndoRecord> {
+        self.journal.iter()
+    }
+
+    /// The writes from here to `end_instruction` are one step, undone
+    /// together by `undo_last`. Panics if we're already in one.
+    pub fn begin_instruction(&mut self) {
+        assert!(self.instruction.is_none(), "already in an instruction");
+        self.instruction = Some(self.journal.len());
+    }
+
+    /// Finish the instruction `begin_instruction` started. One which
+    /// wrote nothing doesn't count as a step. Panics if we aren't in one.
+    pub fn end_instruction(&mut self) {
+        let start = self.instruction.take().expect("not in an instruction");
+        if self.journal.len() > start {
+            self.steps.push(start);
+        }
+    }
+
+    /// Undo the most recent step: the last completed instruction, or
+    /// write outside one. Returns false if there was nothing left to
+    /// undo. Panics in the middle of an instruction.
+    pub async fn undo_last(&mut self) -> bool {
+        assert!(
+            self.instruction.is_none(),
+            "undo_last inside an instruction"
+        );
+        match self.steps.last() {
+            Some(&start) => {
+                self.undo_n(self.journal.len() - start).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Undo just the most recent write, even if it's part of an
+    /// instruction. Returns false if there was nothing left to undo.
+    pub async fn undo_write(&mut self) -> bool {
+        self.undo_n(1).await == 1
+    }
+
+    /// Undo up to n of the most recent writes, newest first, returning how
+    /// many we actually undid (fewer if the journal ran out).
+    pub async fn undo_n(&mut self, n: usize) -> usize {
+        let keep = self.journal.len().saturating_sub(n);
+        let undone = self.journal.split_off(keep);
+        for txn in &mut self.open {
+            // Anything from before a body which it undoes, it must put back.
+            let before = txn.mark.saturating_sub(keep).min(undone.len());
+            txn.popped.splice(0..0, undone[..before].iter().cloned());
+            txn.mark = txn.mark.min(keep);
+        }
+        for record in undone.iter().rev() {
+            self.note_image(record.loc, &record.new);
+            self.inner.write(record.loc, &record.old).await;
+        }
+        // Steps we've undone all of are gone; any we've undone part of
+        // are now shorter.
+        while self.steps.last().is_some_and(|&start| start >= keep) {
+            self.steps.pop();
+        }
+        if let Some(start) = self.instruction.as_mut() {
+            *start = (*start).min(keep);
+        }
+        undone.len()
+    }
+
+    /// Where other's journal first differs from ours in what was written
+    /// where (stamps and old contents aside) - the first write a replay
+    /// got in a different order, or differently, or None if they match.
+    /// If one journal is a prefix of the other, where the shorter ends.
+    pub fn diverges_from<U: Access + Send>(&self, other: &JournalingMemory<U>) -> Option<usize> {
+        let same = |(a, b): (&UndoRecord, &UndoRecord)| a.loc == b.loc && a.new == b.new;
+        match self
+            .journal
+            .iter()
+            .zip(&other.journal)
+            .position(|pair| !same(pair))
+        {
+            Some(index) => Some(index),
+            None if self.journal.len() != other.journal.len() => {
+                Some(self.journal.len().min(other.journal.len()))
+            }
+            None => None,
+        }
+    }
+
+    /// Add a write to the journal, stamped, as a step of its own unless
+    /// we're in an instruction.
+    fn record(&mut self, loc: u64, old: Vec<u64>, new: Vec<u64>) {
+        if self.instruction.is_none() {
+            self.steps.push(self.journal.len());
+        }
+        let stamp = self.clock;
+        self.clock += 1;
+        self.journal.push(UndoRecord {
+            loc,
+            old,
+            new,
+            stamp,
+        });
+    }
+
+    /// Tell any transactions running that the inner memory at loc held old
+    /// before a write.
+    fn note_image(&mut self, loc: u64, old: &[u64]) {
+        for txn in &mut self.open {
+            txn.images.push((loc, old.to_vec()));
+        }
+    }
+
+    /// Run f against the memory, returning its result and a `Transaction`
+    /// covering what it did (writes, and undoing too), eg.
+    /// `mem.transaction(|m| Box::pin(async move { m.write_64(0, 1).await }))`.
+    pub async fn transaction<R>(
+        &mut self,
+        f: impl for<'a> FnOnce(&'a mut Self) -> TransactionBody<'a, R>,
+    ) -> (R, Transaction) {
+        let (first_stamp, steps) = (self.clock, self.steps.clone());
+        self.open.push(TxnLog {
+            mark: self.journal.len(),
+            popped: Vec::new(),
+            images: Vec::new(),
+        });
+        let result = f(self).await;
+        let log = self.open.pop().expect("transaction log went missing");
+        let txn = Transaction {
+            stamps: first_stamp..self.clock,
+            mark: log.mark,
+            popped: log.popped,
+            images: log.images,
+            steps,
+        };
+        (result, txn)
+    }
+}
+
+/// What a transaction body returns: its future, borrowing the memory.
+pub type TransactionBody<'a, R> = Pin<Box<dyn Future<Output = R> + Send + 'a>>;
+
+/// What a `transaction`'s body did, to be kept or rolled back.
+#[must_use = "a transaction should be committed or aborted"]
+#[derive(Debug)]
+pub struct Transaction {
+    /// Stamps of the writes the body journalled.
+    stamps: core::ops::Range<u64>,
+    /// How much of the journal from before the body it left alone.
+    mark: usize,
+    /// The records from before the body which it undid, oldest first.
+    popped: Vec<UndoRecord>,
+    /// (loc, what was there) before each write the body made, oldest first.
+    images: Vec<(u64, Vec<u64>)>,
+    /// The steps from before the body.
+    steps: Vec<usize>,
+}
+
+impl Transaction {
+    /// Keep the writes. They stay in the journal, so undo can still
+    /// reach them.
+    pub fn commit(self) {}
+
+    /// Roll back what the body did - its writes, and anything from before
+    /// it which it undid, journal records included - returning how many
+    /// of its writes were rolled back. What's happened since the body is
+    /// left be: words written since keep their new contents. If what was
+    /// journalled before the body has been undone since, there's nothing
+    /// to put back what it undid on top of, so that part isn't.
+    pub async fn abort<T: Access + Send>(self, mem: &mut JournalingMemory<T>) -> usize {
+        let first = mem
+            .journal
+            .partition_point(|record| record.stamp < self.stamps.start);
+        let end = mem
+            .journal
+            .partition_point(|record| record.stamp < self.stamps.end);
+        let later: BTreeSet<u64> = mem.journal[end..]
+            .iter()
+            .flat_map(|record| (0..record.new.len() as u64).map(|i| record.loc.wrapping_add(i)))
+            .collect();
+        for (loc, old) in self.images.iter().rev() {
+            for (i, word) in old.iter().enumerate() {
+                let at = loc.wrapping_add(i as u64);
+                if !later.contains(&at) {
+                    let was = mem.inner.read_64(at).await;
+                    mem.note_image(at, &[was]);
+                    mem.inner.write_64(at, *word).await;
+                }
+            }
+        }
+        // Put the journal back as it was before the body, with whatever
+        // came after on top.
+        let reinstate = first == self.mark;
+        let popped = if reinstate { self.popped } else { Vec::new() };
+        let delta = popped.len() as isize - (end - first) as isize;
+        let shift = |index: usize| (index as isize + delta) as usize;
+        mem.journal.splice(first..end, popped);
+        let mut steps: Vec<usize> = if reinstate {
+            self.steps
+        } else {
+            mem.steps.iter().copied().filter(|s| *s < first).collect()
+        };
+        steps.extend(mem.steps.iter().filter(|s| **s >= end).map(|s| shift(*s)));
+        mem.steps = steps;
+        if let Some(start) = mem.instruction.as_mut() {
+            *start = if *start >= end {
+                shift(*start)
+            } else {
+                (*start).min(first)
+            };
+        }
+        end - first
+    }
+}
+
+#[async_trait]
+impl<T: Access + Send> Access for JournalingMemory<T> {
+    async fn read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        self.inner.read(loc, span).await
+    }
+
+    async fn write(&mut self, loc: u64, contents: &[u64]) {
+        let old = self.inner.read(loc, contents.len() as u32).await;
+        self.note_image(loc, &old);
+        self.inner.write(loc, contents).await;
+        if self.skip_noop_writes && old == contents {
+            return;
+        }
+        self.record(loc, old, contents.to_vec());
+    }
+
+    async fn read_64(&mut self, loc: u64) -> u64 {
+        self.inner.read_64(loc).await
+    }
+
+    async fn write_64(&mut self, loc: u64, val: u64) {
+        self.write(loc, &[val]).await
+    }
+
+    fn is_mapped(&self, loc: u64) -> bool {
+        self.inner.is_mapped(loc)
+    }
+
+    async fn try_read_64(&mut self, loc: u64) -> Result<u64, MemError> {
+        self.inner.try_read_64(loc).await
+    }
+
+    /// Only the part of a short write which was stored is journalled. We
+    /// can't journal what we can't read, so a write over anything which
+    /// won't read back fails without storing anything.
+    async fn try_write(&mut self, loc: u64, contents: &[u64]) -> Result<usize, MemError> {
+        let mut old = Vec::with_capacity(contents.len());
+        for i in 0..contents.len() as u64 {
+            old.push(self.inner.try_read_64(loc.wrapping_add(i)).await?);
+        }
+        let stored = self.inner.try_write(loc, contents).await?;
+        self.note_image(loc, &old[..stored]);
+        if !(self.skip_noop_writes && old[..stored] == contents[..stored]) {
+            self.record(loc, old[..stored].to_vec(), contents[..stored].to_vec());
+        }
+        Ok(stored)
+    }
+
+    /// A write which faults isn't journalled.
+    async fn try_write_64(&mut self, loc: u64, val: u64) -> Result<(), MemError> {
+        let old = self.inner.try_read_64(loc).await?;
+        self.inner.try_write_64(loc, val).await?;
+        self.note_image(loc, &[old]);
+        if !(self.skip_noop_writes && old == val) {
+            self.record(loc, vec![old], vec![val]);
+        }
+        Ok(())
+    }
+}
+
+/// What fraction of each (start, length) region's words a trace of
+/// (loc, length) accesses touched - how much of a buffer a program actually
+/// used. For writes, pass it `JournalingMemory::journal` mapped through
+/// `UndoRecord::span` (writes since undone have left the journal, so they
+/// don't count); for reads too, `BoundsTracker::accesses`. Touching a word
+/// more than once counts once; an empty region counts as wholly covered.
+pub fn coverage(trace: impl IntoIterator<Item = (u64, u64)>, regions: &[(u64, u64)]) -> Vec<f64> {
+    let mut touched: Vec<(u64, u64)> = trace
+        .into_iter()
+        .filter(|(_, len)| *len > 0)
+        .map(|(loc, len)| (loc, loc.saturating_add(len)))
+        .collect();
+    touched.sort_unstable();
+    regions
+        .iter()
+        .map(|&(start, len)| {
+            if len == 0 {
+                return 1.0;
+            }
+            let end = start.saturating_add(len);
+            // Walk the accesses in order, counting each word past the
+            // furthest one counted so far.
+            let (mut covered, mut upto) = (0, start);
+            for &(from, to) in &touched {
+                if from >= end {
+                    break;
+                }
+                let (from, to) = (from.max(upto), to.min(end));
+                if from < to {
+                    covered += to - from;
+                    upto = to;
+                }
+            }
+            covered as f64 / len as f64
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::bounds_tracker::BoundsTracker;
+    use crate::utils::memory::{Perms, TreeMemory};
+
+    #[tokio::test]
+    async fn check_journal() {
+        let mut mem = JournalingMemory::new(TreeMemory::new());
+        mem.write(0x10, &[1, 2]).await;
+        mem.write_64(0x11, 3).await;
+        mem.write(0x0f, &[4, 5]).await;
+        let records: Vec<&UndoRecord> = mem.journal().collect();
+        assert_eq!(
+            records,
+            vec![
+                &UndoRecord {
+                    loc: 0x10,
+                    old: vec![0, 0],
+                    new: vec![1, 2],
+                    stamp: 0
+                },
+                &UndoRecord {
+                    loc: 0x11,
+                    old: vec![2],
+                    new: vec![3],
+                    stamp: 1
+                },
+                &UndoRecord {
+                    loc: 0x0f,
+                    old: vec![0, 1],
+                    new: vec![4, 5],
+                    stamp: 2
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn check_write_order() {
+        let mut mem = JournalingMemory::new(TreeMemory::new());
+        mem.write_64(0x10, 1).await;
+        mem.write(0x20, &[2, 3]).await;
+        mem.write_64(0x10, 4).await;
+        assert!(mem.undo_write().await);
+        mem.write_64(0x30, 5).await;
+        let stamps: Vec<u64> = mem.journal().map(|record| record.stamp).collect();
+        // The undone write's stamp isn't reused.
+        assert_eq!(stamps, vec![0, 1, 3]);
+
+        // The same again matches; swapping two writes doesn't.
+        let replay = |writes: Vec<(u64, Vec<u64>)>| async move {
+            let mut mem = JournalingMemory::new(TreeMemory::new());
+            for (loc, words) in writes {
+                mem.write(loc, &words).await;
+            }
+            mem
+        };
+        let same = replay(vec![(0x10, vec![1]), (0x20, vec![2, 3]), (0x30, vec![5])]).await;
+        assert_eq!(mem.diverges_from(&same), None);
+        let swapped = replay(vec![(0x10, vec![1]), (0x30, vec![5]), (0x20, vec![2, 3])]).await;
+        assert_eq!(mem.diverges_from(&swapped), Some(1));
+        let short = replay(vec![(0x10, vec![1])]).await;
+        assert_eq!(mem.diverges_from(&short), Some(1));
+        assert_eq!(short.diverges_from(&mem), Some(1));
+        let changed = replay(vec![(0x10, vec![1]), (0x20, vec![2, 4]), (0x30, vec![5])]).await;
+        assert_eq!(mem.diverges_from(&changed), Some(1));
+    }
+
+    #[tokio::test]
+    async fn check_undo() {
+        let mut mem = JournalingMemory::new(TreeMemory::new());
+        mem.write_64(0x10, 1).await;
+        mem.write_64(0x10, 2).await;
+        assert!(mem.undo_last().await);
+        assert_eq!(mem.read_64(0x10).await, 1);
+        assert!(mem.undo_last().await);
+        assert_eq!(mem.read_64(0x10).await, 0);
+        assert!(!mem.undo_last().await);
+        assert_eq!(mem.journal().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn check_undo_instruction() {
+        let mut mem = JournalingMemory::new(TreeMemory::new());
+        mem.write_64(0x10, 1).await;
+        // A push: two words of stack and the stack pointer.
+        mem.begin_instruction();
+        mem.write(0x100, &[2, 3]).await;
+        mem.write_64(0x101, 4).await;
+        mem.write_64(0x8, 0x100).await;
+        mem.end_instruction();
+        mem.begin_instruction();
+        mem.end_instruction();
+        assert!(mem.undo_last().await);
+        assert_eq!(mem.read(0x100, 2).await, vec![0, 0]);
+        assert_eq!(mem.read_64(0x8).await, 0);
+        assert_eq!(mem.read_64(0x10).await, 1);
+        assert!(mem.undo_last().await);
+        assert_eq!(mem.read_64(0x10).await, 0);
+        assert!(!mem.undo_last().await);
+
+        // undo_write takes an instruction apart a write at a time.
+        mem.begin_instruction();
+        mem.write_64(0x20, 5).await;
+        mem.write_64(0x21, 6).await;
+        mem.end_instruction();
+        assert!(mem.undo_write().await);
+        assert_eq!(mem.read(0x20, 2).await, vec![5, 0]);
+        assert!(mem.undo_last().await);
+        assert_eq!(mem.read_64(0x20).await, 0);
+        assert!(!mem.undo_write().await);
+    }
+
+    #[tokio::test]
+    async fn check_undo_n() {
+        let mut mem = JournalingMemory::new(TreeMemory::new());
+        for i in 1..=5 {
+            mem.write_64(0x10, i).await;
+        }
+        assert_eq!(mem.undo_n(2).await, 2);
+        assert_eq!(mem.read_64(0x10).await, 3);
+        assert_eq!(mem.undo_n(3).await, 3);
+        assert_eq!(mem.read_64(0x10).await, 0);
+        assert_eq!(mem.undo_n(1).await, 0);
+
+        for i in 1..=3 {
+            mem.write(0x20, &[i, i]).await;
+        }
+        assert_eq!(mem.undo_n(10).await, 3);
+        assert_eq!(mem.read(0x20, 2).await, vec![0, 0]);
+        assert_eq!(mem.journal().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn check_skip_noop_writes() {
+        let mut mem = JournalingMemory::new(TreeMemory::new());
+        mem.write_64(0x10, 7).await;
+        mem.write_64(0x10, 7).await;
+        assert_eq!(mem.journal().count(), 2);
+
+        let mut mem = JournalingMemory::new(TreeMemory::new());
+        mem.set_skip_noop_writes(true);
+        mem.write_64(0x10, 7).await;
+        mem.write_64(0x10, 7).await;
+        // Partly the same still counts as a change.
+        mem.write(0x10, &[7, 8]).await;
+        assert_eq!(mem.journal().count(), 2);
+        assert_eq!(mem.undo_n(2).await, 2);
+        assert_eq!(mem.read(0x10, 2).await, vec![0, 0]);
+    }
+
+    #[tokio::test]
+    async fn check_faulting_writes_not_journalled() {
+        let mut tree = TreeMemory::new();
+        tree.set_permissions(0x10, 1, Perms::READ_ONLY);
+        let mut mem = JournalingMemory::new(tree);
+        assert_eq!(
+            mem.try_write_64(0x10, 1).await,
+            Err(MemError::PermissionDenied)
+        );
+        assert_eq!(mem.try_write_64(0x11, 2).await, Ok(()));
+        assert_eq!(mem.journal().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn check_unreadable_writes_fail() {
+        let mut tree = TreeMemory::new();
+        tree.set_permissions(0x22, 1, Perms::WRITE_ONLY);
+        let mut mem = JournalingMemory::new(tree);
+        assert_eq!(
+            mem.try_write(0x20, &[1, 2, 3]).await,
+            Err(MemError::PermissionDenied)
+        );
+        assert_eq!(mem.journal().count(), 0);
+        assert!(!mem.inner().is_mapped(0x20));
+        assert_eq!(mem.try_write(0x23, &[4, 5]).await, Ok(2));
+        assert_eq!(mem.journal().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn check_transaction() {
+        let mut mem = JournalingMemory::new(TreeMemory::new());
+        mem.write_64(0x10, 1).await;
+        let (sum, txn) = mem
+            .transaction(|m| {
+                Box::pin(async move {
+                    m.write_64(0x10, 2).await;
+                    m.write(0x20, &[3, 4]).await;
+                    m.read_64(0x10).await + m.read_64(0x21).await
+                })
+            })
+            .await;
+        assert_eq!(sum, 6);
+        assert_eq!(txn.abort(&mut mem).await, 2);
+        assert_eq!(mem.read_64(0x10).await, 1);
+        assert_eq!(mem.read(0x20, 2).await, vec![0, 0]);
+        // Writes before the transaction are still there to undo.
+        assert_eq!(mem.journal().count(), 1);
+
+        let ((), txn) = mem
+            .transaction(|m| Box::pin(async move { m.write_64(0x10, 5).await }))
+            .await;
+        txn.commit();
+        assert_eq!(mem.read_64(0x10).await, 5);
+        assert_eq!(mem.journal().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn check_transaction_which_undoes() {
+        let mut mem = JournalingMemory::new(TreeMemory::new());
+        mem.write_64(4, 1).await;
+        mem.write_64(5, 2).await;
+        let ((), txn) = mem
+            .transaction(|m| {
+                Box::pin(async move {
+                    assert!(m.undo_last().await);
+                    m.write_64(5, 9).await;
+                })
+            })
+            .await;
+        // Shorter than when it started, but it still wrote.
+        assert_eq!(mem.journal().count(), 2);
+        // Since the body: kept, not rolled back with it.
+        mem.write_64(6, 3).await;
+        assert_eq!(txn.abort(&mut mem).await, 1);
+        assert_eq!(mem.read(4, 3).await, vec![1, 2, 3]);
+        let stamps: Vec<u64> = mem.journal().map(|r| r.stamp).collect();
+        assert_eq!(stamps, vec![0, 1, 3]);
+        // The undone write is back in the journal, as its own step.
+        assert!(mem.undo_last().await);
+        assert!(mem.undo_last().await);
+        assert_eq!(mem.read(4, 3).await, vec![1, 0, 0]);
+        assert!(mem.undo_last().await);
+        assert!(!mem.undo_last().await);
+    }
+
+    #[tokio::test]
+    async fn check_short_write_journalled() {
+        let mut tree = TreeMemory::new();
+        tree.set_resident_quota(0x1_0000);
+        let mut mem = JournalingMemory::new(tree);
+        assert_eq!(mem.try_write(0xffff, &[1, 2]).await, Ok(1));
+        let record = mem.journal().next().unwrap();
+        assert_eq!((record.old.len(), record.new.len()), (1, 1));
+        assert!(mem.undo_last().await);
+        assert_eq!(mem.read_64(0xffff).await, 0);
+    }
+
+    #[tokio::test]
+    async fn check_coverage() {
+        let mut mem = JournalingMemory::new(TreeMemory::new());
+        mem.write(0x100, &[1; 4]).await;
+        mem.write_64(0x106, 2).await;
+        mem.write(0x103, &[3; 2]).await;
+        mem.write(0x10e, &[4; 3]).await;
+        mem.write(0x2000, &[5; 8]).await;
+        // 0x100-0x104, 0x106 and 0x10e-0x10f; 0x110 is past the end.
+        let regions = [(0x100, 0x10), (0x2000, 0x10), (0x3000, 4), (0x3000, 0)];
+        let spans = || mem.journal().map(UndoRecord::span);
+        assert_eq!(coverage(spans(), &regions), vec![0.5, 0.5, 0.0, 1.0]);
+        // Undone writes don't count.
+        mem.undo_last().await;
+        let spans = || mem.journal().map(UndoRecord::span);
+        assert_eq!(coverage(spans(), &regions[1..2]), vec![0.0]);
+        assert_eq!(coverage(spans(), &[(0x100, 8)]), vec![0.75]);
+
+        // Reads count too, given a record of them.
+        let mut mem = BoundsTracker::new(TreeMemory::new());
+        mem.record_accesses(true);
+        mem.write(0x100, &[1; 2]).await;
+        mem.read(0x102, 2).await;
+        mem.read_64(0x106).await;
+        mem.read(0x200, 0).await;
+        let accesses = mem.accesses().iter().copied();
+        assert_eq!(coverage(accesses, &[(0x100, 8)]), vec![0.625]);
+    }
+}
@@ -1,6 +1,30 @@
+#[cfg(feature = "std")]
+use crate::utils::pod::{self, Pod};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    format,
+    string::String,
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+#[cfg(feature = "std")]
 use async_trait::async_trait;
-use std::{cell::RefCell, fmt};
+use core::{
+    cell::{Cell, RefCell},
+    fmt,
+};
 
+/// Note a memory event, if built with the `log` feature.
+macro_rules! mem_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "log")]
+        log::debug!(target: "revproc1::memory", $($arg)*);
+    };
+}
+
+#[cfg(feature = "std")]
 #[async_trait]
 pub trait Access {
     /// Read some words - you could use internal mutability here,
@@ -9,231 +33,6086 @@ pub trait Access {
     async fn read(&mut self, loc: u64, span: u32) -> Vec<u64>;
 
     /// Write some words.
-    async fn write(&mut self, loc: u64, contents: &Vec<u64>);
+    async fn write(&mut self, loc: u64, contents: &[u64]);
 
     /// Utility functions
     async fn read_64(&mut self, loc: u64) -> u64;
     async fn write_64(&mut self, loc: u64, val: u64);
+
+    /// Is loc backed by real storage, as opposed to reading as a default?
+    /// Never faults anything in. Backends with no notion of unmapped
+    /// memory say yes.
+    fn is_mapped(&self, _loc: u64) -> bool {
+        true
+    }
+
+    /// As read_64/write_64, but surfacing faults (permissions, quota, ...)
+    /// rather than panicking. Backends which can't fault needn't bother.
+    async fn try_read_64(&mut self, loc: u64) -> Result<u64, MemError> {
+        Ok(self.read_64(loc).await)
+    }
+
+    async fn try_write_64(&mut self, loc: u64, val: u64) -> Result<(), MemError> {
+        self.write_64(loc, val).await;
+        Ok(())
+    }
+
+    /// Write some words, returning how many were actually stored - fewer
+    /// than contents.len() if the write faulted part way through. A fault
+    /// before anything was stored is an error.
+    async fn try_write(&mut self, loc: u64, contents: &[u64]) -> Result<usize, MemError> {
+        self.write(loc, contents).await;
+        Ok(contents.len())
+    }
+
+    /// Drive either operation by value of `MemOp` - convenient for code
+    /// which stores operations uniformly (replay/record).
+    /// Reads fill `iovec`; writes store it.
+    async fn iop(&mut self, loc: u64, iovec: &mut [u64], op: MemOp) {
+        match op {
+            MemOp::Read => {
+                let data = self.read(loc, iovec.len() as u32).await;
+                iovec.copy_from_slice(&data);
+            }
+            MemOp::Write => self.write(loc, iovec).await,
+            MemOp::ReadModifyWrite(f) => {
+                let old = self.read(loc, iovec.len() as u32).await;
+                let new: Vec<u64> = old.iter().map(|word| f(*word)).collect();
+                self.write(loc, &new).await;
+                iovec.copy_from_slice(&old);
+            }
+        }
+    }
+
+    /// Store val at loc and return what was there - an atomic exchange,
+    /// wherever the backend's `iop` does read-modify-writes atomically.
+    async fn swap(&mut self, loc: u64, val: u64) -> u64 {
+        let mut old = [0];
+        self.iop(
+            loc,
+            &mut old,
+            MemOp::ReadModifyWrite(Box::new(move |_| val)),
+        )
+        .await;
+        old[0]
+    }
+
+    /// Read a bit_len bit field starting bit_offset bits into the word at
+    /// loc (bit 0 being the LSB), which may straddle into the next word.
+    async fn read_bits(&mut self, loc: u64, bit_offset: u32, bit_len: u32) -> u64 {
+        let (loc, offset) = bit_position(loc, bit_offset, bit_len);
+        let mut val = self.read_64(loc).await >> offset;
+        if offset + bit_len > 64 {
+            val |= self.read_64(loc.wrapping_add(1)).await << (64 - offset);
+        }
+        val & bit_mask(bit_len)
+    }
+
+    /// Write a bit field (as read_bits), leaving the rest of the word(s) be.
+    async fn write_bits(&mut self, loc: u64, bit_offset: u32, bit_len: u32, val: u64) {
+        let (loc, offset) = bit_position(loc, bit_offset, bit_len);
+        let val = val & bit_mask(bit_len);
+        let mask = bit_mask(bit_len) << offset;
+        let word = self.read_64(loc).await;
+        self.write_64(loc, (word & !mask) | (val << offset)).await;
+        if offset + bit_len > 64 {
+            let next = loc.wrapping_add(1);
+            let mask = bit_mask(offset + bit_len - 64);
+            let word = self.read_64(next).await;
+            self.write_64(next, (word & !mask) | (val >> (64 - offset)))
+                .await;
+        }
+    }
+
+    /// Set bit (0..64, 0 being the LSB) of the word at loc.
+    async fn set_bit(&mut self, loc: u64, bit: u32) {
+        assert!(bit < 64, "bit {bit} is outside a word");
+        let word = self.read_64(loc).await;
+        self.write_64(loc, word | (1 << bit)).await;
+    }
+
+    /// Clear bit of the word at loc.
+    async fn clear_bit(&mut self, loc: u64, bit: u32) {
+        assert!(bit < 64, "bit {bit} is outside a word");
+        let word = self.read_64(loc).await;
+        self.write_64(loc, word & !(1 << bit)).await;
+    }
+
+    /// Is bit of the word at loc set?
+    async fn test_bit(&mut self, loc: u64, bit: u32) -> bool {
+        assert!(bit < 64, "bit {bit} is outside a word");
+        (self.read_64(loc).await >> bit) & 1 == 1
+    }
+
+    /// Read a plain-old-data value (a register block, a descriptor, ...)
+    /// out of the words from loc, as if they were laid out in host memory.
+    async fn read_pod<T: Pod>(&mut self, loc: u64) -> T
+    where
+        Self: Sized,
+    {
+        let words = self.read(loc, pod::pod_words::<T>() as u32).await;
+        pod::pod_from_words(&words)
+    }
+
+    /// Write a plain-old-data value from loc. If it doesn't fill the last
+    /// word, the rest of that word is left as it was.
+    async fn write_pod<T: Pod>(&mut self, loc: u64, val: &T)
+    where
+        Self: Sized,
+    {
+        let count = pod::pod_words::<T>();
+        let mut words = if core::mem::size_of::<T>().is_multiple_of(8) {
+            vec![0; count]
+        } else {
+            self.read(loc, count as u32).await
+        };
+        pod::pod_into_words(val, &mut words);
+        self.write(loc, &words).await;
+    }
+
+    /// Is every one of count words from loc zero? Stops at the first which
+    /// isn't. This reads a chunk at a time; backends which know what's
+    /// unmapped can skip it without reading.
+    async fn is_zero(&mut self, loc: u64, count: u64) -> bool {
+        let mut done = 0;
+        while done < count {
+            let chunk = (count - done).min(COPY_CHUNK_WORDS);
+            let words = self.read(loc.wrapping_add(done), chunk as u32).await;
+            if words.iter().any(|word| *word != 0) {
+                return false;
+            }
+            done += chunk;
+        }
+        true
+    }
+}
+
+/// Fold whole words of a bit offset into the location.
+#[cfg(feature = "std")]
+pub(crate) fn bit_position(loc: u64, bit_offset: u32, bit_len: u32) -> (u64, u32) {
+    assert!(bit_len <= 64, "bit fields are at most a word wide");
+    (loc.wrapping_add((bit_offset / 64) as u64), bit_offset % 64)
+}
+
+#[cfg(feature = "std")]
+fn bit_mask(bits: u32) -> u64 {
+    if bits >= 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+/// A synchronous counterpart to `Access`, for when there's no executor
+/// around (or no std at all).
+pub trait SyncAccess {
+    fn sync_read(&mut self, loc: u64, span: u32) -> Vec<u64>;
+    fn sync_write(&mut self, loc: u64, contents: &[u64]);
+
+    fn sync_read_64(&mut self, loc: u64) -> u64 {
+        self.sync_read(loc, 1)[0]
+    }
+
+    fn sync_write_64(&mut self, loc: u64, val: u64) {
+        self.sync_write(loc, &[val]);
+    }
+}
+
+/// Reading through a shared borrow, so nothing can change - not even a
+/// leaf faulting in. For handing memory to things which only look at it,
+/// like a disassembler.
+pub trait ReadAccess {
+    fn peek(&self, loc: u64, span: u32) -> Result<Vec<u64>, MemError>;
+
+    fn peek_64(&self, loc: u64) -> Result<u64, MemError> {
+        Ok(self.peek(loc, 1)?[0])
+    }
 }
 
-#[derive(Debug)]
 pub enum MemOp {
     Read,
     Write,
+    /// Replace each word w with f(w), leaving the old words in the iovec -
+    /// in one walk of the tree, which matters for atomics on deep trees.
+    ReadModifyWrite(Box<dyn Fn(u64) -> u64 + Send + Sync>),
 }
 
-enum MemorySegment {
-    Nothing(),
-    Next(Vec<RefCell<MemorySegment>>),
-    Memory(RefCell<Vec<u64>>),
+impl fmt::Debug for MemOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemOp::Read => write!(f, "Read"),
+            MemOp::Write => write!(f, "Write"),
+            MemOp::ReadModifyWrite(_) => write!(f, "ReadModifyWrite(..)"),
+        }
+    }
 }
 
-impl MemorySegment {
-    fn new_memory(mem_bits: u32) -> MemorySegment {
-        MemorySegment::Memory(RefCell::new(vec![0; 1 << mem_bits]))
-    }
-    fn new_segment(seg_bits: u32) -> MemorySegment {
-        // I don't really want to implement Copy() for MemorySegments, so ...
-        let mut result: Vec<RefCell<MemorySegment>> = Vec::with_capacity((1 << seg_bits) as usize);
-        for _ in 0..(1 << seg_bits) {
-            result.push(RefCell::new(MemorySegment::Nothing()));
+/// Things that can go wrong with a memory access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemError {
+    /// Faulting in a new leaf would take us over the resident quota.
+    QuotaExceeded,
+    /// The level widths and leaf bits don't describe an address of at
+    /// most 64 bits.
+    BadGeometry,
+    /// Asked for a snapshot we don't have.
+    NoSuchSnapshot,
+    /// The address is beyond what the geometry covers.
+    OutOfRange,
+    /// Read unmapped memory under `UnmappedPolicy::Fault`.
+    Unmapped,
+    /// The access isn't allowed by the permissions on its range.
+    PermissionDenied,
+    /// The access is longer than `TreeMemory::set_max_span` allows.
+    SpanTooLarge,
+    /// `TreeMemory::merge_from` would have overwritten mapped memory, under
+    /// `ConflictPolicy::Error`; or `TreeMemory::alias` was asked for an
+    /// alias which would overlap another, or itself.
+    Conflict,
+}
+
+/// What `TreeMemory::merge_from` does about words which are already
+/// mapped (in resident leaves) here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Copy over them.
+    Overwrite,
+    /// Leave them be, and only copy into unmapped memory.
+    KeepExisting,
+    /// Fail with `MemError::Conflict`, before copying anything.
+    Error,
+}
+
+/// What accesses a range of memory allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Perms {
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Perms {
+    pub const NONE: Perms = Perms {
+        read: false,
+        write: false,
+    };
+    pub const READ_ONLY: Perms = Perms {
+        read: true,
+        write: false,
+    };
+    pub const WRITE_ONLY: Perms = Perms {
+        read: false,
+        write: true,
+    };
+    pub const READ_WRITE: Perms = Perms {
+        read: true,
+        write: true,
+    };
+
+    fn allows(&self, is_write: bool) -> bool {
+        if is_write {
+            self.write
+        } else {
+            self.read
         }
-        MemorySegment::Next(result)
     }
 }
 
-impl fmt::Debug for MemorySegment {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut dbg = f.debug_struct("MemorySegment");
-        match self {
-            MemorySegment::Nothing() => {
-                dbg.field("Nothing", &"nothing");
-                ()
-            }
-            MemorySegment::Next(segment) => {
-                let mut idx = 0;
-                for mem in segment {
-                    match *mem.borrow() {
-                        MemorySegment::Nothing() => (),
-                        _ => {
-                            dbg.field(&format!("Seg {idx}"), mem);
-                            ()
-                        }
-                    }
-                    idx += 1;
+/// What a fault handler wants done about an access the permissions deny;
+/// see `TreeMemory::set_fault_handler`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultAction {
+    /// Let it fail with `MemError::PermissionDenied`.
+    Deny,
+    /// Set count words from loc to perms (say, map the page in), then try
+    /// the access again.
+    Remap { loc: u64, count: u64, perms: Perms },
+}
+
+/// Called with the first word an access may not touch, the access, and
+/// that word's permissions.
+pub type FaultHandler = Box<dyn FnMut(u64, &MemOp, Perms) -> FaultAction + Send>;
+
+/// How many times we'll let a fault handler remap and retry one access
+/// before giving up on it.
+pub const FAULT_RETRIES: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HazardKind {
+    /// A write to a word we recently read.
+    WriteAfterRead,
+    /// A read of a word we recently wrote.
+    ReadAfterWrite,
+}
+
+/// A potential hazard: `loc` was accessed the other way `distance`
+/// accesses ago.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hazard {
+    pub loc: u64,
+    pub kind: HazardKind,
+    pub distance: u64,
+}
+
+/// Remembers the last op on each word. This grows with the number of
+/// distinct words touched, so it's a debugging aid rather than something
+/// to leave on.
+struct HazardTracker {
+    window: u64,
+    seq: u64,
+    /// word => (was it a write?, seq of that access)
+    last: BTreeMap<u64, (bool, u64)>,
+    hook: Box<dyn FnMut(Hazard) + Send>,
+}
+
+impl HazardTracker {
+    fn note(&mut self, address: u64, len: usize, is_write: bool) {
+        self.seq += 1;
+        for offset in 0..len as u64 {
+            let loc = address.wrapping_add(offset);
+            if let Some((was_write, then)) = self.last.insert(loc, (is_write, self.seq)) {
+                let distance = self.seq - then;
+                if was_write != is_write && distance <= self.window {
+                    let kind = if is_write {
+                        HazardKind::WriteAfterRead
+                    } else {
+                        HazardKind::ReadAfterWrite
+                    };
+                    (self.hook)(Hazard {
+                        loc,
+                        kind,
+                        distance,
+                    });
                 }
             }
-            MemorySegment::Memory(vec) => {
-                dbg.field("Memory", &vec.borrow().len());
-                ()
-            }
         }
-        dbg.finish()
     }
 }
 
-/// A tree memory, populated on demand.
-/// Each level of the tree supplies bits_per_segment bits, and each
-/// element is either a memory array, a pointer to another table, or nothing.
-/// By default we put memory itself at the leaves.
-pub struct TreeMemory {
-    /// Root of the tree.
-    root: RefCell<MemorySegment>,
-    /// Bits per segment - size of the tables
-    bits_per_segment: u32,
-    /// How deep is the tree?
-    max_depth: u32,
-    /// Bits in an end index - cached here for convenience
-    mem_bits: u32,
+/// Words read and written per address bin; see `TreeMemory::enable_heatmap`.
+struct Heatmap {
+    start: u64,
+    /// Last word covered.
+    last: u64,
+    bin_words: u64,
+    reads: Vec<u64>,
+    writes: Vec<u64>,
 }
 
-impl TreeMemory {
-    pub fn new() -> TreeMemory {
-        TreeMemory {
-            root: RefCell::new(MemorySegment::Nothing()),
-            /// 4096 element per array
-            bits_per_segment: 12,
-            /// 48 bits => 1MiB segments - a bit small, but ...
-            max_depth: 5,
-            mem_bits: (64 - (12 * 4)),
+impl Heatmap {
+    fn note(&mut self, address: u64, len: usize, is_write: bool) {
+        if len == 0 {
+            return;
+        }
+        let from = address.max(self.start);
+        let to = address.saturating_add(len as u64 - 1).min(self.last);
+        if from > to {
+            return;
+        }
+        let counts = if is_write {
+            &mut self.writes
+        } else {
+            &mut self.reads
+        };
+        // Share the access out between the bins it covers.
+        let mut at = from;
+        loop {
+            let bin = (at - self.start) / self.bin_words;
+            let bin_last = (self.start + bin * self.bin_words).saturating_add(self.bin_words - 1);
+            let piece_last = bin_last.min(to);
+            counts[bin as usize] += piece_last - at + 1;
+            if piece_last == to {
+                break;
+            }
+            at = piece_last + 1;
         }
     }
+}
 
-    /// Perform an iop against a segment
-    /// iops must be aligned within a single memory segments - splitting them
-    /// happens at the cache layer (to simulate a segmented memory architecture)
-    pub fn iop(&mut self, address: u64, iovec: &mut Vec<u64>, op: MemOp) {
-        self.run_op(&self.root, address, iovec, &op, 1);
+/// The resident contents of a `TreeMemory` at some point in time. This
+/// shares structure with the memory it came from - taking one is O(1), and
+/// the memory copies a table or leaf only when it next writes to it.
+#[derive(Clone)]
+pub struct MemorySnapshot {
+    root: MemorySegment,
+    /// Geometry of the tree it's from, so we only restore into the same.
+    level_bits: Vec<u32>,
+    mem_bits: u32,
+    /// Words held in its leaves.
+    words: u64,
+}
+
+/// A copy of just one window of a `TreeMemory` - a device's registers, or
+/// a stack; see `TreeMemory::snapshot_range`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeSnapshot {
+    loc: u64,
+    span: u64,
+    /// The resident parts of the window, as (start, words), ascending.
+    pieces: Vec<(u64, Vec<u64>)>,
+}
+
+/// A read-only view of a `TreeMemory`, from `TreeMemory::snapshot_view`.
+/// Reads honour the geometry, read permissions and unmapped and
+/// out-of-range policies, but fault nothing in and note nothing (hazards,
+/// heatmap, hooks), so any number can be held at once.
+#[derive(Clone, Copy)]
+pub struct MemoryView<'a> {
+    mem: &'a TreeMemory,
+}
+
+impl MemoryView<'_> {
+    pub fn is_mapped(&self, loc: u64) -> bool {
+        self.mem.is_mapped(loc)
     }
+}
 
-    fn run_op(
-        &self,
-        parent: &RefCell<MemorySegment>,
-        address: u64,
-        iovec: &mut Vec<u64>,
-        op: &MemOp,
-        level: u32,
-    ) {
-        let shift = 64 - (self.bits_per_segment * level);
-        let mask = (1 << self.bits_per_segment) - 1;
-        let idx = (address >> shift) & mask;
-        let final_idx = address & ((1 << self.mem_bits) - 1);
-        // println!("level {level:x} address {address:x} shift {shift} idx {idx:x} mask {mask:x} final {final_idx:x} op {op:?}");
-
-        //println!("run_op level {level}, op {op:?} node {parent:?}");
-        let fault_in = match op {
-            MemOp::Read => match &*parent.borrow() {
-                MemorySegment::Nothing() => false,
-                MemorySegment::Next(next_seg) => {
-                    self.run_op(&next_seg[idx as usize], address, iovec, op, level + 1);
-                    false
-                }
-                MemorySegment::Memory(mem) => {
-                    let src = mem.borrow();
-                    let src_iter = src[(final_idx as usize)..].into_iter();
-                    let dst_iter = iovec.iter_mut();
-                    for (dst_i, src_i) in dst_iter.zip(src_iter) {
-                        *dst_i = *src_i;
-                    }
-                    false
+impl ReadAccess for MemoryView<'_> {
+    fn peek(&self, loc: u64, span: u32) -> Result<Vec<u64>, MemError> {
+        let (mem, mem_bits) = (self.mem, self.mem.config.mem_bits);
+        let fill = mem.config.unmapped_fill.unwrap_or(mem.config.default_fill);
+        let mut words = vec![0; span as usize];
+        let (mut rest, mut addr) = (&mut words[..], loc);
+        while !rest.is_empty() {
+            let piece = leaf_room(addr, mem_bits).min(rest.len() as u64);
+            let (out, tail) = rest.split_at_mut(piece as usize);
+            if let Err(err) = mem.check_range(addr, piece) {
+                if mem.config.out_of_range_policy == OutOfRangePolicy::Fault {
+                    return Err(err);
                 }
-            },
-            MemOp::Write => match &*parent.borrow() {
-                MemorySegment::Nothing() => true,
-                MemorySegment::Next(next_seg) => {
-                    self.run_op(&next_seg[idx as usize], address, iovec, op, level + 1);
-                    false
-                }
-                MemorySegment::Memory(mem) => {
-                    let mut dst = mem.borrow_mut();
-                    let src_iter = iovec.into_iter();
-                    let dst_iter = dst[(final_idx as usize)..].iter_mut();
-                    for (dst_i, src_i) in dst_iter.zip(src_iter) {
-                        *dst_i = *src_i;
+                out.fill(fill);
+            } else {
+                mem.check_perms(addr, piece, false)?;
+                match mem.leaf_at(addr) {
+                    Some(leaf) => leaf.read_words(leaf_offset(addr, mem_bits), out),
+                    None if mem.config.unmapped_policy == UnmappedPolicy::Fault => {
+                        return Err(MemError::Unmapped)
                     }
-                    false
+                    None => out.fill(fill),
                 }
-            },
-        };
-
-        if fault_in {
-            // If we get here, we are writing and need to replace parent.
-            if level == self.max_depth - 1 {
-                //println!("Replacing with memory");
-                parent.replace(MemorySegment::new_memory(self.mem_bits));
-            } else {
-                //println!("Replacing with indirection");
-                parent.replace(MemorySegment::new_segment(self.mem_bits));
             }
-            //println!("Got {parent:?}");
-            // And try again
-            self.run_op(parent, address, iovec, op, level);
+            (rest, addr) = (tail, addr.wrapping_add(piece));
         }
+        Ok(words)
     }
 }
 
-#[async_trait]
-impl Access for TreeMemory {
-    async fn read(&mut self, loc: u64, span: u32) -> Vec<u64> {
-        let mut iovec = vec![0; span as usize];
-        self.iop(loc, &mut iovec, MemOp::Read);
-        iovec
+impl RangeSnapshot {
+    pub fn loc(&self) -> u64 {
+        self.loc
     }
 
-    async fn write(&mut self, loc: u64, contents: &Vec<u64>) {
-        let mut a_spurious_copy = contents.clone();
-        self.iop(loc, &mut a_spurious_copy, MemOp::Write);
+    pub fn span(&self) -> u64 {
+        self.span
     }
+}
 
-    async fn read_64(&mut self, loc: u64) -> u64 {
-        self.read(loc, 1).await[0]
+impl MemorySnapshot {
+    /// Rebuild a snapshot for memories with config's geometry from
+    /// (leaf base, leaf words) pairs - say, ones read back from disk.
+    /// Nothing is checked here; `TreeMemory::validate` will tell you if
+    /// they made a sensible tree once restored.
+    pub fn from_regions(config: &TreeConfig, regions: Vec<(u64, Vec<u64>)>) -> MemorySnapshot {
+        let mut root = MemorySegment::Nothing();
+        let mut words = 0;
+        for (base, leaf) in regions {
+            words += leaf.len() as u64;
+            install_leaf(
+                &mut root,
+                config.table_strategy,
+                &config.level_bits,
+                base,
+                config.address_bits(),
+                Leaf::Words(leaf),
+            );
+        }
+        MemorySnapshot {
+            root,
+            level_bits: config.level_bits.clone(),
+            mem_bits: config.mem_bits,
+            words,
+        }
     }
 
-    async fn write_64(&mut self, loc: u64, val: u64) {
-        let iovec = vec![val];
-        self.write(loc, &iovec).await;
+    /// How many leaves were resident?
+    pub fn leaf_count(&self) -> usize {
+        self.leaves().len()
+    }
+
+    /// (leaf base, leaf words) for each resident leaf, ascending.
+    pub fn leaves(&self) -> Vec<(u64, &[u64])> {
+        let mut leaves = Vec::new();
+        let bits = self.level_bits.iter().sum::<u32>() + self.mem_bits;
+        walk_leaves(&self.root, &self.level_bits, 0, bits, &mut |base, words| {
+            leaves.push((base, words))
+        });
+        leaves
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::utils::memory;
-    #[test]
-    fn create_memory() {
-        let _mem = memory::TreeMemory::new();
+impl PartialEq for MemorySnapshot {
+    fn eq(&self, other: &Self) -> bool {
+        self.level_bits == other.level_bits
+            && self.mem_bits == other.mem_bits
+            && self.leaves() == other.leaves()
     }
+}
 
-    #[test]
-    fn check_io() {
-        let mut mem = memory::TreeMemory::new();
-        let mut some_data: Vec<u64> = vec![2, 34, 67, 0x898, 0x12345678];
-        mem.iop(0, &mut some_data, MemOp::Write);
-        let mut other_data: Vec<u64> = vec![0; 16];
-        mem.iop(0, &mut other_data, MemOp::Read);
-        assert_eq!(some_data, other_data[0..some_data.len()]);
+impl Eq for MemorySnapshot {}
+
+impl fmt::Debug for MemorySnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemorySnapshot")
+            .field("leaves", &self.leaf_count())
+            .field("words", &self.words)
+            .finish()
     }
+}
 
-    #[tokio::test]
-    async fn check_interface() {
-        let mut mem = memory::TreeMemory::new();
-        let mut data_out = vec![238];
-        mem.iop(0, &mut data_out, MemOp::Write);
-        let mut data_out2 = vec![45678];
-        mem.iop(0, &mut data_out2, MemOp::Write);
-        assert_eq!(mem.read_64(0).await, 45678);
-        let data_out3 = vec![12345];
-        mem.write(0, &data_out3).await;
-        assert_eq!(mem.read_64(0).await, 12345);
+/// What's allocated at one level of the tree (level 1 being the root).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LevelFootprint {
+    /// Tables of pointers to the next level.
+    pub tables: u64,
+    /// Child slots held by those tables - every entry of a dense table,
+    /// only the populated ones of a sparse one.
+    pub slots: u64,
+    /// Leaves of memory.
+    pub leaves: u64,
+    /// Roughly what those cost, in bytes.
+    pub bytes: u64,
+}
 
-        mem.write_64(0, 0x45788).await;
-        let mut data_in = vec![0];
-        mem.iop(0, &mut data_in, MemOp::Read);
-        assert_eq!(data_in[0], 0x45788);
-        assert_eq!(mem.read_64(0).await, 0x45788);
-        mem.write_64(0x12345678u64, 42).await;
-        assert_eq!(mem.read_64(0).await, 0x45788);
-        assert_eq!(mem.read_64(0x12345678u64).await, 42);
-        assert_eq!(mem.read_64(1).await, 0);
+/// Allocation across the tree, level by level; see `footprint_report`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FootprintReport {
+    /// Indexed by level - 1.
+    pub levels: Vec<LevelFootprint>,
+}
+
+impl FootprintReport {
+    pub fn tables(&self) -> u64 {
+        self.levels.iter().map(|l| l.tables).sum()
+    }
+
+    pub fn slots(&self) -> u64 {
+        self.levels.iter().map(|l| l.slots).sum()
+    }
+
+    pub fn leaves(&self) -> u64 {
+        self.levels.iter().map(|l| l.leaves).sum()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.levels.iter().map(|l| l.bytes).sum()
+    }
+}
+
+/// How scattered the resident memory is; see `fragmentation`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FragReport {
+    /// Separate runs of resident leaves.
+    pub runs: u64,
+    /// Words in resident leaves.
+    pub mapped_words: u64,
+    /// Words from the start of the first run to the end of the last.
+    pub spanned_words: u64,
+}
+
+impl FragReport {
+    /// The fraction of the spanned range which is mapped: 1 for one solid
+    /// run (or nothing at all), heading for 0 as the runs spread out.
+    pub fn density(&self) -> f64 {
+        if self.spanned_words == 0 {
+            return 1.0;
+        }
+        self.mapped_words as f64 / self.spanned_words as f64
+    }
+}
+
+/// A node of the tree. Children are shared (snapshots hold on to them), so
+/// cloning is cheap and writers go through `Arc::make_mut`.
+#[derive(Clone)]
+enum MemorySegment {
+    Nothing(),
+    Next(Arc<Table>),
+    Memory(Arc<Leaf>),
+}
+
+/// Storage for one leaf's words, for leaves which shouldn't just be a
+/// `Vec` - a memory-mapped page, say, or a buffer shared with the host.
+/// Stores lend their words as a slice (`read_ref` and snapshots borrow
+/// them), but every write goes through `write_words`, so a store sees them
+/// all. A store holding its words some other way (compressed, say) would
+/// need to keep a decoded copy to lend.
+pub trait LeafStore: Send + Sync {
+    fn words(&self) -> &[u64];
+
+    /// Store src from offset words into the leaf.
+    fn write_words(&mut self, offset: usize, src: &[u64]);
+
+    /// Copy words from offset into out.
+    fn read_words(&self, offset: usize, out: &mut [u64]) {
+        out.copy_from_slice(&self.words()[offset..offset + out.len()]);
+    }
+
+    /// A copy, for when we write to a leaf a snapshot still shares.
+    fn clone_store(&self) -> Box<dyn LeafStore>;
+}
+
+/// Makes the store for each new leaf, given its length and fill; see
+/// `TreeMemory::set_leaf_factory`.
+pub type LeafFactory = Box<dyn Fn(usize, u64) -> Box<dyn LeafStore> + Send>;
+
+/// A leaf's words: our own vector, or someone else's store.
+enum Leaf {
+    Words(Vec<u64>),
+    Store(Box<dyn LeafStore>),
+}
+
+impl Clone for Leaf {
+    fn clone(&self) -> Self {
+        match self {
+            Leaf::Words(words) => Leaf::Words(words.clone()),
+            Leaf::Store(store) => Leaf::Store(store.clone_store()),
+        }
+    }
+}
+
+impl core::ops::Deref for Leaf {
+    type Target = [u64];
+
+    fn deref(&self) -> &[u64] {
+        match self {
+            Leaf::Words(words) => words,
+            Leaf::Store(store) => store.words(),
+        }
+    }
+}
+
+impl Leaf {
+    /// Fill out from offset, as far as the leaf goes.
+    fn read_words(&self, offset: usize, out: &mut [u64]) {
+        let count = out.len().min(self.len() - offset);
+        match self {
+            Leaf::Words(words) => out[..count].copy_from_slice(&words[offset..offset + count]),
+            Leaf::Store(store) => store.read_words(offset, &mut out[..count]),
+        }
+    }
+
+    /// Store src from offset, as far as the leaf goes.
+    fn write_words(&mut self, offset: usize, src: &[u64]) {
+        let count = src.len().min(self.len() - offset);
+        match self {
+            Leaf::Words(words) => words[offset..offset + count].copy_from_slice(&src[..count]),
+            Leaf::Store(store) => store.write_words(offset, &src[..count]),
+        }
+    }
+}
+
+/// What sparse tables hand out for children they don't have.
+static NOTHING: MemorySegment = MemorySegment::Nothing();
+
+/// The children of a table, stored as `TableStrategy` says.
+#[derive(Clone)]
+enum Table {
+    Dense(Vec<MemorySegment>),
+    /// Only the populated children, by index; the rest are Nothing.
+    Sparse {
+        slots: usize,
+        children: BTreeMap<usize, MemorySegment>,
+    },
+}
+
+impl Table {
+    /// How many children the table covers, populated or not.
+    fn slots(&self) -> usize {
+        match self {
+            Table::Dense(children) => children.len(),
+            Table::Sparse { slots, .. } => *slots,
+        }
+    }
+
+    fn get(&self, idx: usize) -> &MemorySegment {
+        match self {
+            Table::Dense(children) => &children[idx],
+            Table::Sparse { children, .. } => children.get(&idx).unwrap_or(&NOTHING),
+        }
+    }
+
+    /// Sparse tables add an entry for idx if there wasn't one, so `prune`
+    /// after if you might have left it empty.
+    fn get_mut(&mut self, idx: usize) -> &mut MemorySegment {
+        match self {
+            Table::Dense(children) => &mut children[idx],
+            Table::Sparse { children, .. } => children.entry(idx).or_default(),
+        }
+    }
+
+    /// Drop idx's entry from a sparse table if it's empty.
+    fn prune(&mut self, idx: usize) {
+        if let Table::Sparse { children, .. } = self {
+            if matches!(children.get(&idx), Some(MemorySegment::Nothing())) {
+                children.remove(&idx);
+            }
+        }
+    }
+
+    /// (index, child) for each child stored, ascending. Dense tables
+    /// include their empty children.
+    fn iter(&self) -> impl Iterator<Item = (usize, &MemorySegment)> {
+        let (dense, sparse) = match self {
+            Table::Dense(children) => (Some(children.iter().enumerate()), None),
+            Table::Sparse { children, .. } => (None, Some(children.iter())),
+        };
+        dense
+            .into_iter()
+            .flatten()
+            .chain(sparse.into_iter().flatten().map(|(idx, c)| (*idx, c)))
+    }
+
+    /// Child slots actually allocated.
+    fn stored(&self) -> usize {
+        match self {
+            Table::Dense(children) => children.len(),
+            Table::Sparse { children, .. } => children.len(),
+        }
+    }
+
+    /// Roughly what the table costs, in bytes.
+    fn bytes(&self) -> usize {
+        match self {
+            Table::Dense(children) => core::mem::size_of_val(&children[..]),
+            // Near enough for a B-tree: the key and value of each entry.
+            Table::Sparse { children, .. } => {
+                children.len()
+                    * (core::mem::size_of::<usize>() + core::mem::size_of::<MemorySegment>())
+            }
+        }
+    }
+}
+
+impl Default for MemorySegment {
+    fn default() -> Self {
+        MemorySegment::Nothing()
+    }
+}
+
+impl MemorySegment {
+    fn new_memory(mem_bits: u32, fill: u64) -> MemorySegment {
+        MemorySegment::Memory(Arc::new(Leaf::Words(vec![fill; 1 << mem_bits])))
+    }
+    fn new_segment(strategy: TableStrategy, seg_bits: u32) -> MemorySegment {
+        MemorySegment::Next(Arc::new(match strategy {
+            TableStrategy::Dense => Table::Dense(vec![MemorySegment::Nothing(); 1 << seg_bits]),
+            TableStrategy::Sparse => Table::Sparse {
+                slots: 1 << seg_bits,
+                children: BTreeMap::new(),
+            },
+        }))
+    }
+}
+
+/// How many levels of tables `{:?}` shows before summarising what's below.
+const DEBUG_DEPTH: u32 = 4;
+/// How many populated children of a table `{:?}` shows.
+const DEBUG_CHILDREN: usize = 8;
+/// How many nodes `{:?}` shows in all.
+const DEBUG_NODES: usize = 32;
+
+/// A segment to print with `{:?}`, depth levels below where we started.
+/// budget is how many more nodes we can show.
+struct DebugSegment<'a> {
+    segment: &'a MemorySegment,
+    depth: u32,
+    budget: &'a Cell<usize>,
+}
+
+impl fmt::Debug for DebugSegment<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut dbg = f.debug_struct("MemorySegment");
+        match self.segment {
+            MemorySegment::Nothing() => {
+                dbg.field("Nothing", &"nothing");
+            }
+            MemorySegment::Next(segment) => {
+                let populated = segment
+                    .iter()
+                    .filter(|(_, mem)| !matches!(mem, MemorySegment::Nothing()))
+                    .count();
+                // Too deep to be worth showing; just say how big it is.
+                if self.depth >= DEBUG_DEPTH {
+                    let summary = format!("{} children, {populated} populated", segment.slots());
+                    return f
+                        .debug_tuple("Next")
+                        .field(&format_args!("{summary}"))
+                        .finish();
+                }
+                let children = segment
+                    .iter()
+                    .filter(|(_, mem)| !matches!(mem, MemorySegment::Nothing()));
+                let mut shown = 0;
+                for (idx, mem) in children.take(DEBUG_CHILDREN) {
+                    if self.budget.get() == 0 {
+                        break;
+                    }
+                    self.budget.set(self.budget.get() - 1);
+                    shown += 1;
+                    let child = DebugSegment {
+                        segment: mem,
+                        depth: self.depth + 1,
+                        budget: self.budget,
+                    };
+                    dbg.field(&format!("Seg {idx}"), &child);
+                }
+                if populated > shown {
+                    dbg.field("more", &(populated - shown));
+                }
+            }
+            MemorySegment::Memory(vec) => {
+                dbg.field("Memory", &vec.len());
+            }
+        }
+        dbg.finish()
+    }
+}
+
+/// Bounded, however big the tree: only the first few populated children
+/// of each table, the top few levels, and a few dozen nodes in all.
+impl fmt::Debug for MemorySegment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        DebugSegment {
+            segment: self,
+            depth: 0,
+            budget: &Cell::new(DEBUG_NODES),
+        }
+        .fmt(f)
+    }
+}
+
+/// Where element i of a strided access is.
+fn strided(base: u64, stride: u64, i: usize) -> Result<u64, MemError> {
+    stride
+        .checked_mul(i as u64)
+        .and_then(|offset| base.checked_add(offset))
+        .ok_or(MemError::OutOfRange)
+}
+
+/// Call `f(base, words)` for every leaf under node, in ascending address
+/// order. level_bits are the widths of node's level and below, and shift
+/// is where node's level ends in the address (the address width for the
+/// root).
+fn walk_leaves<'a>(
+    node: &'a MemorySegment,
+    level_bits: &[u32],
+    base: u64,
+    shift: u32,
+    f: &mut dyn FnMut(u64, &'a [u64]),
+) {
+    match node {
+        MemorySegment::Nothing() => (),
+        MemorySegment::Next(next_seg) => {
+            let Some((bits, rest)) = level_bits.split_first() else {
+                return;
+            };
+            let shift = shift - bits;
+            for (idx, child) in next_seg.iter() {
+                walk_leaves(child, rest, base | ((idx as u64) << shift), shift, f);
+            }
+        }
+        MemorySegment::Memory(mem) => f(base, mem),
+    }
+}
+
+/// Set every resident leaf under node to fill, keeping them resident.
+/// Leaves (and tables) which already hold nothing else are left shared
+/// with any snapshot.
+fn clear_leaves(node: &mut MemorySegment, fill: u64) {
+    match node {
+        MemorySegment::Nothing() => (),
+        MemorySegment::Next(next_seg) => {
+            let dirty: Vec<usize> = next_seg
+                .iter()
+                .filter(|(_, child)| !holds_only(child, fill))
+                .map(|(idx, _)| idx)
+                .collect();
+            if dirty.is_empty() {
+                return;
+            }
+            let next_seg = Arc::make_mut(next_seg);
+            for idx in dirty {
+                clear_leaves(next_seg.get_mut(idx), fill);
+            }
+        }
+        MemorySegment::Memory(mem) => {
+            if mem.iter().any(|word| *word != fill) {
+                let words = vec![fill; mem.len()];
+                Arc::make_mut(mem).write_words(0, &words);
+            }
+        }
+    }
+}
+
+/// Does every resident leaf under node hold nothing but fill?
+fn holds_only(node: &MemorySegment, fill: u64) -> bool {
+    match node {
+        MemorySegment::Nothing() => true,
+        MemorySegment::Next(next_seg) => next_seg.iter().all(|(_, child)| holds_only(child, fill)),
+        MemorySegment::Memory(mem) => mem.iter().all(|word| *word == fill),
+    }
+}
+
+/// The lowest address at or after from in a resident leaf under node,
+/// which covers the 1 << shift words from base. Subtrees wholly below from
+/// aren't looked into. level_bits and shift are as for `walk_leaves`.
+fn next_leaf(
+    node: &MemorySegment,
+    level_bits: &[u32],
+    base: u64,
+    shift: u32,
+    from: u64,
+) -> Option<u64> {
+    match node {
+        MemorySegment::Nothing() => None,
+        MemorySegment::Next(next_seg) => {
+            let (bits, rest) = level_bits.split_first()?;
+            let shift = shift - bits;
+            next_seg.iter().find_map(|(idx, child)| {
+                let child_base = base | ((idx as u64) << shift);
+                let child_last = child_base | ((1u64 << shift) - 1);
+                if child_last < from {
+                    return None;
+                }
+                next_leaf(child, rest, child_base, shift, from)
+            })
+        }
+        MemorySegment::Memory(_) => Some(base.max(from)),
+    }
+}
+
+/// Push (address, old word, new word) onto out for every word which differs
+/// between two trees of the same geometry, ascending. Subtrees they still
+/// share (nobody's written there since they parted) are skipped without
+/// looking inside; unmapped words read as fill. level_bits and shift are as
+/// for `walk_leaves`.
+fn diff_trees(
+    old: &MemorySegment,
+    new: &MemorySegment,
+    level_bits: &[u32],
+    base: u64,
+    shift: u32,
+    fill: u64,
+    out: &mut Vec<(u64, u64, u64)>,
+) {
+    use MemorySegment::{Memory, Next, Nothing};
+    match (old, new) {
+        (Next(a), Next(b)) if Arc::ptr_eq(a, b) => (),
+        (Memory(a), Memory(b)) if Arc::ptr_eq(a, b) => (),
+        (Nothing(), Nothing()) => (),
+        (Next(_), _) | (_, Next(_)) => {
+            let Some((bits, rest)) = level_bits.split_first() else {
+                return;
+            };
+            let shift = shift - bits;
+            let mut populated = BTreeSet::new();
+            for node in [old, new] {
+                if let Next(table) = node {
+                    populated.extend(table.iter().map(|(idx, _)| idx));
+                }
+            }
+            let child = |node: &MemorySegment, idx| match node {
+                Next(table) => table.get(idx).clone(),
+                _ => Nothing(),
+            };
+            for idx in populated {
+                let (a, b) = (child(old, idx), child(new, idx));
+                let base = base | ((idx as u64) << shift);
+                diff_trees(&a, &b, rest, base, shift, fill, out);
+            }
+        }
+        _ => {
+            let words = |node: &MemorySegment, i: usize| match node {
+                Memory(leaf) => leaf[i],
+                _ => fill,
+            };
+            let len = match (old, new) {
+                (Memory(leaf), _) | (_, Memory(leaf)) => leaf.len(),
+                _ => 0,
+            };
+            for i in 0..len {
+                let (a, b) = (words(old, i), words(new, i));
+                if a != b {
+                    out.push((base + i as u64, a, b));
+                }
+            }
+        }
+    }
+}
+
+/// Put leaf in place at base under node, adding tables as needed.
+fn install_leaf(
+    node: &mut MemorySegment,
+    strategy: TableStrategy,
+    level_bits: &[u32],
+    base: u64,
+    shift: u32,
+    leaf: Leaf,
+) {
+    let Some((bits, rest)) = level_bits.split_first() else {
+        *node = MemorySegment::Memory(Arc::new(leaf));
+        return;
+    };
+    if !matches!(node, MemorySegment::Next(_)) {
+        *node = MemorySegment::new_segment(strategy, *bits);
+    }
+    if let MemorySegment::Next(next_seg) = node {
+        let shift = shift - bits;
+        install_leaf(
+            Arc::make_mut(next_seg).get_mut(table_index(base, shift, *bits)),
+            strategy,
+            rest,
+            base,
+            shift,
+            leaf,
+        );
+    }
+}
+
+/// The shift to get each level's table index out of an address, root
+/// first, for tables of level_bits over leaves of mem_bits.
+pub fn level_shifts(level_bits: &[u32], mem_bits: u32) -> Vec<u32> {
+    let mut shift = level_bits.iter().sum::<u32>() + mem_bits;
+    level_bits
+        .iter()
+        .map(|bits| {
+            shift -= bits;
+            shift
+        })
+        .collect()
+}
+
+/// Index into a table of bits width whose index ends shift bits up the
+/// address.
+pub fn table_index(address: u64, shift: u32, bits: u32) -> usize {
+    ((address >> shift) & ((1u64 << bits) - 1)) as usize
+}
+
+/// Where address falls within its leaf of mem_bits.
+pub fn leaf_offset(address: u64, mem_bits: u32) -> usize {
+    (address & ((1u64 << mem_bits) - 1)) as usize
+}
+
+/// The first address of the leaf holding address.
+pub fn leaf_base(address: u64, mem_bits: u32) -> u64 {
+    address & !((1u64 << mem_bits) - 1)
+}
+
+/// Words from address to the end of its leaf, address included.
+pub fn leaf_room(address: u64, mem_bits: u32) -> u64 {
+    (1u64 << mem_bits) - leaf_offset(address, mem_bits) as u64
+}
+
+/// Byte order used when viewing words as bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Endianness {
+    #[default]
+    Little,
+    Big,
+}
+
+/// What reads of unmapped memory do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnmappedPolicy {
+    /// Read as the default fill.
+    #[default]
+    Fill,
+    /// Fail with `MemError::Unmapped`. Writes still fault leaves in.
+    Fault,
+}
+
+/// What writes to unmapped memory do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteMissPolicy {
+    /// Fault in a leaf and write to it.
+    #[default]
+    Allocate,
+    /// As Allocate, unless every word written is the default fill - then
+    /// the write is dropped, since the memory reads that way already.
+    NoAllocate,
+}
+
+/// What accesses beyond the geometry's reach do - which a small custom
+/// geometry makes easy to stumble into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutOfRangePolicy {
+    /// Fail with `MemError::OutOfRange`.
+    #[default]
+    Fault,
+    /// Read as unmapped memory does, and drop writes, as a bus with
+    /// nothing decoded there might.
+    Ignore,
+}
+
+/// What reads of unmapped memory do to the tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadMissPolicy {
+    /// Leave it alone; the read just sees what unmapped memory reads as.
+    #[default]
+    NoAllocate,
+    /// Fault in a leaf of default fill and read that, as a cache filling a
+    /// line on a read miss would. `UnmappedPolicy` and `unmapped_fill`
+    /// don't come into it - the memory is mapped by the time it's read.
+    Allocate,
+}
+
+/// How tables hold their children.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TableStrategy {
+    /// An array of every child - one index per lookup, but a table costs
+    /// its full width however few children it has.
+    #[default]
+    Dense,
+    /// A map of just the populated children - a lookup costs a search,
+    /// but sparse trees (a few scattered pages in a huge space) get much
+    /// smaller.
+    Sparse,
+}
+
+/// A recognisable pattern for `set_unmapped_fill`, so reads of memory
+/// nothing ever wrote stand out.
+pub const POISON: u64 = 0xbadc_0ffe_e0dd_f00d;
+
+/// Everything configurable about a `TreeMemory`, in one place.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeConfig {
+    /// Bits per level - size of the tables at each level, root first
+    pub level_bits: Vec<u32>,
+    /// How deep is the tree? The leaves live at this level, so this is
+    /// always level_bits.len() + 1.
+    pub max_depth: u32,
+    /// Bits in an end index
+    pub mem_bits: u32,
+    /// How words are viewed as bytes.
+    pub endianness: Endianness,
+    /// What reads of unmapped memory do.
+    pub unmapped_policy: UnmappedPolicy,
+    /// What writes to unmapped memory do.
+    pub write_miss_policy: WriteMissPolicy,
+    /// What reads of unmapped memory do to the tree.
+    pub read_miss_policy: ReadMissPolicy,
+    /// What accesses beyond the geometry's reach do.
+    pub out_of_range_policy: OutOfRangePolicy,
+    /// How tables hold their children.
+    pub table_strategy: TableStrategy,
+    /// What unmapped memory reads as, and what new leaves start out holding.
+    pub default_fill: u64,
+    /// If set, what reads of unmapped memory return instead of the default
+    /// fill - a poison pattern, say. New leaves still get the default fill.
+    pub unmapped_fill: Option<u64>,
+}
+
+impl Default for TreeConfig {
+    fn default() -> Self {
+        // 4 levels of 4096 element tables, then 16 bits => 512KiB leaves.
+        TreeConfig {
+            level_bits: vec![12; 4],
+            max_depth: 5,
+            mem_bits: 64 - (12 * 4),
+            endianness: Endianness::Little,
+            unmapped_policy: UnmappedPolicy::Fill,
+            write_miss_policy: WriteMissPolicy::Allocate,
+            read_miss_policy: ReadMissPolicy::NoAllocate,
+            out_of_range_policy: OutOfRangePolicy::Fault,
+            table_strategy: TableStrategy::Dense,
+            default_fill: 0,
+            unmapped_fill: None,
+        }
+    }
+}
+
+impl TreeConfig {
+    /// Bits of word address the geometry covers.
+    pub fn address_bits(&self) -> u32 {
+        self.level_bits.iter().sum::<u32>() + self.mem_bits
+    }
+
+    /// Mask of the word addresses this geometry can reach.
+    pub fn address_mask(&self) -> u64 {
+        let bits = self.address_bits();
+        if bits >= 64 {
+            u64::MAX
+        } else {
+            (1 << bits) - 1
+        }
+    }
+
+    /// Check the geometry covers at most 64 bits of address. Smaller
+    /// spaces are fine - with no tables at all (max_depth 1) the root is
+    /// itself the one leaf.
+    fn validate(&self) -> Result<(), MemError> {
+        if self.level_bits.iter().any(|bits| *bits == 0 || *bits > 32)
+            || self.max_depth != self.level_bits.len() as u32 + 1
+            || self.mem_bits == 0
+            || self.mem_bits > 32
+            || self.address_bits() > 64
+        {
+            return Err(MemError::BadGeometry);
+        }
+        Ok(())
+    }
+}
+
+/// A tree memory, populated on demand.
+/// Each level of the tree supplies some bits of the address (its own
+/// level_bits entry), and each element is either a memory array, a pointer
+/// to another table, or nothing.
+/// By default we put memory itself at the leaves.
+pub struct TreeMemory {
+    /// Root of the tree.
+    root: MemorySegment,
+    /// Geometry and policies.
+    config: TreeConfig,
+    /// Shift to get each level's index out of an address - cached
+    level_shifts: Vec<u32>,
+    /// config.address_mask(), cached
+    address_mask: u64,
+    /// Words held in resident leaves.
+    resident_words: Cell<u64>,
+    /// If set, the most resident leaf words we'll fault in.
+    resident_quota: Option<u64>,
+    /// Read/write hazard detection, if on.
+    hazards: Option<HazardTracker>,
+    /// Access counts by address, if on.
+    heatmap: Option<Heatmap>,
+    /// Non-overlapping ranges with non-default permissions, as
+    /// start => (last word, perms). Anything not in here is read/write.
+    perms: BTreeMap<u64, (u64, Perms)>,
+    /// Called with the level of every node an access visits, if set.
+    level_hook: Option<RefCell<LevelHook>>,
+    /// Buffers from freed leaves, emptied, ready for the next fault-in.
+    leaf_pool: RefCell<Vec<Vec<u64>>>,
+    /// How many fault-ins got a buffer from leaf_pool.
+    leaf_reuses: Cell<u64>,
+    /// Makes new leaves' storage, if set; otherwise they're plain vectors.
+    leaf_factory: Option<LeafFactory>,
+    /// Longest access we'll do, in words, if limited.
+    max_span: Option<u32>,
+    /// Gets a say in accesses the permissions deny, if set.
+    fault_handler: Option<FaultHandler>,
+    /// Called with what every write stored, if set.
+    on_write: Option<WriteHook>,
+    /// (leaf base, level) of each leaf faulted in since the last drain, if
+    /// we're keeping them.
+    fault_events: Option<RefCell<Vec<(u64, u32)>>>,
+    /// Regions handed out by `allocate`, as base => length.
+    allocations: BTreeMap<u64, u64>,
+    /// Which words are tainted, as a bitmap per leaf (by leaf base) with
+    /// any bits set.
+    taint: BTreeMap<u64, Vec<u64>>,
+    /// Leaves which are other names for other leaves, as view leaf base =>
+    /// the base of the leaf it shows. Targets are never views themselves.
+    aliases: BTreeMap<u64, u64>,
+    /// Translates every access's addresses before anything else, if set.
+    address_remap: Option<AddressRemap>,
+}
+
+impl fmt::Debug for TreeMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TreeMemory")
+            .field("config", &self.config)
+            .field("resident_words", &self.resident_words.get())
+            .field("root", &self.root)
+            .finish_non_exhaustive()
+    }
+}
+
+/// One word of a `TreeMemory`; see `TreeMemory::at`. Accesses are checked
+/// (range, permissions, ...) just as `load_word` and `store_word` are.
+pub struct WordRef<'a> {
+    mem: &'a mut TreeMemory,
+    loc: u64,
+}
+
+impl WordRef<'_> {
+    pub fn loc(&self) -> u64 {
+        self.loc
+    }
+
+    pub fn get(&mut self) -> Result<u64, MemError> {
+        self.mem.load_word(self.loc)
+    }
+
+    pub fn set(&mut self, val: u64) -> Result<(), MemError> {
+        self.mem.store_word(self.loc, val)
+    }
+}
+
+/// See `TreeMemory::set_level_hook`.
+pub type LevelHook = Box<dyn FnMut(u32) + Send>;
+
+/// See `TreeMemory::set_write_hook`.
+pub type WriteHook = Box<dyn FnMut(u64, &[u64]) + Send>;
+
+/// See `TreeMemory::set_address_remap`.
+pub type AddressRemap = Box<dyn Fn(u64) -> u64 + Send>;
+
+/// Most freed leaf buffers we keep around for reuse.
+pub const LEAF_POOL_CAP: usize = 8;
+
+/// Builds a `TreeMemory` with a non-default configuration.
+pub struct TreeMemoryBuilder {
+    config: TreeConfig,
+}
+
+impl TreeMemoryBuilder {
+    /// Use the same width for `depth` levels of tables.
+    pub fn uniform_levels(self, bits_per_segment: u32, depth: u32) -> Self {
+        self.level_bits(vec![bits_per_segment; depth as usize])
+    }
+
+    /// Per-level table widths, root first - like a real page table hierarchy.
+    pub fn level_bits(mut self, level_bits: Vec<u32>) -> Self {
+        self.config.max_depth = level_bits.len() as u32 + 1;
+        self.config.level_bits = level_bits;
+        self
+    }
+
+    /// Bits of address resolved within a leaf.
+    pub fn mem_bits(mut self, mem_bits: u32) -> Self {
+        self.config.mem_bits = mem_bits;
+        self
+    }
+
+    pub fn endianness(mut self, endianness: Endianness) -> Self {
+        self.config.endianness = endianness;
+        self
+    }
+
+    pub fn unmapped_policy(mut self, unmapped_policy: UnmappedPolicy) -> Self {
+        self.config.unmapped_policy = unmapped_policy;
+        self
+    }
+
+    pub fn write_miss_policy(mut self, write_miss_policy: WriteMissPolicy) -> Self {
+        self.config.write_miss_policy = write_miss_policy;
+        self
+    }
+
+    pub fn read_miss_policy(mut self, read_miss_policy: ReadMissPolicy) -> Self {
+        self.config.read_miss_policy = read_miss_policy;
+        self
+    }
+
+    pub fn out_of_range_policy(mut self, out_of_range_policy: OutOfRangePolicy) -> Self {
+        self.config.out_of_range_policy = out_of_range_policy;
+        self
+    }
+
+    pub fn table_strategy(mut self, table_strategy: TableStrategy) -> Self {
+        self.config.table_strategy = table_strategy;
+        self
+    }
+
+    pub fn default_fill(mut self, default_fill: u64) -> Self {
+        self.config.default_fill = default_fill;
+        self
+    }
+
+    pub fn unmapped_fill(mut self, unmapped_fill: u64) -> Self {
+        self.config.unmapped_fill = Some(unmapped_fill);
+        self
+    }
+
+    /// Check the geometry covers at most 64 bits of address and build.
+    pub fn build(self) -> Result<TreeMemory, MemError> {
+        TreeMemory::with_config(self.config)
+    }
+}
+
+impl Default for TreeMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TreeMemory {
+    pub fn new() -> TreeMemory {
+        TreeMemory::with_config(TreeConfig::default()).expect("default geometry is valid")
+    }
+
+    pub fn builder() -> TreeMemoryBuilder {
+        TreeMemoryBuilder {
+            config: TreeConfig::default(),
+        }
+    }
+
+    pub fn with_config(config: TreeConfig) -> Result<TreeMemory, MemError> {
+        config.validate()?;
+        let level_shifts = level_shifts(&config.level_bits, config.mem_bits);
+        Ok(TreeMemory {
+            root: MemorySegment::Nothing(),
+            address_mask: config.address_mask(),
+            config,
+            level_shifts,
+            resident_words: Cell::new(0),
+            resident_quota: None,
+            hazards: None,
+            heatmap: None,
+            perms: BTreeMap::new(),
+            level_hook: None,
+            leaf_pool: RefCell::new(Vec::new()),
+            leaf_reuses: Cell::new(0),
+            leaf_factory: None,
+            max_span: None,
+            fault_handler: None,
+            on_write: None,
+            fault_events: None,
+            taint: BTreeMap::new(),
+            allocations: BTreeMap::new(),
+            aliases: BTreeMap::new(),
+            address_remap: None,
+        })
+    }
+
+    pub fn config(&self) -> &TreeConfig {
+        &self.config
+    }
+
+    /// Add levels of tables above the root until the tree is depth deep,
+    /// widening the address space - so a memory can start shallow and
+    /// grow when something starts using higher addresses. Each new level
+    /// is as wide as the root table was (or the leaves, with no tables),
+    /// trimmed to keep addresses to 64 bits. Everything stays where it
+    /// is: the old tree becomes the bottom corner of the new one.
+    /// Shallower than now, or deeper than 64 bits allow, is `BadGeometry`.
+    pub fn set_max_depth(&mut self, depth: u32) -> Result<(), MemError> {
+        if depth < self.config.max_depth {
+            return Err(MemError::BadGeometry);
+        }
+        let bits = *self
+            .config
+            .level_bits
+            .first()
+            .unwrap_or(&self.config.mem_bits);
+        let mut config = self.config.clone();
+        while config.max_depth < depth {
+            let room = 64 - config.address_bits();
+            if room == 0 {
+                return Err(MemError::BadGeometry);
+            }
+            config.level_bits.insert(0, bits.min(room));
+            config.max_depth += 1;
+        }
+        config.validate()?;
+        for bits in config.level_bits[..(depth - self.config.max_depth) as usize]
+            .iter()
+            .rev()
+        {
+            let old = core::mem::take(&mut self.root);
+            if !matches!(old, MemorySegment::Nothing()) {
+                let mut root = MemorySegment::new_segment(config.table_strategy, *bits);
+                if let MemorySegment::Next(table) = &mut root {
+                    *Arc::make_mut(table).get_mut(0) = old;
+                }
+                self.root = root;
+            }
+        }
+        self.level_shifts = level_shifts(&config.level_bits, config.mem_bits);
+        self.address_mask = config.address_mask();
+        self.config = config;
+        Ok(())
+    }
+
+    /// Limit the number of resident leaf words. Writes which would fault in
+    /// a leaf taking us over the limit fail with `QuotaExceeded`; leaves
+    /// which are already resident stay writable.
+    pub fn set_resident_quota(&mut self, max_words: u64) {
+        self.resident_quota = Some(max_words);
+    }
+
+    /// Call `hook` whenever a word is written within `window` accesses of
+    /// being read, or read within `window` accesses of being written.
+    pub fn set_hazard_hook(&mut self, window: u64, hook: Box<dyn FnMut(Hazard) + Send>) {
+        self.hazards = Some(HazardTracker {
+            window,
+            seq: 0,
+            last: BTreeMap::new(),
+            hook,
+        });
+    }
+
+    pub fn clear_hazard_hook(&mut self) {
+        self.hazards = None;
+    }
+
+    /// Ask handler what to do whenever the permissions deny an access,
+    /// rather than just failing it - so it can map pages in on demand, as
+    /// an OS's page fault handler would. It can't touch the memory itself,
+    /// so it answers with a `FaultAction`.
+    pub fn set_fault_handler(&mut self, handler: FaultHandler) {
+        self.fault_handler = Some(handler);
+    }
+
+    pub fn clear_fault_handler(&mut self) {
+        self.fault_handler = None;
+    }
+
+    /// Call hook with the address and the words stored after every write
+    /// which succeeds - for snooping writes into a cache or coherency
+    /// model, whichever way they came in. A write spanning leaves is seen
+    /// a leaf at a time, and freeing a leaf is seen as writing the default
+    /// fill over it. Restoring a snapshot isn't seen.
+    pub fn set_write_hook(&mut self, hook: WriteHook) {
+        self.on_write = Some(hook);
+    }
+
+    pub fn clear_write_hook(&mut self) {
+        self.on_write = None;
+    }
+
+    /// Put every access's addresses through f before anything else -
+    /// range and permission checks, hooks and the tree all see what f
+    /// gives - for trying out mirrored regions or address scrambling
+    /// without a full MMU. Reads and writes are translated alike, a word
+    /// at a time (f needn't keep neighbours together), so accesses are
+    /// slower while it's set. Whatever lends out leaves directly
+    /// (`read_ref`, `as_iovecs`, `bytes_iter`, views) and the
+    /// whole-memory operations (`fill`, `free_range`, `load_blob`,
+    /// `snapshot_range`, `restore_range`, `relocate`) aren't translated at
+    /// all: they work on the tree's own addresses, hooks included.
+    pub fn set_address_remap(&mut self, f: impl Fn(u64) -> u64 + Send + 'static) {
+        self.address_remap = Some(Box::new(f));
+    }
+
+    pub fn clear_address_remap(&mut self) {
+        self.address_remap = None;
+    }
+
+    /// Start keeping a note of each leaf faulted in, for
+    /// `drain_fault_events` - for tools rebuilding the allocation timeline
+    /// which would rather poll than hook every access.
+    pub fn enable_fault_events(&mut self) {
+        self.fault_events = Some(RefCell::new(Vec::new()));
+    }
+
+    /// Stop, dropping any events not drained yet.
+    pub fn disable_fault_events(&mut self) {
+        self.fault_events = None;
+    }
+
+    /// The (leaf base, level) of each leaf faulted in since the last drain
+    /// (or enable_fault_events), oldest first. Empty if they're not on.
+    pub fn drain_fault_events(&mut self) -> Vec<(u64, u32)> {
+        self.fault_events
+            .as_mut()
+            .map_or_else(Vec::new, |events| core::mem::take(events.get_mut()))
+    }
+
+    /// Start counting the words read and written in [start, start + len),
+    /// in bins equal sized bins (the last may be short) - to see where an
+    /// emulated program's hot spots are. Any counts so far are dropped.
+    pub fn enable_heatmap(&mut self, start: u64, len: u64, bins: usize) {
+        assert!(len > 0 && bins > 0, "a heatmap needs something to cover");
+        let bins = (bins as u64).min(len);
+        let bin_words = len.div_ceil(bins);
+        self.heatmap = Some(Heatmap {
+            start,
+            last: start.saturating_add(len - 1),
+            bin_words,
+            reads: vec![0; bins as usize],
+            writes: vec![0; bins as usize],
+        });
+    }
+
+    pub fn disable_heatmap(&mut self) {
+        self.heatmap = None;
+    }
+
+    /// (bin start, words read, words written) for each heatmap bin, in
+    /// address order; empty if the heatmap isn't on.
+    pub fn heatmap(&self) -> Vec<(u64, u64, u64)> {
+        let Some(heat) = &self.heatmap else {
+            return Vec::new();
+        };
+        heat.reads
+            .iter()
+            .zip(heat.writes.iter())
+            .enumerate()
+            .map(|(bin, (reads, writes))| {
+                (heat.start + bin as u64 * heat.bin_words, *reads, *writes)
+            })
+            .collect()
+    }
+
+    /// Call `hook(level)` for each node of the tree an access visits (1
+    /// being the root, max_depth the leaf) - to see what walks cost.
+    pub fn set_level_hook(&mut self, hook: LevelHook) {
+        self.level_hook = Some(RefCell::new(hook));
+    }
+
+    pub fn clear_level_hook(&mut self) {
+        self.level_hook = None;
+    }
+
+    fn visit(&self, level: u32) {
+        if let Some(hook) = &self.level_hook {
+            (hook.borrow_mut())(level);
+        }
+    }
+
+    /// Refuse reads and writes of more than words words with
+    /// `MemError::SpanTooLarge`, before allocating anything for them - to
+    /// catch runaway accesses, like a guest length field gone bad. This is
+    /// for accesses through `Access`, `SyncAccess`, `iop`, `try_read` and
+    /// `write_words`; our own bulk operations (`fill`, `load_blob`, ...)
+    /// aren't limited. There's no limit by default.
+    pub fn set_max_span(&mut self, words: u32) {
+        self.max_span = Some(words);
+    }
+
+    pub fn clear_max_span(&mut self) {
+        self.max_span = None;
+    }
+
+    fn check_span(&self, span: usize) -> Result<(), MemError> {
+        match self.max_span {
+            Some(max) if span > max as usize => Err(MemError::SpanTooLarge),
+            _ => Ok(()),
+        }
+    }
+
+    /// How many words are held in resident leaves?
+    pub fn resident_words(&self) -> u64 {
+        self.resident_words.get()
+    }
+
+    /// Drop everything - back to a freshly built memory.
+    pub fn reset(&mut self) {
+        self.root = MemorySegment::Nothing();
+        self.resident_words.set(0);
+        self.taint.clear();
+    }
+
+    /// Set everything back to the default fill, as `reset` does, but keep
+    /// the resident leaves (and tables) rather than freeing them - for
+    /// running a program again without faulting it all back in. Like
+    /// reset it's not an access, so permissions and the write hook don't
+    /// come into it; taint is cleared.
+    pub fn zero_all(&mut self) {
+        clear_leaves(&mut self.root, self.config.default_fill);
+        self.taint.clear();
+    }
+
+    /// Take a snapshot of the resident contents. This is O(1): it shares
+    /// the tree, and later writes copy whatever they touch first.
+    pub fn snapshot(&self) -> MemorySnapshot {
+        MemorySnapshot {
+            root: self.root.clone(),
+            level_bits: self.config.level_bits.clone(),
+            mem_bits: self.config.mem_bits,
+            words: self.resident_words.get(),
+        }
+    }
+
+    /// Borrow the memory read-only, as a `MemoryView` - unlike `snapshot`
+    /// nothing is shared or copied, but nothing can change while it's held.
+    pub fn snapshot_view(&self) -> MemoryView<'_> {
+        MemoryView { mem: self }
+    }
+
+    /// Copy span words from loc, to put back later with `restore_range`. Only
+    /// resident leaves are copied, so a big window over little memory is
+    /// cheap.
+    pub fn snapshot_range(&self, loc: u64, span: u64) -> RangeSnapshot {
+        let mut pieces = Vec::new();
+        if span > 0 {
+            let last = loc.saturating_add(span - 1);
+            self.for_each_leaf(&mut |base, words| {
+                let top = base + (words.len() as u64 - 1);
+                if top < loc || base > last {
+                    return;
+                }
+                let (from, to) = (loc.max(base), last.min(top));
+                let words = &words[(from - base) as usize..=(to - base) as usize];
+                pieces.push((from, words.to_vec()));
+            });
+        }
+        RangeSnapshot { loc, span, pieces }
+    }
+
+    /// Put the window snap was taken of back as it was, leaving everything
+    /// outside it alone. What wasn't resident then goes back to the default
+    /// fill. Fails (part way through) if the window isn't writable now, or
+    /// the quota won't let us fault it back in.
+    pub fn restore_range(&mut self, snap: &RangeSnapshot) -> Result<(), MemError> {
+        self.untranslated(|mem| {
+            mem.fill(snap.loc, snap.span, mem.config.default_fill)?;
+            for (start, words) in &snap.pieces {
+                let mut words = words.clone();
+                mem.split_iop(*start, &mut words, &MemOp::Write)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Move count words from `from` to `to`, permissions, taint and all -
+    /// as a loader relocating a module would. The source is left unmapped
+    /// (wherever the destination doesn't cover it), and the destination
+    /// ends up holding exactly what the source did, unmapped parts
+    /// included. It's all or nothing: a failure (range, quota) leaves the
+    /// memory as it was. The move itself isn't an access, so the
+    /// permissions being moved don't stand in its way.
+    pub fn relocate(&mut self, from: u64, to: u64, count: u64) -> Result<(), MemError> {
+        self.check_range(from, count)?;
+        self.check_range(to, count)?;
+        if count == 0 || from == to {
+            return Ok(());
+        }
+        let (saved, saved_perms, saved_taint) =
+            (self.snapshot(), self.perms.clone(), self.taint.clone());
+        let last = from + (count - 1);
+        let moved: Vec<(u64, u64, Perms)> = self
+            .perms
+            .range(..=last)
+            .filter(|(_, (r_last, _))| *r_last >= from)
+            .map(|(start, (r_last, perms))| ((*start).max(from), (*r_last).min(last), *perms))
+            .collect();
+        let contents = self.snapshot_range(from, count);
+        let taint = self.taint_bits(from, count);
+        self.set_permissions(from, count, Perms::READ_WRITE);
+        self.set_permissions(to, count, Perms::READ_WRITE);
+        let result = self.untranslated(|mem| mem.free_then_place(&contents, to));
+        if result.is_err() {
+            self.restore(&saved)?;
+            self.perms = saved_perms;
+            self.taint = saved_taint;
+            return result;
+        }
+        for (start, r_last, perms) in moved {
+            self.set_permissions(start - from + to, r_last - start + 1, perms);
+        }
+        self.apply_taint(to, &taint);
+        Ok(())
+    }
+
+    /// relocate's moving part: clear the source and put its contents at to.
+    fn free_then_place(&mut self, contents: &RangeSnapshot, to: u64) -> Result<(), MemError> {
+        self.fill(contents.loc, contents.span, self.config.default_fill)?;
+        self.fill(to, contents.span, self.config.default_fill)?;
+        for (start, words) in &contents.pieces {
+            let mut words = words.clone();
+            self.split_iop(start - contents.loc + to, &mut words, &MemOp::Write)?;
+        }
+        Ok(())
+    }
+
+    /// Every word which has changed since snap was taken, as (address,
+    /// the word in snap, the word now), ascending - what an instruction
+    /// did, say. Only the parts of the tree written since are compared, so
+    /// it's quick when little has changed. Fails if snap is for another
+    /// geometry.
+    pub fn diff_against_snapshot(
+        &self,
+        snap: &MemorySnapshot,
+    ) -> Result<Vec<(u64, u64, u64)>, MemError> {
+        if snap.level_bits != self.config.level_bits || snap.mem_bits != self.config.mem_bits {
+            return Err(MemError::BadGeometry);
+        }
+        let mut diffs = Vec::new();
+        diff_trees(
+            &snap.root,
+            &self.root,
+            &self.config.level_bits,
+            0,
+            self.config.address_bits(),
+            self.config.default_fill,
+            &mut diffs,
+        );
+        Ok(diffs)
+    }
+
+    /// Replace our contents with a snapshot's. Fails if the snapshot is
+    /// for another geometry, or if the quota won't let us hold its leaves.
+    /// The snapshot's leaves are installed as they are, so if it came from
+    /// somewhere dubious, `validate` afterwards.
+    pub fn restore(&mut self, snap: &MemorySnapshot) -> Result<(), MemError> {
+        if snap.level_bits != self.config.level_bits || snap.mem_bits != self.config.mem_bits {
+            return Err(MemError::BadGeometry);
+        }
+        if let Some(quota) = self.resident_quota {
+            if snap.words > quota {
+                return Err(MemError::QuotaExceeded);
+            }
+        }
+        self.root = snap.root.clone();
+        self.resident_words.set(snap.words);
+        Ok(())
+    }
+
+    /// Check the tree's structure: tables have the right number of
+    /// entries for their level, leaves are the right length and only at
+    /// the bottom, nothing is deeper than max_depth, and the resident word
+    /// count adds up. Catches corruption from bad refactors or restoring a
+    /// malformed snapshot.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut words = 0;
+        self.validate_node(&self.root, 0, 1, &mut words)?;
+        if words != self.resident_words.get() {
+            return Err(format!(
+                "resident_words is {} but leaves hold {words}",
+                self.resident_words.get()
+            ));
+        }
+        Ok(())
+    }
+
+    fn validate_node(
+        &self,
+        node: &MemorySegment,
+        base: u64,
+        level: u32,
+        words: &mut u64,
+    ) -> Result<(), String> {
+        if level > self.config.max_depth {
+            return Err(format!("node at {base:#x} is below max_depth"));
+        }
+        match node {
+            MemorySegment::Nothing() => Ok(()),
+            MemorySegment::Next(next_seg) => {
+                if level == self.config.max_depth {
+                    return Err(format!("table at {base:#x} where a leaf should be"));
+                }
+                let expected = 1usize << self.config.level_bits[(level - 1) as usize];
+                if next_seg.slots() != expected {
+                    return Err(format!(
+                        "table at {base:#x} level {level} has {} entries, not {expected}",
+                        next_seg.slots()
+                    ));
+                }
+                let shift = self.level_shifts[(level - 1) as usize];
+                for (idx, child) in next_seg.iter() {
+                    if idx >= expected {
+                        return Err(format!("table at {base:#x} has an entry at {idx}"));
+                    }
+                    self.validate_node(child, base | ((idx as u64) << shift), level + 1, words)?;
+                }
+                Ok(())
+            }
+            MemorySegment::Memory(mem) => {
+                if level != self.config.max_depth {
+                    return Err(format!("leaf at {base:#x} on level {level}"));
+                }
+                let len = mem.len();
+                if len != 1 << self.config.mem_bits {
+                    return Err(format!(
+                        "leaf at {base:#x} has {len} words, not {}",
+                        1u64 << self.config.mem_bits
+                    ));
+                }
+                *words += len as u64;
+                Ok(())
+            }
+        }
+    }
+
+    /// Call `f(base, words)` for every resident leaf, in ascending address order.
+    fn for_each_leaf(&self, f: &mut dyn FnMut(u64, &[u64])) {
+        walk_leaves(
+            &self.root,
+            &self.config.level_bits,
+            0,
+            self.config.address_bits(),
+            f,
+        );
+    }
+
+    /// How many tables and leaves are allocated at each level of the tree,
+    /// and what they cost. Handy for tuning the geometry.
+    pub fn footprint_report(&self) -> FootprintReport {
+        let mut report = FootprintReport {
+            levels: vec![LevelFootprint::default(); self.config.max_depth as usize],
+        };
+        self.tally(&self.root, 1, &mut report);
+        report
+    }
+
+    fn tally(&self, node: &MemorySegment, level: u32, report: &mut FootprintReport) {
+        let entry = &mut report.levels[(level - 1) as usize];
+        match node {
+            MemorySegment::Nothing() => (),
+            MemorySegment::Next(next_seg) => {
+                entry.tables += 1;
+                entry.slots += next_seg.stored() as u64;
+                entry.bytes += next_seg.bytes() as u64;
+                for (_, child) in next_seg.iter() {
+                    self.tally(child, level + 1, report);
+                }
+            }
+            MemorySegment::Memory(mem) => {
+                entry.leaves += 1;
+                entry.bytes += core::mem::size_of_val(&mem[..]) as u64;
+            }
+        }
+    }
+
+    /// Number of tables and leaves allocated in total.
+    pub fn segment_count(&self) -> u64 {
+        let report = self.footprint_report();
+        report.tables() + report.leaves()
+    }
+
+    /// A 64-bit digest of the geometry, default fill and contents, for
+    /// comparing or caching emulator states. Leaves are hashed in address
+    /// order, and ones holding nothing but the default fill are skipped
+    /// (they read the same as unmapped memory), so how the memory got into
+    /// a state doesn't matter - only what it reads as.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hash = FNV_OFFSET;
+        for bits in self.config.level_bits.iter() {
+            hash = fnv_step(hash, *bits as u64);
+        }
+        hash = fnv_step(hash, self.config.mem_bits as u64);
+        hash = fnv_step(hash, self.config.default_fill);
+        let fill = self.config.default_fill;
+        self.for_each_leaf(&mut |base, words| {
+            if words.iter().all(|word| *word == fill) {
+                return;
+            }
+            hash = fnv_step(hash, base);
+            hash = words.iter().fold(hash, |hash, word| fnv_step(hash, *word));
+        });
+        hash
+    }
+
+    /// (address, value) for every resident word which isn't default,
+    /// ascending - even inside resident leaves, default words are skipped.
+    /// For mostly uniform memory it's about the smallest snapshot there is.
+    pub fn sparse_words(&self, default: u64) -> Vec<(u64, u64)> {
+        let mut words = Vec::new();
+        self.for_each_leaf(&mut |base, leaf| {
+            for (i, word) in leaf.iter().enumerate() {
+                if *word != default {
+                    words.push((base + i as u64, *word));
+                }
+            }
+        });
+        words
+    }
+
+    /// Is every one of count words from loc zero? Unmapped words count as
+    /// what a read of them gives (so never zero if reads of them fault),
+    /// and aren't faulted in, or even looked at beyond the tables. Words
+    /// past the top of the geometry don't exist, so don't count.
+    /// Permissions aren't checked.
+    pub fn is_zero(&self, loc: u64, count: u64) -> bool {
+        if count == 0 || loc > self.address_mask {
+            return true;
+        }
+        let last = loc.saturating_add(count - 1).min(self.address_mask);
+        let fill_zero = match self.config.unmapped_policy {
+            UnmappedPolicy::Fill => {
+                self.config
+                    .unmapped_fill
+                    .unwrap_or(self.config.default_fill)
+                    == 0
+            }
+            UnmappedPolicy::Fault => false,
+        };
+        let mut zero = true;
+        // Next address not yet looked at; None once we're past last.
+        let mut next = Some(loc);
+        self.for_each_leaf(&mut |base, words| {
+            let top = base + (words.len() as u64 - 1);
+            let Some(from) = next.filter(|from| zero && *from <= top && base <= last) else {
+                return;
+            };
+            // Unmapped words before this leaf.
+            if from < base && !fill_zero {
+                zero = false;
+                return;
+            }
+            let (start, end) = (base.max(from), top.min(last));
+            let words = &words[(start - base) as usize..=(end - base) as usize];
+            zero = words.iter().all(|word| *word == 0);
+            next = end.checked_add(1).filter(|n| *n <= last);
+        });
+        zero && (fill_zero || next.is_none())
+    }
+
+    /// Addresses of the words among count from loc for which
+    /// `word & mask == pattern & mask`, ascending - for finding signatures
+    /// in guest memory. Unmapped words count as what a read of them gives,
+    /// but are skipped without looking if that can't match (or reads of
+    /// them fault). Permissions aren't checked.
+    pub fn scan_masked(&self, loc: u64, count: u64, pattern: u64, mask: u64) -> Vec<u64> {
+        let mut found = Vec::new();
+        if count == 0 || loc > self.address_mask {
+            return found;
+        }
+        let last = loc.saturating_add(count - 1).min(self.address_mask);
+        let hit = |word: u64| word & mask == pattern & mask;
+        let unmapped_hit = match self.config.unmapped_policy {
+            UnmappedPolicy::Fill => hit(self
+                .config
+                .unmapped_fill
+                .unwrap_or(self.config.default_fill)),
+            UnmappedPolicy::Fault => false,
+        };
+        // Next address not yet scanned; None once we're past last.
+        let mut next = Some(loc);
+        self.for_each_leaf(&mut |base, words| {
+            let top = base + (words.len() as u64 - 1);
+            let Some(from) = next.filter(|from| *from <= top && base <= last) else {
+                return;
+            };
+            if unmapped_hit {
+                found.extend(from..base.max(from));
+            }
+            let start = base.max(from);
+            let end = top.min(last);
+            let leaf = &words[(start - base) as usize..=(end - base) as usize];
+            for (i, word) in leaf.iter().enumerate() {
+                if hit(*word) {
+                    found.push(start + i as u64);
+                }
+            }
+            next = end.checked_add(1).filter(|n| *n <= last);
+        });
+        if let (true, Some(from)) = (unmapped_hit, next) {
+            found.extend(from..=last);
+        }
+        found
+    }
+
+    /// Resident address ranges as (start, length) in words, ascending, with
+    /// adjacent leaves coalesced into one run.
+    /// A run reaching the very top of memory has its length clipped to fit.
+    pub fn mapped_regions(&self) -> Vec<(u64, u64)> {
+        let leaf_words = 1u64 << self.config.mem_bits;
+        let mut regions: Vec<(u64, u64)> = Vec::new();
+        self.for_each_leaf(&mut |base, _| match regions.last_mut() {
+            Some((start, len)) if start.wrapping_add(*len) == base => *len += leaf_words,
+            _ => regions.push((base, leaf_words)),
+        });
+        if let Some((start, len)) = regions.last_mut() {
+            if start.checked_add(*len).is_none() {
+                *len = u64::MAX - *start;
+            }
+        }
+        regions
+    }
+
+    /// The lowest mapped (resident) address at or after from, if there is
+    /// one - for walking a sparse memory a leaf at a time, without
+    /// scanning the gaps or building all of `mapped_regions`.
+    pub fn next_mapped(&self, from: u64) -> Option<u64> {
+        if from > self.address_mask {
+            return None;
+        }
+        next_leaf(
+            &self.root,
+            &self.config.level_bits,
+            0,
+            self.config.address_bits(),
+            from,
+        )
+    }
+
+    /// How many runs the resident memory is in, and how spread out they
+    /// are - to tell whether it's worth moving things closer together.
+    pub fn fragmentation(&self) -> FragReport {
+        let regions = self.mapped_regions();
+        let (Some(first), Some(last)) = (regions.first(), regions.last()) else {
+            return FragReport::default();
+        };
+        FragReport {
+            runs: regions.len() as u64,
+            mapped_words: regions.iter().map(|(_, len)| len).sum(),
+            spanned_words: last.0 + last.1 - first.0,
+        }
+    }
+
+    /// Find the lowest run of size_words unmapped words starting at a
+    /// multiple of align_words, fault its leaves in and return its base -
+    /// a first-fit allocator over the address space, for tests and tools
+    /// wanting somewhere to put things. Regions allocated before are
+    /// avoided even if they've since been freed; give them back with
+    /// `deallocate`. None if nothing fits, or the quota runs out.
+    pub fn allocate(&mut self, size_words: u64, align_words: u64) -> Option<u64> {
+        assert!(align_words > 0, "alignment must be at least a word");
+        let align_up = |loc: u64| loc.checked_next_multiple_of(align_words);
+        let leaf_words = 1u64 << self.config.mem_bits;
+        let mut base = 0u64;
+        let last = loop {
+            let last = base.checked_add(size_words.checked_sub(1)?)?;
+            if last > self.address_mask {
+                return None;
+            }
+            // Past whatever's in the way, and try again.
+            let clash = self
+                .allocations
+                .range(..=last)
+                .next_back()
+                .map(|(start, len)| start + (len - 1))
+                .filter(|end| *end >= base);
+            if let Some(end) = clash {
+                base = align_up(end.checked_add(1)?)?;
+                continue;
+            }
+            match self.next_mapped(base) {
+                Some(loc) if loc <= last => {
+                    base = align_up(leaf_base(loc, self.config.mem_bits).checked_add(leaf_words)?)?;
+                }
+                _ => break last,
+            }
+        };
+        for leaf in (leaf_base(base, self.config.mem_bits)..=last).step_by(leaf_words as usize) {
+            if self.fault_in(leaf).is_err() {
+                // Only what we just faulted in: it was all unmapped.
+                self.free_range(base, size_words);
+                return None;
+            }
+        }
+        self.allocations.insert(base, size_words);
+        Some(base)
+    }
+
+    /// Free a region `allocate` gave out, returning it to the default fill,
+    /// and let it be allocated again. False if base isn't an allocation.
+    pub fn deallocate(&mut self, base: u64) -> bool {
+        match self.allocations.remove(&base) {
+            Some(len) => {
+                self.free_range(base, len);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Make the leaf covering address resident, without it counting as an
+    /// access. Reads go the write way when they need to allocate.
+    fn fault_in(&mut self, address: u64) -> Result<(), MemError> {
+        let mut root = core::mem::take(&mut self.root);
+        let result = self.write_below(&mut root, address, &mut [0], &MemOp::Read, 1);
+        self.root = root;
+        result
+    }
+
+    /// Find the biggest unmapped run in [search_start, search_end),
+    /// returned as (start, length). A fully mapped window gives a zero
+    /// length gap at search_start.
+    pub fn largest_free_gap(&self, search_start: u64, search_end: u64) -> (u64, u64) {
+        let mut best = (search_start, 0);
+        let mut cursor = search_start;
+        let consider = |from: u64, to: u64, best: &mut (u64, u64)| {
+            if to > from && to - from > best.1 {
+                *best = (from, to - from);
+            }
+        };
+        for (start, len) in self.mapped_regions() {
+            let end = start.saturating_add(len);
+            if end <= cursor {
+                continue;
+            }
+            if start >= search_end {
+                break;
+            }
+            consider(cursor, start, &mut best);
+            cursor = end;
+        }
+        consider(cursor, search_end, &mut best);
+        best
+    }
+
+    /// Render the used portion of the address space (lowest to highest
+    /// resident leaf) as a bar of `width` cells: '#' where any of the
+    /// cell is mapped, '.' where none of it is. Good for a quick look at
+    /// fragmentation.
+    pub fn ascii_map(&self, width: usize) -> String {
+        let mut leaves: Vec<u64> = Vec::new();
+        self.for_each_leaf(&mut |base, _| leaves.push(base));
+        let mut cells = vec![false; width];
+        if let (Some(lo), Some(hi)) = (leaves.first(), leaves.last()) {
+            let lo = *lo as u128;
+            let leaf_words = 1u128 << self.config.mem_bits;
+            let span = (*hi as u128) + leaf_words - lo;
+            let width = width as u128;
+            for base in leaves.iter() {
+                let start = (*base as u128) - lo;
+                let first = start * width / span;
+                let last = ((start + leaf_words) * width).div_ceil(span);
+                for cell in cells.iter_mut().take(last as usize).skip(first as usize) {
+                    *cell = true;
+                }
+            }
+        }
+        cells.iter().map(|c| if *c { '#' } else { '.' }).collect()
+    }
+
+    /// Perform an iop against a segment
+    /// iops must be aligned within a single memory segments - splitting them
+    /// happens at the cache layer (to simulate a segmented memory architecture),
+    /// or in read/write, which go through split_iop.
+    pub fn iop(&mut self, address: u64, iovec: &mut [u64], op: MemOp) -> Result<(), MemError> {
+        self.check_span(iovec.len())?;
+        self.leaf_op(address, iovec, &op)
+    }
+
+    /// Make count words from `to` another name for the words at `from`, as
+    /// a shared memory window mapped twice would be: a write through either
+    /// is seen through both. What was at `to` is freed. Both must be leaf
+    /// aligned, and count a whole number of leaves.
+    ///
+    /// Only the storage is shared - each range keeps its own permissions,
+    /// and hooks, hazards and the heatmap see the address used. Things
+    /// which walk the tree itself (snapshots, `mapped_regions`, `save`)
+    /// find the words under `from` only. Aliasing an alias aliases what it
+    /// shows; overlapping an existing alias, or `from`, is a `Conflict`.
+    pub fn alias(&mut self, from: u64, to: u64, count: u64) -> Result<(), MemError> {
+        let leaf_words = 1u64 << self.config.mem_bits;
+        if (from | to | count) & (leaf_words - 1) != 0 {
+            return Err(MemError::BadGeometry);
+        }
+        self.check_range(from, count)?;
+        self.check_range(to, count)?;
+        let mut pairs = Vec::new();
+        for i in (0..count).step_by(leaf_words as usize) {
+            let (target, view) = (self.resolve(from + i), to + i);
+            let overlaps = |base: u64| base >= to && base - to < count;
+            if overlaps(target)
+                || self.aliases.contains_key(&view)
+                || self.aliases.values().any(|target| overlaps(*target))
+            {
+                return Err(MemError::Conflict);
+            }
+            pairs.push((view, target));
+        }
+        for (view, target) in pairs {
+            self.free_leaf(view);
+            self.aliases.insert(view, target);
+        }
+        Ok(())
+    }
+
+    /// Undo `alias` for count words from `to` (leaf aligned, as for
+    /// alias), which go back to being unmapped memory of their own. Parts
+    /// which aren't aliases are left alone.
+    pub fn unalias(&mut self, to: u64, count: u64) -> Result<(), MemError> {
+        let leaf_words = 1u64 << self.config.mem_bits;
+        if (to | count) & (leaf_words - 1) != 0 {
+            return Err(MemError::BadGeometry);
+        }
+        self.check_range(to, count)?;
+        for i in (0..count).step_by(leaf_words as usize) {
+            self.aliases.remove(&(to + i));
+        }
+        Ok(())
+    }
+
+    /// Where the word at address is really kept.
+    fn resolve(&self, address: u64) -> u64 {
+        if self.aliases.is_empty() {
+            return address;
+        }
+        let base = leaf_base(address, self.config.mem_bits);
+        match self.aliases.get(&base) {
+            Some(target) => address - base + target,
+            None => address,
+        }
+    }
+
+    /// Everything which touches a leaf comes through here.
+    fn leaf_op(&mut self, address: u64, iovec: &mut [u64], op: &MemOp) -> Result<(), MemError> {
+        if self.address_remap.is_none() {
+            return self.translated_op(address, iovec, op);
+        }
+        for (i, word) in iovec.iter_mut().enumerate() {
+            let address = self.remapped(address.wrapping_add(i as u64));
+            self.translated_op(address, core::slice::from_mut(word), op)?;
+        }
+        Ok(())
+    }
+
+    /// Run f with any address remap put aside, for the whole-memory
+    /// operations, which work on the tree's own addresses.
+    fn untranslated<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let remap = self.address_remap.take();
+        let result = f(self);
+        self.address_remap = remap;
+        result
+    }
+
+    fn remapped(&self, address: u64) -> u64 {
+        match &self.address_remap {
+            Some(f) => f(address),
+            None => address,
+        }
+    }
+
+    /// leaf_op, once any address remap has been done.
+    fn translated_op(
+        &mut self,
+        address: u64,
+        iovec: &mut [u64],
+        op: &MemOp,
+    ) -> Result<(), MemError> {
+        if let Err(err) = self.check_range(address, iovec.len() as u64) {
+            if self.config.out_of_range_policy == OutOfRangePolicy::Fault {
+                return Err(err);
+            }
+            // Leaves are wholly in range or out, so all of this is out.
+            if !matches!(op, MemOp::Write) {
+                let fill = self.config.default_fill;
+                iovec.fill(self.config.unmapped_fill.unwrap_or(fill));
+            }
+            return Ok(());
+        }
+        let (reads, writes) = match op {
+            MemOp::Read => (true, false),
+            MemOp::Write => (false, true),
+            MemOp::ReadModifyWrite(_) => (true, true),
+        };
+        let len = iovec.len() as u64;
+        let mut retries = 0;
+        while let Some((loc, perms)) = self.denied(address, len, reads, writes) {
+            let handler = self.fault_handler.as_mut();
+            let action = handler.map_or(FaultAction::Deny, |handle| handle(loc, op, perms));
+            match action {
+                FaultAction::Remap { loc, count, perms } if retries < FAULT_RETRIES => {
+                    self.set_permissions(loc, count, perms);
+                    retries += 1;
+                }
+                _ => return Err(MemError::PermissionDenied),
+            }
+        }
+        if let Some(hazards) = &mut self.hazards {
+            if reads {
+                hazards.note(address, iovec.len(), false);
+            }
+            if writes {
+                hazards.note(address, iovec.len(), true);
+            }
+        }
+        if let Some(heat) = &mut self.heatmap {
+            if reads {
+                heat.note(address, iovec.len(), false);
+            }
+            if writes {
+                heat.note(address, iovec.len(), true);
+            }
+        }
+        self.run_op(address, iovec, op)?;
+        if let Some(hook) = &mut self.on_write {
+            match op {
+                MemOp::Read => (),
+                MemOp::Write => hook(address, iovec),
+                // The iovec has the old words; work out what we stored.
+                MemOp::ReadModifyWrite(f) => {
+                    let stored: Vec<u64> = iovec.iter().map(|word| f(*word)).collect();
+                    hook(address, &stored);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Is [address, address + len) within the geometry?
+    fn check_range(&self, address: u64, len: u64) -> Result<(), MemError> {
+        if len > 0 && address.saturating_add(len - 1) > self.address_mask {
+            return Err(MemError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    /// Set the permissions for count words from loc, replacing whatever
+    /// applied to them before. They belong to the addresses, not to leaves,
+    /// so they can cover unmapped memory, and leaves faulted in or freed
+    /// there later don't change them.
+    pub fn set_permissions(&mut self, loc: u64, count: u64, perms: Perms) {
+        if count == 0 {
+            return;
+        }
+        let last = loc.saturating_add(count - 1);
+        // Trim or split anything overlapping the new range.
+        let overlapping: Vec<(u64, (u64, Perms))> = self
+            .perms
+            .range(..=last)
+            .filter(|(_, (r_last, _))| *r_last >= loc)
+            .map(|(start, range)| (*start, *range))
+            .collect();
+        for (start, (r_last, r_perms)) in overlapping {
+            self.perms.remove(&start);
+            if start < loc {
+                self.perms.insert(start, (loc - 1, r_perms));
+            }
+            if r_last > last {
+                self.perms.insert(last + 1, (r_last, r_perms));
+            }
+        }
+        if perms != Perms::READ_WRITE {
+            self.perms.insert(loc, (last, perms));
+        }
+    }
+
+    /// The permissions on a single word.
+    pub fn permissions(&self, loc: u64) -> Perms {
+        match self.perms.range(..=loc).next_back() {
+            Some((_, (last, perms))) if *last >= loc => *perms,
+            _ => Perms::READ_WRITE,
+        }
+    }
+
+    fn check_perms(&self, address: u64, len: u64, is_write: bool) -> Result<(), MemError> {
+        match self.denied(address, len, !is_write, is_write) {
+            Some(_) => Err(MemError::PermissionDenied),
+            None => Ok(()),
+        }
+    }
+
+    /// The first word of [address, address + len) which may not be read
+    /// (if reads) or written (if writes), with its permissions.
+    fn denied(&self, address: u64, len: u64, reads: bool, writes: bool) -> Option<(u64, Perms)> {
+        if len == 0 || self.perms.is_empty() {
+            return None;
+        }
+        let last = address.saturating_add(len - 1);
+        let mut first = None;
+        for (start, (r_last, perms)) in self.perms.range(..=last).rev() {
+            if *r_last < address {
+                break;
+            }
+            if (reads && !perms.allows(false)) || (writes && !perms.allows(true)) {
+                first = Some(((*start).max(address), *perms));
+            }
+        }
+        first
+    }
+
+    /// Store a single word without any heap allocation (once the leaf is
+    /// resident) - register spills come through here constantly. It's
+    /// what `SyncAccess::sync_write_64` and `Access::write_64` use too,
+    /// though the latter's future is still boxed by async_trait, which
+    /// costs one allocation.
+    pub fn store_word(&mut self, loc: u64, val: u64) -> Result<(), MemError> {
+        self.leaf_op(loc, &mut [val], &MemOp::Write)
+    }
+
+    /// The word at loc, as something to `get` and `set` - so a read then a
+    /// conditional write reads like one, eg.
+    /// `let w = mem.at(loc); if w.get()? == 0 { w.set(1)? }`.
+    pub fn at(&mut self, loc: u64) -> WordRef<'_> {
+        WordRef { mem: self, loc }
+    }
+
+    /// Store just the bits of val which are set in mask, leaving the rest of
+    /// the word as it was - byte and halfword stores, or registers with
+    /// reserved bits. Done as one read-modify-write of the leaf.
+    pub fn write_masked(&mut self, loc: u64, val: u64, mask: u64) -> Result<(), MemError> {
+        let merge = move |old: u64| (old & !mask) | (val & mask);
+        self.leaf_op(loc, &mut [0], &MemOp::ReadModifyWrite(Box::new(merge)))
+    }
+
+    /// Load a single word without any heap allocation.
+    pub fn load_word(&mut self, loc: u64) -> Result<u64, MemError> {
+        let mut word = [0];
+        self.leaf_op(loc, &mut word, &MemOp::Read)?;
+        Ok(word[0])
+    }
+
+    /// Load a word, and say whether it came from unmapped memory (so it's
+    /// the fill rather than anything written) - for catching guests reading
+    /// memory they never initialised. Checked before the read, so a read
+    /// which faults the leaf in still counts as unmapped.
+    pub fn read_64_tagged(&mut self, loc: u64) -> Result<(u64, bool), MemError> {
+        let unmapped = !self.is_mapped(loc);
+        Ok((self.load_word(loc)?, unmapped))
+    }
+
+    /// Is loc backed by a resident leaf? Only walks the tables, and
+    /// doesn't fault anything in.
+    pub fn is_mapped(&self, loc: u64) -> bool {
+        self.leaf_resident(self.remapped(loc))
+    }
+
+    /// Is the leaf covering address resident? Doesn't fault anything in.
+    fn leaf_resident(&self, address: u64) -> bool {
+        address <= self.address_mask && self.resident_below(&self.root, self.resolve(address), 1)
+    }
+
+    fn resident_below(&self, node: &MemorySegment, address: u64, level: u32) -> bool {
+        match node {
+            MemorySegment::Nothing() => false,
+            MemorySegment::Memory(_) => true,
+            MemorySegment::Next(next_seg) => {
+                let idx = self.level_index(address, level);
+                self.resident_below(next_seg.get(idx), address, level + 1)
+            }
+        }
+    }
+
+    /// Drop the leaf covering address, if it's resident.
+    fn free_leaf(&mut self, address: u64) {
+        let address = self.resolve(address);
+        // Checking first means we don't copy shared tables for nothing.
+        if !self.leaf_resident(address) {
+            return;
+        }
+        let mut root = core::mem::take(&mut self.root);
+        self.free_below(&mut root, address, 1);
+        self.root = root;
+        self.resident_words
+            .set(self.resident_words.get() - (1u64 << self.config.mem_bits));
+    }
+
+    fn free_below(&self, node: &mut MemorySegment, address: u64, level: u32) {
+        match node {
+            MemorySegment::Next(next_seg) => {
+                let idx = self.level_index(address, level);
+                let next_seg = Arc::make_mut(next_seg);
+                self.free_below(next_seg.get_mut(idx), address, level + 1);
+                next_seg.prune(idx);
+            }
+            MemorySegment::Memory(_) => {
+                mem_event!("free addr={address:#x} level={level}");
+                if let MemorySegment::Memory(mem) = core::mem::take(node) {
+                    // A snapshot may still be using it.
+                    if let Ok(Leaf::Words(buf)) = Arc::try_unwrap(mem) {
+                        self.recycle_leaf(buf);
+                    }
+                }
+            }
+            MemorySegment::Nothing() => (),
+        }
+    }
+
+    /// Hand a freed leaf's buffer back to the pool, if there's room.
+    fn recycle_leaf(&self, mut buf: Vec<u64>) {
+        let mut pool = self.leaf_pool.borrow_mut();
+        if pool.len() < LEAF_POOL_CAP {
+            buf.clear();
+            pool.push(buf);
+        }
+    }
+
+    /// A fresh leaf full of default fill, from the pool if we can.
+    fn new_leaf(&self) -> MemorySegment {
+        let words = 1usize << self.config.mem_bits;
+        if let Some(factory) = &self.leaf_factory {
+            let store = factory(words, self.config.default_fill);
+            assert_eq!(store.words().len(), words, "leaf factory made a bad leaf");
+            return MemorySegment::Memory(Arc::new(Leaf::Store(store)));
+        }
+        match self.leaf_pool.borrow_mut().pop() {
+            Some(mut buf) => {
+                self.leaf_reuses.set(self.leaf_reuses.get() + 1);
+                buf.resize(words, self.config.default_fill);
+                MemorySegment::Memory(Arc::new(Leaf::Words(buf)))
+            }
+            None => MemorySegment::new_memory(self.config.mem_bits, self.config.default_fill),
+        }
+    }
+
+    /// Have leaves faulted in from now on stored however factory says
+    /// (it's given the leaf's length and fill), rather than in a plain
+    /// vector. Leaves already resident keep their storage.
+    pub fn set_leaf_factory(&mut self, factory: LeafFactory) {
+        self.leaf_factory = Some(factory);
+    }
+
+    pub fn clear_leaf_factory(&mut self) {
+        self.leaf_factory = None;
+    }
+
+    /// Make store the leaf at base (which must be leaf aligned, the store
+    /// holding exactly a leaf's words), replacing whatever was there.
+    pub fn map_leaf_store(&mut self, base: u64, store: Box<dyn LeafStore>) -> Result<(), MemError> {
+        let leaf_words = 1u64 << self.config.mem_bits;
+        if base & (leaf_words - 1) != 0 || store.words().len() as u64 != leaf_words {
+            return Err(MemError::BadGeometry);
+        }
+        self.check_range(base, leaf_words)?;
+        if !self.leaf_resident(base) {
+            if let Some(quota) = self.resident_quota {
+                if self.resident_words.get() + leaf_words > quota {
+                    return Err(MemError::QuotaExceeded);
+                }
+            }
+            self.resident_words
+                .set(self.resident_words.get() + leaf_words);
+        }
+        install_leaf(
+            &mut self.root,
+            self.config.table_strategy,
+            &self.config.level_bits,
+            base,
+            self.config.address_bits(),
+            Leaf::Store(store),
+        );
+        Ok(())
+    }
+
+    /// How many leaves were faulted in using a recycled buffer rather than
+    /// a fresh allocation.
+    pub fn leaf_reuses(&self) -> u64 {
+        self.leaf_reuses.get()
+    }
+
+    /// Set the value unmapped memory reads as, and which new leaves are
+    /// filled with when faulted in. Leaves already resident keep their
+    /// contents.
+    pub fn set_default_fill(&mut self, value: u64) {
+        self.config.default_fill = value;
+    }
+
+    /// Make reads of unmapped memory return value (eg. `POISON`) to catch
+    /// reads of memory nothing initialised. Unlike `set_default_fill` this
+    /// doesn't change what new leaves hold, so writing a word doesn't make
+    /// its neighbours look initialised. Freeing (`free_range`, or filling
+    /// with the default) still unmaps, so freed memory reads as poison too.
+    pub fn set_unmapped_fill(&mut self, value: u64) {
+        self.config.unmapped_fill = Some(value);
+    }
+
+    /// Back to unmapped memory reading as the default fill.
+    pub fn clear_unmapped_fill(&mut self) {
+        self.config.unmapped_fill = None;
+    }
+
+    /// Call `f(start, count, whole_leaf)` for each piece of [loc, loc + count)
+    /// split at leaf boundaries.
+    fn for_each_leaf_piece(&self, loc: u64, count: u64, f: &mut dyn FnMut(u64, u64, bool)) {
+        let leaf_words = 1u64 << self.config.mem_bits;
+        let mut addr = loc;
+        let mut done = 0;
+        while done < count {
+            let piece = leaf_room(addr, self.config.mem_bits).min(count - done);
+            f(addr, piece, piece == leaf_words);
+            done += piece;
+            addr = addr.wrapping_add(piece);
+        }
+    }
+
+    /// Set count words from loc to value. Filling with the default fill
+    /// keeps things sparse: covered leaves are freed rather than written,
+    /// and unmapped memory is left unmapped since it reads that way anyway.
+    pub fn fill(&mut self, loc: u64, count: u64, value: u64) -> Result<(), MemError> {
+        self.check_range(loc, count)?;
+        let mut pieces = Vec::new();
+        self.for_each_leaf_piece(loc, count, &mut |start, len, whole| {
+            pieces.push((start, len, whole))
+        });
+        for (start, len, whole) in pieces {
+            self.untranslated(|mem| mem.fill_piece(start, len, whole, value))?;
+            // Whatever was there, a constant isn't tainted.
+            self.mark_taint(start, len, false);
+        }
+        Ok(())
+    }
+
+    /// fill's work for a piece within one leaf.
+    fn fill_piece(
+        &mut self,
+        start: u64,
+        len: u64,
+        whole: bool,
+        value: u64,
+    ) -> Result<(), MemError> {
+        if value == self.config.default_fill {
+            self.check_perms(start, len, true)?;
+            if whole {
+                let resident = self.leaf_resident(start);
+                if let Some(hook) = self.on_write.as_mut().filter(|_| resident) {
+                    hook(start, &vec![value; len as usize]);
+                }
+                self.free_leaf(start);
+                return Ok(());
+            }
+            if !self.leaf_resident(start) {
+                return Ok(());
+            }
+        }
+        let mut words = vec![value; len as usize];
+        self.leaf_op(start, &mut words, &MemOp::Write)
+    }
+
+    /// Mark count words from loc as tainted - holding untrusted data, say
+    /// from a network packet - to follow where it goes. Taint is copied
+    /// along with the words by `copy_within` and `dma_transfer`, and
+    /// cleared by `fill` and `clear_taint`; other writes leave it alone,
+    /// since only the emulator knows where what it writes came from. It
+    /// doesn't need the memory mapped, and isn't kept in snapshots.
+    pub fn set_taint(&mut self, loc: u64, count: u64) {
+        self.mark_taint(loc, count, true);
+    }
+
+    pub fn clear_taint(&mut self, loc: u64, count: u64) {
+        self.mark_taint(loc, count, false);
+    }
+
+    pub fn is_tainted(&self, loc: u64) -> bool {
+        let offset = leaf_offset(loc, self.config.mem_bits);
+        self.taint
+            .get(&leaf_base(loc, self.config.mem_bits))
+            .is_some_and(|bits| bits[offset / 64] & (1 << (offset % 64)) != 0)
+    }
+
+    fn mark_taint(&mut self, loc: u64, count: u64, tainted: bool) {
+        if count == 0 || (!tainted && self.taint.is_empty()) {
+            return;
+        }
+        let mem_bits = self.config.mem_bits;
+        let last = loc.saturating_add(count - 1);
+        let bases: Vec<u64> = if tainted {
+            let mut bases = Vec::new();
+            self.for_each_leaf_piece(loc, last - loc + 1, &mut |start, _, _| {
+                bases.push(leaf_base(start, mem_bits))
+            });
+            bases
+        } else {
+            let (first, top) = (leaf_base(loc, mem_bits), leaf_base(last, mem_bits));
+            self.taint
+                .range(first..=top)
+                .map(|(base, _)| *base)
+                .collect()
+        };
+        let bitmap_words = (1usize << mem_bits).div_ceil(64);
+        for base in bases {
+            let bits = self
+                .taint
+                .entry(base)
+                .or_insert_with(|| vec![0; bitmap_words]);
+            let from = leaf_offset(loc.max(base), mem_bits);
+            let to = leaf_offset(last.min(base | ((1 << mem_bits) - 1)), mem_bits);
+            for offset in from..=to {
+                if tainted {
+                    bits[offset / 64] |= 1 << (offset % 64);
+                } else {
+                    bits[offset / 64] &= !(1 << (offset % 64));
+                }
+            }
+            if bits.iter().all(|word| *word == 0) {
+                self.taint.remove(&base);
+            }
+        }
+    }
+
+    /// Whether each of count words from loc is tainted.
+    fn taint_bits(&self, loc: u64, count: u64) -> Vec<bool> {
+        if self.taint.is_empty() {
+            return vec![false; count as usize];
+        }
+        (0..count)
+            .map(|i| self.is_tainted(loc.wrapping_add(i)))
+            .collect()
+    }
+
+    /// Set the taint from loc to match bits.
+    fn apply_taint(&mut self, loc: u64, bits: &[bool]) {
+        let mut done = 0;
+        while done < bits.len() {
+            let tainted = bits[done];
+            let run = bits[done..]
+                .iter()
+                .take_while(|bit| **bit == tainted)
+                .count();
+            self.mark_taint(loc.wrapping_add(done as u64), run as u64, tainted);
+            done += run;
+        }
+    }
+
+    /// Return count words from loc to the default fill, freeing any leaves
+    /// wholly inside the range.
+    pub fn free_range(&mut self, loc: u64, count: u64) {
+        // Can't fail: filling with the default never faults anything in.
+        let _ = self.fill(loc, count, self.config.default_fill);
+    }
+
+    /// A word, treating unmapped memory as the default fill (what it'll
+    /// hold once faulted in) whatever the policies.
+    fn word_or_fill(&mut self, loc: u64) -> Result<u64, MemError> {
+        if self.check_range(loc, 1).is_ok() && !self.leaf_resident(loc) {
+            return Ok(self.config.default_fill);
+        }
+        self.load_word(loc)
+    }
+
+    /// Store bytes starting at byte address byte_addr (word byte_addr / 8),
+    /// packing them into words in the configured endianness. Words only
+    /// partly covered keep their other bytes.
+    pub fn load_blob(&mut self, byte_addr: u64, bytes: &[u8]) -> Result<(), MemError> {
+        self.untranslated(|mem| mem.place_blob(byte_addr, bytes))
+    }
+
+    /// load_blob, with the remap put aside.
+    fn place_blob(&mut self, byte_addr: u64, bytes: &[u8]) -> Result<(), MemError> {
+        let endianness = self.config.endianness;
+        let lane_shift = |lane: u64| match endianness {
+            Endianness::Little => 8 * lane,
+            Endianness::Big => 56 - 8 * lane,
+        };
+        let mut addr = byte_addr;
+        let mut rest = bytes;
+        // Partial words go byte by byte; whole ones in one write.
+        while !rest.is_empty() {
+            let lane = addr & 7;
+            if lane == 0 && rest.len() >= 8 {
+                let whole = rest.len() / 8;
+                let mut words: Vec<u64> = rest[..whole * 8]
+                    .chunks_exact(8)
+                    .map(|chunk| {
+                        let chunk: [u8; 8] = chunk.try_into().unwrap();
+                        match endianness {
+                            Endianness::Little => u64::from_le_bytes(chunk),
+                            Endianness::Big => u64::from_be_bytes(chunk),
+                        }
+                    })
+                    .collect();
+                self.split_iop(addr >> 3, &mut words, &MemOp::Write)?;
+                addr = addr.wrapping_add(whole as u64 * 8);
+                rest = &rest[whole * 8..];
+            } else {
+                let word = self.word_or_fill(addr >> 3)?;
+                let shift = lane_shift(lane);
+                let word = (word & !(0xff << shift)) | ((rest[0] as u64) << shift);
+                self.store_word(addr >> 3, word)?;
+                addr = addr.wrapping_add(1);
+                rest = &rest[1..];
+            }
+        }
+        Ok(())
+    }
+
+    /// Stream count_words words from loc as bytes, in the given order
+    /// (whatever the memory's own endianness is), without collecting them
+    /// anywhere - for hashing or hexdumping big regions. Range, permissions
+    /// and the unmapped policy are checked up front; unmapped words come
+    /// out as the fill, and nothing is faulted in.
+    pub fn bytes_iter(
+        &mut self,
+        loc: u64,
+        count_words: u64,
+        endian: Endianness,
+    ) -> Result<impl Iterator<Item = u8> + '_, MemError> {
+        if let Err(err) = self.check_range(loc, count_words) {
+            if self.config.out_of_range_policy == OutOfRangePolicy::Fault {
+                return Err(err);
+            }
+        }
+        self.check_perms(loc, count_words, false)?;
+        if self.config.unmapped_policy == UnmappedPolicy::Fault {
+            let mut missing = false;
+            self.for_each_leaf_piece(loc, count_words, &mut |addr, _, _| {
+                missing |= self.check_range(addr, 1).is_ok() && self.leaf_at(addr).is_none();
+            });
+            if missing {
+                return Err(MemError::Unmapped);
+            }
+        }
+        if let Some(hazards) = &mut self.hazards {
+            hazards.note(loc, count_words as usize, false);
+        }
+        if let Some(heat) = &mut self.heatmap {
+            heat.note(loc, count_words as usize, false);
+        }
+        let this = &*self;
+        let mem_bits = this.config.mem_bits;
+        let fill = this
+            .config
+            .unmapped_fill
+            .unwrap_or(this.config.default_fill);
+        let (mut addr, mut left) = (loc, count_words);
+        // A piece per leaf: its words if it's there, else that many fills.
+        let pieces = core::iter::from_fn(move || {
+            if left == 0 {
+                return None;
+            }
+            let piece = leaf_room(addr, mem_bits).min(left);
+            let leaf = match this.check_range(addr, piece) {
+                Ok(()) => this.leaf_at(addr),
+                Err(_) => None,
+            };
+            let (words, gap) = match leaf {
+                Some(leaf) => {
+                    let offset = leaf_offset(addr, mem_bits);
+                    (&leaf[offset..offset + piece as usize], 0)
+                }
+                None => (&[][..], piece as usize),
+            };
+            addr = addr.wrapping_add(piece);
+            left -= piece;
+            Some(words.iter().copied().chain(core::iter::repeat_n(fill, gap)))
+        });
+        Ok(pieces.flatten().flat_map(move |word| match endian {
+            Endianness::Little => word.to_le_bytes(),
+            Endianness::Big => word.to_be_bytes(),
+        }))
+    }
+
+    /// Borrow span words from loc straight out of their leaf, without
+    /// copying. Only works when the span is within one resident leaf (and
+    /// readable); otherwise `None`, and you'll want `read`.
+    pub fn read_ref(&mut self, loc: u64, span: u32) -> Option<&[u64]> {
+        let offset = leaf_offset(loc, self.config.mem_bits);
+        if span as u64 > leaf_room(loc, self.config.mem_bits) {
+            return None;
+        }
+        self.check_range(loc, span as u64).ok()?;
+        self.check_perms(loc, span as u64, false)
+            .ok()
+            .filter(|_| self.leaf_resident(loc))?;
+        // Only noted now we know it's done; a None means they'll read
+        // instead, which notes it then.
+        if let Some(hazards) = &mut self.hazards {
+            hazards.note(loc, span as usize, false);
+        }
+        if let Some(heat) = &mut self.heatmap {
+            heat.note(loc, span as usize, false);
+        }
+        let leaf = self.leaf_at(loc)?;
+        Some(&leaf[offset..offset + span as usize])
+    }
+
+    /// The resident leaf covering address, found with no side effects at
+    /// all (so no hooks, heatmap, etc).
+    fn leaf_at(&self, address: u64) -> Option<&Leaf> {
+        let (mut node, address) = (&self.root, self.resolve(address));
+        for level in 1..self.config.max_depth {
+            match node {
+                MemorySegment::Next(next_seg) => {
+                    node = next_seg.get(self.level_index(address, level))
+                }
+                _ => return None,
+            }
+        }
+        match node {
+            MemorySegment::Memory(mem) => Some(mem),
+            _ => None,
+        }
+    }
+
+    /// Do the words from loc equal expected? Compared in constant time -
+    /// every word is read and compared, differences OR'd together with no
+    /// early exit - for emulating secure elements and the like, where a
+    /// compare bailing out at the first mismatch leaks timing. A region we
+    /// can't read isn't equal.
+    pub fn ct_eq_region(&mut self, loc: u64, expected: &[u64]) -> bool {
+        let mut words = vec![0; expected.len()];
+        if self.split_iop(loc, &mut words, &MemOp::Read).is_err() {
+            return false;
+        }
+        let diff = words
+            .iter()
+            .zip(expected.iter())
+            .fold(0, |diff, (word, want)| diff | (word ^ want));
+        // Keep the optimiser from turning the fold back into a search.
+        core::hint::black_box(diff) == 0
+    }
+
+    /// Copy count words from src to dst, taint and all. Overlapping ranges
+    /// are fine; a fault stops it part way, as for `dma_transfer`.
+    pub fn copy_within(&mut self, src: u64, dst: u64, count: u64) -> Result<(), MemError> {
+        self.dma_transfer(src, dst, count, COPY_BURST_WORDS)
+    }
+
+    /// Copy count words from src to dst the way a DMA engine would: in
+    /// bursts of up to burst words. Overlapping ranges are fine, as with
+    /// `copy_within`. A fault stops the transfer, with the bursts before it
+    /// done.
+    pub fn dma_transfer(
+        &mut self,
+        src: u64,
+        dst: u64,
+        count: u64,
+        burst: usize,
+    ) -> Result<(), MemError> {
+        self.dma_transfer_with_progress(src, dst, count, burst, &mut |_| ())
+    }
+
+    /// As `dma_transfer`, calling progress with the words transferred so
+    /// far after each burst - where a peripheral would raise its interrupt.
+    pub fn dma_transfer_with_progress(
+        &mut self,
+        src: u64,
+        dst: u64,
+        count: u64,
+        burst: usize,
+        progress: &mut dyn FnMut(u64),
+    ) -> Result<(), MemError> {
+        assert!(burst > 0, "DMA bursts must move something");
+        // Copying up into an overlapping range has to start from the end,
+        // or we'd read what we'd just written.
+        let backwards = dst > src && dst - src < count;
+        let mut buf = vec![0; burst.min(count as usize)];
+        let mut done = 0;
+        while done < count {
+            let len = (count - done).min(burst as u64);
+            let offset = if backwards { count - done - len } else { done };
+            let words = &mut buf[..len as usize];
+            self.split_iop(src.wrapping_add(offset), words, &MemOp::Read)?;
+            self.split_iop(dst.wrapping_add(offset), words, &MemOp::Write)?;
+            let taint = self.taint_bits(src.wrapping_add(offset), len);
+            self.apply_taint(dst.wrapping_add(offset), &taint);
+            done += len;
+            progress(done);
+        }
+        Ok(())
+    }
+
+    /// Read span words, also returning the base of the leaf they came from,
+    /// or `None` if they're unmapped or span more than one leaf. Handy for
+    /// following up with `read_ref` or anything else working a leaf at a
+    /// time.
+    pub fn read_with_leaf_base(&mut self, loc: u64, span: u32) -> (Vec<u64>, Option<u64>) {
+        let mut words = vec![0; span as usize];
+        let _ = self.split_iop(loc, &mut words, &MemOp::Read);
+        let mem_bits = self.config.mem_bits;
+        let within = span as u64 <= leaf_room(loc, mem_bits);
+        let base = (within && self.leaf_resident(loc)).then_some(leaf_base(loc, mem_bits));
+        (words, base)
+    }
+
+    /// Read span words, also returning how many of them came from resident
+    /// leaves rather than being default fill for unmapped memory.
+    pub fn read_resident(&mut self, loc: u64, span: u32) -> (Vec<u64>, u32) {
+        let mut words = vec![0; span as usize];
+        let _ = self.split_iop(loc, &mut words, &MemOp::Read);
+        let mut resident = 0;
+        let mut addr = loc;
+        let mut done = 0;
+        while done < span as u64 {
+            let count = leaf_room(addr, self.config.mem_bits).min(span as u64 - done);
+            if self.leaf_resident(addr) {
+                resident += count as u32;
+            }
+            done += count;
+            addr = addr.wrapping_add(count);
+        }
+        (words, resident)
+    }
+
+    /// As iop, but split at leaf boundaries so the op can span leaves.
+    fn split_iop(&mut self, address: u64, iovec: &mut [u64], op: &MemOp) -> Result<(), MemError> {
+        self.split_ops(address, iovec, op).1
+    }
+
+    /// As split_iop, but also saying how many words were done before any
+    /// fault.
+    fn split_ops(
+        &mut self,
+        address: u64,
+        iovec: &mut [u64],
+        op: &MemOp,
+    ) -> (usize, Result<(), MemError>) {
+        let mut loc = address;
+        let mut done = 0;
+        while done < iovec.len() {
+            let room = leaf_room(loc, self.config.mem_bits) as usize;
+            let count = room.min(iovec.len() - done);
+            if done == 0 && count < iovec.len() {
+                mem_event!("split addr={address:#x} len={} op={op:?}", iovec.len());
+            }
+            if let Err(err) = self.leaf_op(loc, &mut iovec[done..done + count], op) {
+                return (done, Err(err));
+            }
+            done += count;
+            loc = loc.wrapping_add(count as u64);
+        }
+        (done, Ok(()))
+    }
+
+    /// Write contents from loc, returning how many words were stored. A
+    /// fault part way through (quota, permissions, ...) gives a short count:
+    /// the words before the leaf which faulted are stored, the rest aren't.
+    /// A fault before anything was stored is an error.
+    pub fn write_words(&mut self, loc: u64, contents: &[u64]) -> Result<usize, MemError> {
+        self.check_span(contents.len())?;
+        let mut iovec = contents.to_vec();
+        match self.split_ops(loc, &mut iovec, &MemOp::Write) {
+            (0, Err(err)) if !contents.is_empty() => Err(err),
+            (done, _) => Ok(done),
+        }
+    }
+
+    /// Read span words from loc, surfacing faults rather than panicking
+    /// like `read` does.
+    pub fn try_read(&mut self, loc: u64, span: u32) -> Result<Vec<u64>, MemError> {
+        self.check_span(span as usize)?;
+        let mut iovec = vec![0; span as usize];
+        self.split_iop(loc, &mut iovec, &MemOp::Read)?;
+        Ok(iovec)
+    }
+
+    /// Gather count words, stride apart from base - a column of a row-major
+    /// matrix, say.
+    pub fn read_strided(
+        &mut self,
+        base: u64,
+        stride: u64,
+        count: usize,
+    ) -> Result<Vec<u64>, MemError> {
+        self.check_span(count)?;
+        let mut words = vec![0; count];
+        for (i, word) in words.iter_mut().enumerate() {
+            let loc = strided(base, stride, i)?;
+            self.leaf_op(loc, core::slice::from_mut(word), &MemOp::Read)?;
+        }
+        Ok(words)
+    }
+
+    /// Scatter values stride apart from base. A fault stops it there, with
+    /// the words before stored.
+    pub fn write_strided(
+        &mut self,
+        base: u64,
+        stride: u64,
+        values: &[u64],
+    ) -> Result<(), MemError> {
+        self.check_span(values.len())?;
+        for (i, val) in values.iter().enumerate() {
+            let loc = strided(base, stride, i)?;
+            self.leaf_op(loc, &mut [*val], &MemOp::Write)?;
+        }
+        Ok(())
+    }
+
+    /// The words at addrs, in the order asked for - for pointer chasing
+    /// and other big random index lists. The addresses are visited in
+    /// sorted order, and when nothing needs to see each access (no
+    /// permissions, hazards, heatmap, level hook or address remap) each
+    /// leaf is looked up once for all the addresses in it.
+    pub fn gather(&mut self, addrs: &[u64]) -> Result<Vec<u64>, MemError> {
+        let mut order: Vec<usize> = (0..addrs.len()).collect();
+        order.sort_unstable_by_key(|i| addrs[*i]);
+        let quiet = self.perms.is_empty()
+            && self.hazards.is_none()
+            && self.heatmap.is_none()
+            && self.level_hook.is_none()
+            && self.address_remap.is_none();
+        let mem_bits = self.config.mem_bits;
+        let mut words = vec![0; addrs.len()];
+        let mut i = 0;
+        while i < order.len() {
+            let base = leaf_base(addrs[order[i]], mem_bits);
+            let group = order[i..]
+                .iter()
+                .take_while(|j| leaf_base(addrs[**j], mem_bits) == base)
+                .count();
+            // leaf_at masks its indices, so out of range goes the slow way.
+            let fast = quiet && self.check_range(base, 1).is_ok();
+            match self.leaf_at(base).filter(|_| fast) {
+                Some(leaf) => {
+                    for j in &order[i..i + group] {
+                        words[*j] = leaf[leaf_offset(addrs[*j], mem_bits)];
+                    }
+                }
+                None => {
+                    for j in &order[i..i + group] {
+                        self.leaf_op(
+                            addrs[*j],
+                            core::slice::from_mut(&mut words[*j]),
+                            &MemOp::Read,
+                        )?;
+                    }
+                }
+            }
+            i += group;
+        }
+        Ok(words)
+    }
+
+    /// Store each (address, value), the last one winning where an address
+    /// comes up twice. They're stored in address order, runs of adjacent
+    /// addresses in one write apiece, so a fault stops it with the pairs
+    /// below the faulting address stored, not the ones before it in pairs.
+    pub fn scatter(&mut self, pairs: &[(u64, u64)]) -> Result<(), MemError> {
+        let mut sorted = pairs.to_vec();
+        // Stable, so duplicates stay in order and the last is kept.
+        sorted.sort_by_key(|(loc, _)| *loc);
+        sorted.reverse();
+        sorted.dedup_by_key(|(loc, _)| *loc);
+        sorted.reverse();
+        let mut i = 0;
+        while i < sorted.len() {
+            let start = sorted[i].0;
+            let run = sorted[i..]
+                .iter()
+                .enumerate()
+                .take_while(|(k, (loc, _))| start.checked_add(*k as u64) == Some(*loc))
+                .count();
+            let mut words: Vec<u64> = sorted[i..i + run].iter().map(|(_, val)| *val).collect();
+            self.split_iop(start, &mut words, &MemOp::Write)?;
+            i += run;
+        }
+        Ok(())
+    }
+
+    /// Index into the table at level for address.
+    fn level_index(&self, address: u64, level: u32) -> usize {
+        let level = (level - 1) as usize;
+        table_index(
+            address,
+            self.level_shifts[level],
+            self.config.level_bits[level],
+        )
+    }
+
+    fn run_op(&mut self, address: u64, iovec: &mut [u64], op: &MemOp) -> Result<(), MemError> {
+        let address = self.resolve(address);
+        let allocating = self.config.read_miss_policy == ReadMissPolicy::Allocate;
+        match op {
+            MemOp::Read if !allocating || self.leaf_resident(address) => {
+                self.read_below(&self.root, address, iovec, 1)
+            }
+            // Allocating reads of unmapped memory go the write way, which
+            // faults things in.
+            _ => {
+                // Take the root out so we can mutate it while reading config.
+                let mut root = core::mem::take(&mut self.root);
+                let result = self.write_below(&mut root, address, iovec, op, 1);
+                self.root = root;
+                result
+            }
+        }
+    }
+
+    fn read_below(
+        &self,
+        node: &MemorySegment,
+        address: u64,
+        iovec: &mut [u64],
+        level: u32,
+    ) -> Result<(), MemError> {
+        self.visit(level);
+        match node {
+            MemorySegment::Nothing() => {
+                if self.config.unmapped_policy == UnmappedPolicy::Fault {
+                    return Err(MemError::Unmapped);
+                }
+                iovec.fill(
+                    self.config
+                        .unmapped_fill
+                        .unwrap_or(self.config.default_fill),
+                );
+            }
+            MemorySegment::Next(next_seg) => {
+                let idx = self.level_index(address, level);
+                self.read_below(next_seg.get(idx), address, iovec, level + 1)?;
+            }
+            MemorySegment::Memory(mem) => {
+                mem.read_words(leaf_offset(address, self.config.mem_bits), iovec);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes and read-modify-writes.
+    fn write_below(
+        &self,
+        node: &mut MemorySegment,
+        address: u64,
+        iovec: &mut [u64],
+        op: &MemOp,
+        level: u32,
+    ) -> Result<(), MemError> {
+        self.visit(level);
+        self.write_at(node, address, iovec, op, level)
+    }
+
+    fn write_at(
+        &self,
+        node: &mut MemorySegment,
+        address: u64,
+        iovec: &mut [u64],
+        op: &MemOp,
+        level: u32,
+    ) -> Result<(), MemError> {
+        match node {
+            MemorySegment::Nothing() => {
+                let fill = self.config.default_fill;
+                if let MemOp::ReadModifyWrite(f) = op {
+                    if self.config.unmapped_policy == UnmappedPolicy::Fault {
+                        return Err(MemError::Unmapped);
+                    }
+                    if self.config.write_miss_policy == WriteMissPolicy::NoAllocate
+                        && f(fill) == fill
+                    {
+                        iovec.fill(fill);
+                        return Ok(());
+                    }
+                } else if matches!(op, MemOp::Write)
+                    && self.config.write_miss_policy == WriteMissPolicy::NoAllocate
+                    && iovec.iter().all(|word| *word == fill)
+                {
+                    return Ok(());
+                }
+                // Fault in whatever belongs here, and try again.
+                if level == self.config.max_depth {
+                    let leaf_words = 1u64 << self.config.mem_bits;
+                    if let Some(quota) = self.resident_quota {
+                        if self.resident_words.get() + leaf_words > quota {
+                            return Err(MemError::QuotaExceeded);
+                        }
+                    }
+                    self.resident_words
+                        .set(self.resident_words.get() + leaf_words);
+                    mem_event!("fault-in leaf addr={address:#x} level={level} op={op:?}");
+                    if let Some(events) = &self.fault_events {
+                        let base = leaf_base(address, self.config.mem_bits);
+                        events.borrow_mut().push((base, level));
+                    }
+                    *node = self.new_leaf();
+                } else {
+                    mem_event!("fault-in table addr={address:#x} level={level} op={op:?}");
+                    let seg_bits = self.config.level_bits[(level - 1) as usize];
+                    *node = MemorySegment::new_segment(self.config.table_strategy, seg_bits);
+                }
+                self.write_at(node, address, iovec, op, level)?;
+            }
+            MemorySegment::Next(next_seg) => {
+                let idx = self.level_index(address, level);
+                // Copies the table first if a snapshot shares it.
+                let next_seg = Arc::make_mut(next_seg);
+                let result = self.write_below(next_seg.get_mut(idx), address, iovec, op, level + 1);
+                // A dropped or failed write may leave an empty entry behind.
+                next_seg.prune(idx);
+                result?;
+            }
+            MemorySegment::Memory(mem) => {
+                let final_idx = leaf_offset(address, self.config.mem_bits);
+                let dst = Arc::make_mut(mem);
+                match (op, dst) {
+                    (MemOp::ReadModifyWrite(f), Leaf::Words(words)) => {
+                        for (dst_i, io_i) in words[final_idx..].iter_mut().zip(iovec.iter_mut()) {
+                            let old = *dst_i;
+                            *dst_i = f(old);
+                            *io_i = old;
+                        }
+                    }
+                    (MemOp::ReadModifyWrite(f), dst) => {
+                        dst.read_words(final_idx, iovec);
+                        let new: Vec<u64> = iovec.iter().map(|old| f(*old)).collect();
+                        dst.write_words(final_idx, &new);
+                    }
+                    (MemOp::Read, dst) => dst.read_words(final_idx, iovec),
+                    (_, dst) => dst.write_words(final_idx, iovec),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SyncAccess for TreeMemory {
+    fn sync_read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        self.try_read(loc, span).expect("read failed")
+    }
+
+    fn sync_write(&mut self, loc: u64, contents: &[u64]) {
+        self.check_span(contents.len()).expect("write failed");
+        let mut a_spurious_copy = contents.to_vec();
+        self.split_iop(loc, &mut a_spurious_copy, &MemOp::Write)
+            .expect("write failed");
+    }
+
+    fn sync_read_64(&mut self, loc: u64) -> u64 {
+        self.load_word(loc).expect("read failed")
+    }
+
+    fn sync_write_64(&mut self, loc: u64, val: u64) {
+        self.store_word(loc, val).expect("write failed");
+    }
+}
+
+/// How much `load_reader` reads at a time.
+#[cfg(feature = "std")]
+pub const LOAD_CHUNK_BYTES: usize = 64 * 1024;
+
+#[cfg(feature = "std")]
+impl TreeMemory {
+    /// count words from loc as byte slices borrowed straight from their
+    /// leaves, one per leaf they span and in order, for a vectored write
+    /// to a host file or socket without copying. The bytes are the words
+    /// as the host lays them out, in its own byte order. Unmapped leaves
+    /// are faulted in first (which can fail on the quota); the range must
+    /// be readable.
+    pub fn as_iovecs(
+        &mut self,
+        loc: u64,
+        count: u64,
+    ) -> Result<Vec<std::io::IoSlice<'_>>, MemError> {
+        self.check_range(loc, count)?;
+        self.check_perms(loc, count, false)?;
+        let mut pieces = Vec::new();
+        self.for_each_leaf_piece(loc, count, &mut |start, len, _| pieces.push((start, len)));
+        for (start, len) in &pieces {
+            if !self.leaf_resident(*start) {
+                self.fault_in(*start)?;
+            }
+            if let Some(hazards) = &mut self.hazards {
+                hazards.note(*start, *len as usize, false);
+            }
+            if let Some(heat) = &mut self.heatmap {
+                heat.note(*start, *len as usize, false);
+            }
+        }
+        let mem_bits = self.config.mem_bits;
+        Ok(pieces
+            .into_iter()
+            .map(|(start, len)| {
+                let leaf = self.leaf_at(start).expect("faulted in above");
+                let offset = leaf_offset(start, mem_bits);
+                let words = &leaf[offset..offset + len as usize];
+                // Safety: any u64 is 8 initialised bytes, and u8 has no
+                // alignment to break.
+                let bytes = unsafe {
+                    core::slice::from_raw_parts(words.as_ptr() as *const u8, words.len() * 8)
+                };
+                std::io::IoSlice::new(bytes)
+            })
+            .collect())
+    }
+
+    /// Stream everything from reader into memory from byte address addr
+    /// (as `load_blob`), a chunk at a time so big images never have to be
+    /// held in memory whole. Returns how many bytes were loaded. The last
+    /// word can be partial, in which case its other bytes are kept.
+    pub fn load_reader<R: std::io::Read>(
+        &mut self,
+        addr: u64,
+        reader: &mut R,
+    ) -> std::io::Result<u64> {
+        let mut buf = vec![0u8; LOAD_CHUNK_BYTES];
+        let mut total = 0u64;
+        loop {
+            let got = match reader.read(&mut buf) {
+                Ok(0) => return Ok(total),
+                Ok(got) => got,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            self.load_blob(addr.wrapping_add(total), &buf[..got])
+                .map_err(|err| std::io::Error::other(format!("{err:?}")))?;
+            total += got as u64;
+        }
+    }
+}
+
+/// Most words `TreeMemory::copy_within` holds in flight at once.
+const COPY_BURST_WORDS: usize = 4096;
+
+/// Most words `copy_between` will hold in flight at once.
+#[cfg(feature = "std")]
+pub const COPY_CHUNK_WORDS: u64 = 4096;
+
+/// Stream `count` words from one memory to another, a chunk at a time so
+/// we never allocate more than `COPY_CHUNK_WORDS` - eg. to move an image
+/// built in a scratch `VectorMemory` into a live `TreeMemory`.
+#[cfg(feature = "std")]
+pub async fn copy_between<S: Access + Send, D: Access + Send>(
+    src: &mut S,
+    dst: &mut D,
+    src_loc: u64,
+    dst_loc: u64,
+    count: u64,
+) {
+    let mut done = 0;
+    while done < count {
+        let chunk = (count - done).min(COPY_CHUNK_WORDS);
+        let data = src.read(src_loc.wrapping_add(done), chunk as u32).await;
+        dst.write(dst_loc.wrapping_add(done), &data).await;
+        done += chunk;
+    }
+}
+
+#[cfg(feature = "std")]
+impl TreeMemory {
+    /// Copy the (start, length) regions from other into us - a patch image
+    /// over a base one, say - doing whatever conflict says about words we
+    /// already have mapped. Faults (quota, permissions) stop it part way.
+    pub async fn merge_from<A: Access + Send>(
+        &mut self,
+        other: &mut A,
+        regions: &[(u64, u64)],
+        conflict: ConflictPolicy,
+    ) -> Result<(), MemError> {
+        let mut pieces = Vec::new();
+        for (start, len) in regions {
+            self.check_range(*start, *len)?;
+            self.for_each_leaf_piece(*start, *len, &mut |loc, count, _| pieces.push((loc, count)));
+        }
+        let pieces: Vec<(u64, u64, bool)> = pieces
+            .into_iter()
+            .map(|(loc, count)| (loc, count, self.leaf_resident(loc)))
+            .collect();
+        if conflict == ConflictPolicy::Error && pieces.iter().any(|(_, _, mapped)| *mapped) {
+            return Err(MemError::Conflict);
+        }
+        for (loc, count, mapped) in pieces {
+            if mapped && conflict == ConflictPolicy::KeepExisting {
+                continue;
+            }
+            let mut done = 0;
+            while done < count {
+                let chunk = (count - done).min(COPY_CHUNK_WORDS);
+                let mut words = other.read(loc + done, chunk as u32).await;
+                self.split_iop(loc + done, &mut words, &MemOp::Write)?;
+                done += chunk;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Store bytes into any memory from word addr_word, packed little-endian
+/// (byte 0 in the low byte of the first word). Whole words go as one write;
+/// the bytes of a partial last word are stored one at a time as 8 bit
+/// fields, so the rest of that word is kept. `TreeMemory::load_blob` does
+/// the same for byte addresses and either endianness.
+#[cfg(feature = "std")]
+pub async fn write_bytes_le<A: Access + Send>(mem: &mut A, addr_word: u64, bytes: &[u8]) {
+    let whole = bytes.len() / 8;
+    if whole > 0 {
+        let words: Vec<u64> = bytes[..whole * 8]
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        mem.write(addr_word, &words).await;
+    }
+    let tail = addr_word.wrapping_add(whole as u64);
+    for (lane, byte) in bytes[whole * 8..].iter().enumerate() {
+        mem.write_bits(tail, 8 * lane as u32, 8, *byte as u64).await;
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// One step of FNV-1a, over a whole word at a time.
+fn fnv_step(hash: u64, word: u64) -> u64 {
+    (hash ^ word).wrapping_mul(0x0000_0100_0000_01b3)
+}
+
+/// A cheap rolling checksum of some words.
+#[cfg(feature = "std")]
+fn checksum(words: &[u64]) -> u64 {
+    words
+        .iter()
+        .fold(FNV_OFFSET, |hash, word| fnv_step(hash, *word))
+}
+
+/// Do two memories hold the same words over each (start, length) region?
+/// Each chunk is checked by checksum first, which rules most mismatches
+/// out without comparing word by word; matching checksums are confirmed
+/// in full, so the answer is exact. A yes/no for "are these two emulator
+/// states the same".
+#[cfg(feature = "std")]
+pub async fn content_equal<A: Access + Send, B: Access + Send>(
+    a: &mut A,
+    b: &mut B,
+    regions: &[(u64, u64)],
+) -> bool {
+    for (start, len) in regions.iter() {
+        let mut done = 0;
+        while done < *len {
+            let chunk = (*len - done).min(COPY_CHUNK_WORDS);
+            let loc = start.wrapping_add(done);
+            let from_a = a.read(loc, chunk as u32).await;
+            let from_b = b.read(loc, chunk as u32).await;
+            if checksum(&from_a) != checksum(&from_b) || from_a != from_b {
+                return false;
+            }
+            done += chunk;
+        }
+    }
+    true
+}
+
+#[cfg(feature = "std")]
+#[async_trait]
+impl Access for TreeMemory {
+    async fn read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        self.sync_read(loc, span)
+    }
+
+    async fn write(&mut self, loc: u64, contents: &[u64]) {
+        self.sync_write(loc, contents)
+    }
+
+    async fn read_64(&mut self, loc: u64) -> u64 {
+        self.load_word(loc).expect("read failed")
+    }
+
+    async fn write_64(&mut self, loc: u64, val: u64) {
+        self.store_word(loc, val).expect("write failed");
+    }
+
+    async fn try_read_64(&mut self, loc: u64) -> Result<u64, MemError> {
+        self.load_word(loc)
+    }
+
+    fn is_mapped(&self, loc: u64) -> bool {
+        TreeMemory::is_mapped(self, loc)
+    }
+
+    /// Skips unmapped memory rather than reading it.
+    async fn is_zero(&mut self, loc: u64, count: u64) -> bool {
+        TreeMemory::is_zero(self, loc, count)
+    }
+
+    async fn try_write_64(&mut self, loc: u64, val: u64) -> Result<(), MemError> {
+        self.store_word(loc, val)
+    }
+
+    async fn try_write(&mut self, loc: u64, contents: &[u64]) -> Result<usize, MemError> {
+        self.write_words(loc, contents)
+    }
+
+    /// One walk per leaf, even for read-modify-writes.
+    async fn iop(&mut self, loc: u64, iovec: &mut [u64], op: MemOp) {
+        self.check_span(iovec.len()).expect("iop failed");
+        self.split_iop(loc, iovec, &op).expect("iop failed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "std")]
+    use crate::utils::flat_memory::FlatMemory;
+    use crate::utils::memory;
+    #[cfg(feature = "std")]
+    use crate::utils::vector_memory::VectorMemory;
+    #[test]
+    fn create_memory() {
+        let _mem = memory::TreeMemory::new();
+    }
+
+    #[test]
+    fn check_io() {
+        let mut mem = memory::TreeMemory::new();
+        let mut some_data: Vec<u64> = vec![2, 34, 67, 0x898, 0x12345678];
+        mem.iop(0, &mut some_data, MemOp::Write).unwrap();
+        let mut other_data: Vec<u64> = vec![0; 16];
+        mem.iop(0, &mut other_data, MemOp::Read).unwrap();
+        assert_eq!(some_data, other_data[0..some_data.len()]);
+    }
+
+    /// A base with 0x100.. mapped, and a patch covering 0xf0..0x110.
+    #[cfg(feature = "std")]
+    async fn merge_setup() -> (TreeMemory, VectorMemory) {
+        let mut base = memory::TreeMemory::builder()
+            .level_bits(vec![8, 8])
+            .mem_bits(4)
+            .build()
+            .unwrap();
+        base.write_words(0x100, &[1; 0x10]).unwrap();
+        let mut patch = VectorMemory::new();
+        patch.write(0xf0, &[2; 0x20]).await;
+        (base, patch)
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_merge_overwrite() {
+        let (mut base, mut patch) = merge_setup().await;
+        let regions = [(0xf8, 0x10), (0x200, 2)];
+        base.merge_from(&mut patch, &regions, ConflictPolicy::Overwrite)
+            .await
+            .unwrap();
+        assert_eq!(base.try_read(0xf6, 2), Ok(vec![0, 0]));
+        assert_eq!(base.try_read(0xf8, 0x10), Ok(vec![2; 0x10]));
+        assert_eq!(base.try_read(0x108, 8), Ok(vec![1; 8]));
+        assert_eq!(base.try_read(0x200, 3), Ok(vec![0, 0, 0]));
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_merge_keep_existing() {
+        let (mut base, mut patch) = merge_setup().await;
+        let regions = [(0xf8, 0x10)];
+        base.merge_from(&mut patch, &regions, ConflictPolicy::KeepExisting)
+            .await
+            .unwrap();
+        assert_eq!(base.try_read(0xf8, 8), Ok(vec![2; 8]));
+        assert_eq!(base.try_read(0x100, 0x10), Ok(vec![1; 0x10]));
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_merge_conflict_error() {
+        let (mut base, mut patch) = merge_setup().await;
+        let regions = [(0xf0, 0x10), (0x10f, 1)];
+        assert_eq!(
+            base.merge_from(&mut patch, &regions, ConflictPolicy::Error)
+                .await,
+            Err(MemError::Conflict)
+        );
+        // Nothing was copied, not even the region without a conflict.
+        assert_eq!(base.try_read(0xf0, 0x10), Ok(vec![0; 0x10]));
+        assert_eq!(base.load_word(0x10f), Ok(1));
+        base.merge_from(&mut patch, &regions[..1], ConflictPolicy::Error)
+            .await
+            .unwrap();
+        assert_eq!(base.try_read(0xf0, 0x10), Ok(vec![2; 0x10]));
+    }
+
+    /// Nothing here actually waits, so needs no executor: polling once
+    /// finishes the job.
+    #[cfg(feature = "std")]
+    fn run_now<F: core::future::Future>(fut: F) -> F::Output {
+        use core::task::{Context, Poll, Waker};
+        let mut fut = core::pin::pin!(fut);
+        match fut.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(out) => out,
+            Poll::Pending => panic!("a memory future blocked"),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn check_without_executor() {
+        let mut mem = memory::TreeMemory::new();
+        run_now(mem.write(0x1000, &[1, 2, 3]));
+        run_now(mem.set_bit(0x1003, 7));
+        assert_eq!(run_now(mem.read(0x1000, 4)), vec![1, 2, 3, 0x80]);
+        assert!(run_now(mem.try_write_64(0x2000, 4)).is_ok());
+        let mut copy = VectorMemory::new();
+        run_now(copy_between(&mut mem, &mut copy, 0x1000, 0, 4));
+        assert_eq!(copy.sync_read(0, 4), vec![1, 2, 3, 0x80]);
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_swap() {
+        let mut mem = memory::TreeMemory::new();
+        assert_eq!(mem.swap(0x10, 5).await, 0);
+        assert_eq!(mem.swap(0x10, 6).await, 5);
+        assert_eq!(mem.read_64(0x10).await, 6);
+        // Through the default, read then write.
+        let mut vec = VectorMemory::new();
+        vec.write_64(3, 1).await;
+        assert_eq!(vec.swap(3, 2).await, 1);
+        assert_eq!(vec.read_64(3).await, 2);
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_interface() {
+        let mut mem = memory::TreeMemory::new();
+        let mut data_out = vec![238];
+        mem.iop(0, &mut data_out, MemOp::Write).unwrap();
+        let mut data_out2 = vec![45678];
+        mem.iop(0, &mut data_out2, MemOp::Write).unwrap();
+        assert_eq!(mem.read_64(0).await, 45678);
+        let data_out3 = vec![12345];
+        mem.write(0, &data_out3).await;
+        assert_eq!(mem.read_64(0).await, 12345);
+
+        mem.write_64(0, 0x45788).await;
+        let mut data_in = vec![0];
+        mem.iop(0, &mut data_in, MemOp::Read).unwrap();
+        assert_eq!(data_in[0], 0x45788);
+        assert_eq!(mem.read_64(0).await, 0x45788);
+        mem.write_64(0x12345678u64, 42).await;
+        assert_eq!(mem.read_64(0).await, 0x45788);
+        assert_eq!(mem.read_64(0x12345678u64).await, 42);
+        assert_eq!(mem.read_64(1).await, 0);
+    }
+
+    #[test]
+    fn check_short_write_keeps_neighbours() {
+        let mut mem = memory::TreeMemory::new();
+        mem.iop(0x40, &mut [1, 2, 3, 4, 5], MemOp::Write).unwrap();
+        mem.iop(0x40, &mut [10, 20, 30], MemOp::Write).unwrap();
+        let mut data_in = vec![0; 5];
+        mem.iop(0x40, &mut data_in, MemOp::Read).unwrap();
+        assert_eq!(data_in, vec![10, 20, 30, 4, 5]);
+        // And the word before the write is untouched too.
+        mem.iop(0x3f, &mut data_in, MemOp::Read).unwrap();
+        assert_eq!(data_in, vec![0, 10, 20, 30, 4]);
+
+        // Ending a word short of a leaf boundary, and straddling one.
+        let leaf = 1u64 << mem.config().mem_bits;
+        mem.write_words(leaf - 4, &[1, 2, 3, 4, 5]).unwrap();
+        mem.write_words(leaf - 4, &[10, 20, 30]).unwrap();
+        assert_eq!(mem.try_read(leaf - 4, 5), Ok(vec![10, 20, 30, 4, 5]));
+        mem.write_words(leaf - 2, &[40, 50, 60]).unwrap();
+        assert_eq!(mem.try_read(leaf - 3, 5), Ok(vec![20, 40, 50, 60, 0]));
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_short_write_keeps_neighbours_via_access() {
+        let mut mem = memory::TreeMemory::new();
+        let mut flat = FlatMemory::<256>::new();
+        mem.write(0x40, &[1, 2, 3, 4, 5]).await;
+        mem.write(0x40, &[10, 20, 30]).await;
+        assert_eq!(mem.read(0x40, 5).await, vec![10, 20, 30, 4, 5]);
+        flat.write(0x40, &[1, 2, 3, 4, 5]).await;
+        flat.write(0x40, &[10, 20, 30]).await;
+        assert_eq!(flat.read(0x40, 5).await, vec![10, 20, 30, 4, 5]);
+
+        let leaf = 1u64 << mem.config().mem_bits;
+        mem.write(leaf - 4, &[1, 2, 3, 4, 5]).await;
+        mem.write(leaf - 4, &[10, 20, 30]).await;
+        assert_eq!(mem.read(leaf - 4, 5).await, vec![10, 20, 30, 4, 5]);
+        mem.write(leaf - 2, &[1, 2, 3, 4, 5]).await;
+        mem.write(leaf - 2, &[40, 50, 60]).await;
+        assert_eq!(mem.read(leaf - 2, 5).await, vec![40, 50, 60, 4, 5]);
+    }
+
+    #[cfg(feature = "std")]
+    async fn replay<T: Access + Send>(mem: &mut T) -> Vec<u64> {
+        let mut data_out = vec![3, 4, 5];
+        mem.iop(0x100, &mut data_out, MemOp::Write).await;
+        let mut data_in = vec![0; 4];
+        mem.iop(0xff, &mut data_in, MemOp::Read).await;
+        data_in
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_trait_iop() {
+        let mut mem = memory::TreeMemory::new();
+        assert_eq!(replay(&mut mem).await, vec![0, 3, 4, 5]);
+        assert_eq!(mem.read_64(0x101).await, 4);
+        let mut flat = FlatMemory::<512>::new();
+        assert_eq!(replay(&mut flat).await, vec![0, 3, 4, 5]);
+    }
+
+    #[test]
+    fn check_quota() {
+        let mut mem = memory::TreeMemory::new();
+        let leaf_words = 1u64 << mem.config.mem_bits;
+        mem.set_resident_quota(2 * leaf_words);
+        mem.iop(0, &mut [1], MemOp::Write).unwrap();
+        mem.iop(1 << 40, &mut [2], MemOp::Write).unwrap();
+        assert_eq!(mem.resident_words(), 2 * leaf_words);
+        assert_eq!(
+            mem.iop(2 << 40, &mut [3], MemOp::Write),
+            Err(MemError::QuotaExceeded)
+        );
+        assert_eq!(mem.resident_words(), 2 * leaf_words);
+        // Already resident, so fine.
+        mem.iop(5, &mut [4], MemOp::Write).unwrap();
+        mem.iop((1 << 40) + 7, &mut [5], MemOp::Write).unwrap();
+        let mut data_in = vec![0; 1];
+        mem.iop(2 << 40, &mut data_in, MemOp::Read).unwrap();
+        assert_eq!(data_in[0], 0);
+        mem.iop(5, &mut data_in, MemOp::Read).unwrap();
+        assert_eq!(data_in[0], 4);
+    }
+
+    #[test]
+    fn check_sync_access() {
+        let mut mem = memory::TreeMemory::new();
+        mem.sync_write(0x20, &[7, 8, 9]);
+        assert_eq!(mem.sync_read(0x1f, 4), vec![0, 7, 8, 9]);
+        mem.sync_write_64(0x21, 42);
+        assert_eq!(mem.sync_read_64(0x21), 42);
+        assert_eq!(mem.sync_read_64(0x12345678), 0);
+    }
+
+    #[test]
+    fn check_leaves_dont_alias() {
+        let mut mem = memory::TreeMemory::new();
+        // Every bit of the address should matter.
+        for bit in 0..64 {
+            mem.sync_write_64(1 << bit, bit + 1);
+        }
+        for bit in 0..64 {
+            assert_eq!(mem.sync_read_64(1 << bit), bit + 1);
+        }
+        assert_eq!(mem.sync_read_64(0), 0);
+    }
+
+    #[test]
+    fn check_addressing_helpers() {
+        assert_eq!(memory::level_shifts(&[12; 4], 16), vec![52, 40, 28, 16]);
+        assert_eq!(
+            memory::level_shifts(&[9, 9, 9, 9], 12),
+            vec![39, 30, 21, 12]
+        );
+        assert_eq!(memory::level_shifts(&[], 8), vec![]);
+        for (level_bits, mem_bits) in [
+            (vec![12; 4], 16),
+            (vec![9, 9, 9, 9], 12),
+            (vec![1], 1),
+            (vec![32], 32),
+            (vec![], 20),
+        ] {
+            let shifts = memory::level_shifts(&level_bits, mem_bits);
+            let bits = level_bits.iter().sum::<u32>() + mem_bits;
+            let mask = if bits == 64 {
+                u64::MAX
+            } else {
+                (1 << bits) - 1
+            };
+            // Every boundary of every level (and just either side of it),
+            // plus the ends of memory.
+            let mut addresses = vec![0, 1, mask, mask - 1, 0x5555_5555_5555_5555 & mask];
+            for shift in shifts.iter().chain([&mem_bits]) {
+                let edge = 1u64 << shift;
+                addresses.extend([edge - 1, edge, edge + 1, mask & !(edge - 1)]);
+            }
+            for address in addresses {
+                let address = address & mask;
+                let offset = memory::leaf_offset(address, mem_bits);
+                assert!((offset as u64) < 1 << mem_bits);
+                let base = memory::leaf_base(address, mem_bits);
+                assert_eq!(base + offset as u64, address);
+                assert_eq!(
+                    memory::leaf_room(address, mem_bits),
+                    (1 << mem_bits) - offset as u64
+                );
+                // The indices and offset put back together are the address.
+                let mut rebuilt = offset as u64;
+                for (shift, bits) in shifts.iter().zip(level_bits.iter()) {
+                    let idx = memory::table_index(address, *shift, *bits);
+                    assert!((idx as u64) < 1 << bits);
+                    rebuilt |= (idx as u64) << shift;
+                }
+                assert_eq!(
+                    rebuilt, address,
+                    "{address:#x} in {level_bits:?} + {mem_bits}"
+                );
+            }
+        }
+        assert_eq!(memory::table_index(0xabc0_0000_0000_0000, 52, 12), 0xabc);
+        assert_eq!(memory::table_index(u64::MAX, 0, 32), 0xffff_ffff);
+        assert_eq!(memory::leaf_room(0x1_ffff, 16), 1);
+        assert_eq!(memory::leaf_room(0x2_0000, 16), 0x1_0000);
+    }
+
+    #[test]
+    fn check_geometry_validation() {
+        assert_eq!(
+            memory::TreeMemory::builder()
+                .level_bits(vec![9, 9, 9, 9, 17])
+                .mem_bits(12)
+                .build()
+                .err(),
+            Some(MemError::BadGeometry)
+        );
+        assert_eq!(
+            memory::TreeMemory::builder()
+                .level_bits(vec![0, 16, 16, 16])
+                .mem_bits(16)
+                .build()
+                .err(),
+            Some(MemError::BadGeometry)
+        );
+        assert!(memory::TreeMemory::builder()
+            .uniform_levels(8, 7)
+            .mem_bits(8)
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn check_asymmetric_geometry() {
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![9, 9, 9, 9, 16])
+            .mem_bits(12)
+            .build()
+            .unwrap();
+        assert_eq!(mem.config.max_depth, 6);
+        // Poke at each level's field boundaries; leaves are 12 bits and
+        // the tables sit at shifts of 12, 28, 37, 46 and 55.
+        let addrs: Vec<u64> = vec![
+            0,
+            0xfff,
+            1 << 12,
+            0xffff << 12,
+            1 << 28,
+            0x1ff << 28,
+            1 << 37,
+            1 << 46,
+            0x1ff << 55,
+            u64::MAX,
+        ];
+        for (i, addr) in addrs.iter().enumerate() {
+            mem.sync_write_64(*addr, i as u64 + 100);
+        }
+        for (i, addr) in addrs.iter().enumerate() {
+            assert_eq!(mem.sync_read_64(*addr), i as u64 + 100);
+        }
+        // 0 and 0xfff share a leaf.
+        assert_eq!(mem.resident_words(), (addrs.len() as u64 - 1) * 0x1000);
+    }
+
+    #[test]
+    fn check_ascii_map() {
+        let mut mem = memory::TreeMemory::new();
+        assert_eq!(mem.ascii_map(4), "....");
+        let leaf_words = 1u64 << mem.config.mem_bits;
+        mem.sync_write_64(10, 1);
+        mem.sync_write_64(3 * leaf_words + 10, 1);
+        assert_eq!(mem.ascii_map(4), "#..#");
+        assert_eq!(mem.ascii_map(8), "##....##");
+        mem.sync_write_64(leaf_words, 1);
+        assert_eq!(mem.ascii_map(4), "##.#");
+        assert_eq!(mem.ascii_map(2), "##");
+    }
+
+    #[test]
+    fn check_spanning_access() {
+        let mut mem = memory::TreeMemory::new();
+        let leaf_words = 1u64 << mem.config.mem_bits;
+        mem.sync_write(leaf_words - 2, &[1, 2, 3, 4]);
+        assert_eq!(mem.resident_words(), 2 * leaf_words);
+        assert_eq!(mem.sync_read(leaf_words - 3, 6), vec![0, 1, 2, 3, 4, 0]);
+        let mut data_in = vec![0; 2];
+        mem.iop(leaf_words, &mut data_in, MemOp::Read).unwrap();
+        assert_eq!(data_in, vec![3, 4]);
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_copy_between() {
+        let mut scratch = VectorMemory::new();
+        let image: Vec<u64> = (0..10000).map(|i| i * 3 + 1).collect();
+        scratch.write(5, &image).await;
+        let mut mem = memory::TreeMemory::new();
+        // Land it across a leaf boundary, too.
+        let dst = (1u64 << mem.config.mem_bits) - 100;
+        copy_between(&mut scratch, &mut mem, 5, dst, image.len() as u64).await;
+        assert_eq!(mem.read(dst, image.len() as u32).await, image);
+        assert_eq!(mem.read_64(dst - 1).await, 0);
+        assert_eq!(mem.read_64(dst + image.len() as u64).await, 0);
+    }
+
+    #[test]
+    fn check_free_gaps() {
+        let mut mem = memory::TreeMemory::new();
+        let leaf = 1u64 << mem.config.mem_bits;
+        assert_eq!(mem.largest_free_gap(0, 100), (0, 100));
+        // Leaves 1 & 2, 4, and 10 are mapped.
+        for idx in [1, 2, 4, 10] {
+            mem.sync_write_64(idx * leaf + 3, 1);
+        }
+        assert_eq!(
+            mem.mapped_regions(),
+            vec![(leaf, 2 * leaf), (4 * leaf, leaf), (10 * leaf, leaf)]
+        );
+        assert_eq!(mem.largest_free_gap(0, 20 * leaf), (11 * leaf, 9 * leaf));
+        assert_eq!(mem.largest_free_gap(0, 10 * leaf), (5 * leaf, 5 * leaf));
+        assert_eq!(mem.largest_free_gap(0, 4 * leaf), (0, leaf));
+        assert_eq!(mem.largest_free_gap(leaf + 5, 3 * leaf), (leaf + 5, 0));
+        assert_eq!(
+            mem.largest_free_gap(2 * leaf, 7 * leaf),
+            (5 * leaf, 2 * leaf)
+        );
+    }
+
+    #[test]
+    fn check_hazard_hook() {
+        use std::sync::{Arc, Mutex};
+        let seen: Arc<Mutex<Vec<Hazard>>> = Arc::new(Mutex::new(Vec::new()));
+        let mut mem = memory::TreeMemory::new();
+        let sink = seen.clone();
+        mem.set_hazard_hook(2, Box::new(move |h| sink.lock().unwrap().push(h)));
+        mem.sync_read(0x10, 2);
+        mem.sync_write_64(0x11, 5);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![Hazard {
+                loc: 0x11,
+                kind: HazardKind::WriteAfterRead,
+                distance: 1
+            }]
+        );
+        seen.lock().unwrap().clear();
+        mem.sync_read_64(0x11);
+        assert_eq!(seen.lock().unwrap()[0].kind, HazardKind::ReadAfterWrite);
+        // Outside the window, and same-kind accesses, are fine.
+        seen.lock().unwrap().clear();
+        mem.sync_read_64(0x20);
+        mem.sync_read_64(0x30);
+        mem.sync_read_64(0x30);
+        mem.sync_write_64(0x20, 1);
+        assert!(seen.lock().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_bit_fields() {
+        let mut mem = memory::TreeMemory::new();
+        mem.write_64(0x10, 0xffff_0000_ffff_0000).await;
+        // Inside one word.
+        assert_eq!(mem.read_bits(0x10, 12, 8).await, 0xf0);
+        mem.write_bits(0x10, 4, 8, 0xab).await;
+        assert_eq!(mem.read_64(0x10).await, 0xffff_0000_ffff_0ab0);
+        assert_eq!(mem.read_bits(0x10, 4, 8).await, 0xab);
+        // Straddling two words.
+        mem.write_bits(0x20, 60, 8, 0x5a).await;
+        assert_eq!(mem.read_64(0x20).await, 0xa000_0000_0000_0000);
+        assert_eq!(mem.read_64(0x21).await, 0x5);
+        assert_eq!(mem.read_bits(0x20, 60, 8).await, 0x5a);
+        // Offsets past a word just move along.
+        assert_eq!(mem.read_bits(0x1f, 124, 8).await, 0x5a);
+        // Full width, aligned and not.
+        mem.write_bits(0x30, 0, 64, 0x0123_4567_89ab_cdef).await;
+        assert_eq!(mem.read_bits(0x30, 0, 64).await, 0x0123_4567_89ab_cdef);
+        mem.write_bits(0x40, 32, 64, 0x0123_4567_89ab_cdef).await;
+        assert_eq!(mem.read_64(0x40).await, 0x89ab_cdef_0000_0000);
+        assert_eq!(mem.read_64(0x41).await, 0x0123_4567);
+        assert_eq!(mem.read_bits(0x40, 32, 64).await, 0x0123_4567_89ab_cdef);
+        // Out of range bits of the value are dropped.
+        mem.write_bits(0x50, 0, 4, 0xff).await;
+        assert_eq!(mem.read_64(0x50).await, 0xf);
+    }
+
+    #[test]
+    fn check_snapshot_restore() {
+        let mut mem = memory::TreeMemory::new();
+        mem.sync_write(0x100, &[1, 2, 3]);
+        mem.sync_write_64(1 << 40, 4);
+        let snap = mem.snapshot();
+        assert_eq!(snap.leaf_count(), 2);
+        mem.sync_write(0x100, &[9, 9]);
+        mem.sync_write_64(1 << 50, 5);
+        mem.restore(&snap).unwrap();
+        assert_eq!(mem.sync_read(0x100, 3), vec![1, 2, 3]);
+        assert_eq!(mem.sync_read_64(1 << 40), 4);
+        assert_eq!(mem.sync_read_64(1 << 50), 0);
+        assert_eq!(mem.snapshot(), snap);
+        mem.reset();
+        assert_eq!(mem.resident_words(), 0);
+        assert_eq!(mem.sync_read_64(0x100), 0);
+    }
+
+    #[test]
+    fn check_word_fast_path() {
+        let mut mem = memory::TreeMemory::new();
+        mem.store_word(0x100, 17).unwrap();
+        mem.store_word(u64::MAX, 18).unwrap();
+        assert_eq!(mem.load_word(0x100), Ok(17));
+        assert_eq!(mem.load_word(u64::MAX), Ok(18));
+        assert_eq!(mem.sync_read(0xff, 2), vec![0, 17]);
+        mem.set_resident_quota(mem.resident_words());
+        assert_eq!(mem.store_word(1 << 40, 1), Err(MemError::QuotaExceeded));
+    }
+
+    #[test]
+    fn check_read_resident() {
+        let mut mem = memory::TreeMemory::new();
+        let leaf = 1u64 << mem.config.mem_bits;
+        assert_eq!(mem.read_resident(0, 4), (vec![0; 4], 0));
+        mem.sync_write_64(leaf - 1, 5);
+        // Two words from the resident leaf, three from beyond it.
+        assert_eq!(mem.read_resident(leaf - 2, 5), (vec![0, 5, 0, 0, 0], 2));
+        mem.sync_write_64(leaf + 1, 6);
+        assert_eq!(mem.read_resident(leaf - 2, 5), (vec![0, 5, 0, 6, 0], 5));
+        assert_eq!(mem.read_resident(3 * leaf, 4).1, 0);
+        // Nothing got faulted in by looking.
+        assert_eq!(mem.resident_words(), 2 * leaf);
+    }
+
+    #[test]
+    fn check_validate() {
+        let mut mem = memory::TreeMemory::new();
+        assert_eq!(mem.validate(), Ok(()));
+        mem.sync_write(0x100, &[1, 2, 3]);
+        mem.sync_write_64(1 << 40, 4);
+        assert_eq!(mem.validate(), Ok(()));
+        let leaf = 1usize << mem.config.mem_bits;
+        // A well formed region set is fine...
+        let good = MemorySnapshot::from_regions(
+            &mem.config,
+            vec![(0, vec![5; leaf]), (1 << 40, vec![6; leaf])],
+        );
+        mem.restore(&good).unwrap();
+        assert_eq!(mem.validate(), Ok(()));
+        assert_eq!(mem.sync_read_64(1 << 40), 6);
+        // ...but a truncated leaf isn't.
+        let bad = MemorySnapshot::from_regions(
+            &mem.config,
+            vec![(0, vec![5; leaf]), (1 << 40, vec![1, 2, 3])],
+        );
+        mem.restore(&bad).unwrap();
+        let err = mem.validate().unwrap_err();
+        assert!(err.contains("has 3 words"), "{err}");
+    }
+
+    #[test]
+    fn check_default_fill() {
+        let mut mem = memory::TreeMemory::new();
+        let leaf = 1u64 << mem.config.mem_bits;
+        mem.set_default_fill(0xaa);
+        assert_eq!(mem.sync_read(leaf - 1, 2), vec![0xaa, 0xaa]);
+        // Filling a large sparse range with the default allocates nothing.
+        mem.fill(5, 10 * leaf, 0xaa).unwrap();
+        assert_eq!(mem.resident_words(), 0);
+        assert_eq!(mem.sync_read_64(3 * leaf), 0xaa);
+        // A new leaf starts out filled, too.
+        mem.sync_write_64(10, 1);
+        assert_eq!(mem.sync_read(9, 3), vec![0xaa, 1, 0xaa]);
+        // Any other value does allocate.
+        mem.fill(leaf - 2, leaf + 4, 0x55).unwrap();
+        assert_eq!(mem.resident_words(), 3 * leaf);
+        assert_eq!(mem.sync_read(leaf - 3, 3), vec![0xaa, 0x55, 0x55]);
+        assert_eq!(mem.sync_read(2 * leaf + 1, 2), vec![0x55, 0xaa]);
+        // Back to the default frees the whole leaf and patches the rest.
+        mem.fill(leaf - 2, leaf + 4, 0xaa).unwrap();
+        assert_eq!(mem.resident_words(), 2 * leaf);
+        assert_eq!(mem.sync_read(leaf - 3, 3), vec![0xaa; 3]);
+        assert_eq!(mem.sync_read_64(10), 1);
+        mem.free_range(0, 3 * leaf);
+        assert_eq!(mem.resident_words(), 0);
+        assert_eq!(mem.validate(), Ok(()));
+    }
+
+    #[test]
+    fn check_permissions_outlive_leaves() {
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![8, 8])
+            .mem_bits(8)
+            .read_miss_policy(ReadMissPolicy::Allocate)
+            .build()
+            .unwrap();
+        mem.store_word(0x1000, 1).unwrap();
+        mem.set_permissions(0x1000, 0x400, Perms::READ_ONLY);
+        // Fault new leaves in under the range with allocating reads.
+        assert!(!mem.is_mapped(0x1200));
+        assert_eq!(mem.load_word(0x1200), Ok(0));
+        assert_eq!(mem.gather(&[0x13ff, 0x1300]), Ok(vec![0, 0]));
+        assert!(mem.is_mapped(0x1200) && mem.is_mapped(0x1300));
+        for loc in [0x1000, 0x1200, 0x1300, 0x13ff] {
+            assert_eq!(mem.permissions(loc), Perms::READ_ONLY);
+            assert_eq!(mem.store_word(loc, 2), Err(MemError::PermissionDenied));
+        }
+        // Nor does freeing and faulting back in reset them.
+        mem.reset();
+        assert_eq!(mem.load_word(0x1000), Ok(0));
+        assert_eq!(mem.store_word(0x1000, 2), Err(MemError::PermissionDenied));
+        assert_eq!(mem.store_word(0x1400, 2), Ok(()));
+    }
+
+    #[test]
+    fn check_permission_ranges() {
+        let mut mem = memory::TreeMemory::new();
+        mem.set_permissions(0x100, 0x100, Perms::READ_ONLY);
+        mem.set_permissions(0x180, 0x10, Perms::NONE);
+        assert_eq!(mem.permissions(0xff), Perms::READ_WRITE);
+        assert_eq!(mem.permissions(0x100), Perms::READ_ONLY);
+        assert_eq!(mem.permissions(0x185), Perms::NONE);
+        assert_eq!(mem.permissions(0x190), Perms::READ_ONLY);
+        assert_eq!(mem.permissions(0x1ff), Perms::READ_ONLY);
+        assert_eq!(mem.permissions(0x200), Perms::READ_WRITE);
+        mem.set_permissions(0x100, 0x200, Perms::READ_WRITE);
+        assert_eq!(mem.permissions(0x185), Perms::READ_WRITE);
+        assert_eq!(mem.perms.len(), 0);
+        // Freeing protected memory counts as writing it.
+        mem.sync_write_64(0x10, 1);
+        mem.set_permissions(0x10, 1, Perms::READ_ONLY);
+        assert_eq!(
+            mem.fill(0, 1 << mem.config.mem_bits, 0),
+            Err(MemError::PermissionDenied)
+        );
+        assert_eq!(mem.sync_read_64(0x10), 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_fallible_access() {
+        let mut mem = memory::TreeMemory::new();
+        mem.write_64(0x104, 7).await;
+        mem.set_permissions(0x100, 0x10, Perms::READ_ONLY);
+        mem.set_permissions(0x200, 0x10, Perms::NONE);
+        assert_eq!(
+            mem.try_write_64(0x105, 1).await,
+            Err(MemError::PermissionDenied)
+        );
+        assert_eq!(mem.try_read_64(0x104).await, Ok(7));
+        assert_eq!(
+            mem.try_read_64(0x205).await,
+            Err(MemError::PermissionDenied)
+        );
+        assert_eq!(mem.try_write_64(0x120, 2).await, Ok(()));
+        assert_eq!(mem.read_64(0x120).await, 2);
+        // Spans are checked as a whole.
+        let mut data = vec![1, 2];
+        assert_eq!(
+            mem.iop(0xff, &mut data, MemOp::Write),
+            Err(MemError::PermissionDenied)
+        );
+        assert_eq!(mem.read_64(0xff).await, 0);
+        mem.set_resident_quota(mem.resident_words());
+        assert_eq!(
+            mem.try_write_64(1 << 40, 2).await,
+            Err(MemError::QuotaExceeded)
+        );
+    }
+
+    #[test]
+    fn check_config() {
+        let mem = memory::TreeMemory::new();
+        assert_eq!(*mem.config(), TreeConfig::default());
+        let mem = memory::TreeMemory::builder()
+            .level_bits(vec![16, 16, 16])
+            .mem_bits(16)
+            .endianness(Endianness::Big)
+            .unmapped_policy(UnmappedPolicy::Fault)
+            .default_fill(0xff)
+            .build()
+            .unwrap();
+        assert_eq!(
+            *mem.config(),
+            TreeConfig {
+                level_bits: vec![16, 16, 16],
+                max_depth: 4,
+                mem_bits: 16,
+                endianness: Endianness::Big,
+                unmapped_policy: UnmappedPolicy::Fault,
+                write_miss_policy: WriteMissPolicy::Allocate,
+                read_miss_policy: ReadMissPolicy::NoAllocate,
+                out_of_range_policy: OutOfRangePolicy::Fault,
+                table_strategy: TableStrategy::Dense,
+                default_fill: 0xff,
+                unmapped_fill: None,
+            }
+        );
+        let mut config = mem.config().clone();
+        config.max_depth = 3;
+        assert_eq!(
+            memory::TreeMemory::with_config(config).err(),
+            Some(MemError::BadGeometry)
+        );
+    }
+
+    #[test]
+    fn check_unmapped_policy() {
+        let mut mem = memory::TreeMemory::builder()
+            .unmapped_policy(UnmappedPolicy::Fault)
+            .build()
+            .unwrap();
+        assert_eq!(mem.load_word(0x10), Err(MemError::Unmapped));
+        mem.store_word(0x10, 3).unwrap();
+        assert_eq!(mem.load_word(0x11), Ok(0));
+        assert_eq!(mem.load_word(0x10), Ok(3));
+    }
+
+    #[test]
+    fn check_load_blob() {
+        let mut mem = memory::TreeMemory::new();
+        mem.store_word(0, u64::MAX).unwrap();
+        mem.load_blob(3, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14])
+            .unwrap();
+        assert_eq!(mem.load_word(0), Ok(0x0504_0302_01ff_ffff));
+        assert_eq!(mem.load_word(1), Ok(0x0d0c_0b0a_0908_0706));
+        assert_eq!(mem.load_word(2), Ok(0x0e));
+        let mut mem = memory::TreeMemory::builder()
+            .endianness(Endianness::Big)
+            .build()
+            .unwrap();
+        mem.load_blob(8, &[1, 2, 3, 4, 5, 6, 7, 8, 9]).unwrap();
+        assert_eq!(mem.load_word(1), Ok(0x0102_0304_0506_0708));
+        assert_eq!(mem.load_word(2), Ok(0x0900_0000_0000_0000));
+    }
+
+    #[test]
+    fn check_bytes_iter() {
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![8, 8])
+            .mem_bits(4)
+            .default_fill(0xaa)
+            .build()
+            .unwrap();
+        mem.write_words(0x1e, &[0x0102_0304_0506_0708, 0x1122_3344_5566_7788, 9])
+            .unwrap();
+        let words = mem.try_read(0x1c, 40).unwrap();
+        let le: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        let be: Vec<u8> = words.iter().flat_map(|w| w.to_be_bytes()).collect();
+        let segments = mem.segment_count();
+        assert_eq!(
+            mem.bytes_iter(0x1c, 40, Endianness::Little)
+                .unwrap()
+                .collect::<Vec<u8>>(),
+            le
+        );
+        assert_eq!(
+            mem.bytes_iter(0x1c, 40, Endianness::Big)
+                .unwrap()
+                .collect::<Vec<u8>>(),
+            be
+        );
+        assert_eq!(&be[16..20], &[1, 2, 3, 4]);
+        assert_eq!(be[0], 0);
+        assert_eq!(mem.segment_count(), segments);
+        // Bytes loaded as a blob come back the same way round.
+        let blob: Vec<u8> = (0..64).collect();
+        mem.load_blob(0x400, &blob).unwrap();
+        let back: Vec<u8> = mem
+            .bytes_iter(0x80, 8, Endianness::Little)
+            .unwrap()
+            .collect();
+        assert_eq!(back, blob);
+        assert_eq!(mem.bytes_iter(0, 0, Endianness::Big).unwrap().count(), 0);
+        assert!(mem.bytes_iter(0xff_fffe, 3, Endianness::Big).is_err());
+        mem.set_permissions(0x60, 1, Perms::NONE);
+        assert_eq!(
+            mem.bytes_iter(0x50, 0x20, Endianness::Big).err(),
+            Some(MemError::PermissionDenied)
+        );
+    }
+
+    #[test]
+    fn check_leaf_pool() {
+        let mut mem = memory::TreeMemory::new();
+        let leaf = 1u64 << mem.config.mem_bits;
+        mem.store_word(0x5000, 1).unwrap();
+        for round in 0..20u64 {
+            mem.free_range(0, leaf);
+            assert_eq!(mem.resident_words(), 0);
+            mem.store_word(0x5001, round).unwrap();
+            // Recycled leaves come back as default fill.
+            assert_eq!(mem.load_word(0x5000), Ok(0));
+            assert_eq!(mem.leaf_reuses(), round + 1);
+        }
+        // Never more than the cap hanging about.
+        for n in 0..(2 * LEAF_POOL_CAP as u64) {
+            mem.store_word(n * leaf, 1).unwrap();
+        }
+        mem.free_range(0, 2 * LEAF_POOL_CAP as u64 * leaf);
+        assert_eq!(mem.resident_words(), 0);
+        assert!(mem.leaf_pool.borrow().len() <= LEAF_POOL_CAP);
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_pod() {
+        #[repr(C)]
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        struct Descriptor {
+            addr_lo: u32,
+            addr_hi: u32,
+            len: u32,
+            flags: u16,
+            next: u16,
+            tag: [u8; 4],
+        }
+        unsafe impl Pod for Descriptor {}
+
+        let mut mem = memory::TreeMemory::new();
+        let desc = Descriptor {
+            addr_lo: 0xbeef_0000,
+            addr_hi: 0xdead,
+            len: 0x200,
+            flags: 3,
+            next: 7,
+            tag: *b"ring",
+        };
+        // 20 bytes - rounds up to 3 words, and the end of the last is kept.
+        mem.write_64(0x102, u64::MAX).await;
+        mem.write_pod(0x100, &desc).await;
+        assert_eq!(mem.read_pod::<Descriptor>(0x100).await, desc);
+        assert_eq!(mem.read_64(0x100).await, 0xdead_beef_0000);
+        assert_eq!(mem.read_64(0x102).await >> 32, 0xffff_ffff);
+        assert_eq!(mem.read_pod::<[u16; 2]>(0x101).await, [0x200, 0]);
+    }
+
+    #[test]
+    fn check_footprint_report() {
+        let mut mem = memory::TreeMemory::new();
+        assert_eq!(mem.segment_count(), 0);
+        // Two leaves sharing everything but the last table, and one off on
+        // its own from the root.
+        mem.store_word(0, 1).unwrap();
+        mem.store_word(0x1_0000, 1).unwrap();
+        mem.store_word(1 << 63, 1).unwrap();
+        let report = mem.footprint_report();
+        let tables: Vec<u64> = report.levels.iter().map(|l| l.tables).collect();
+        let leaves: Vec<u64> = report.levels.iter().map(|l| l.leaves).collect();
+        assert_eq!(tables, vec![1, 2, 2, 2, 0]);
+        assert_eq!(leaves, vec![0, 0, 0, 0, 3]);
+        assert_eq!(report.levels[4].bytes, (3 * 8) << mem.config.mem_bits);
+        assert_eq!(mem.segment_count(), 10);
+        assert_eq!(report.leaves() << mem.config.mem_bits, mem.resident_words());
+    }
+
+    #[test]
+    fn check_read_with_leaf_base() {
+        let mut mem = memory::TreeMemory::new();
+        let leaf_words = 1u64 << mem.config.mem_bits;
+        mem.sync_write(0x3_0005, &[1, 2, 3]);
+        let (words, base) = mem.read_with_leaf_base(0x3_0005, 3);
+        assert_eq!(words, vec![1, 2, 3]);
+        assert_eq!(base, Some(0x3_0000));
+        assert_eq!(base.unwrap() % leaf_words, 0);
+        assert_eq!(mem.read_with_leaf_base(0x3_ffff, 1).1, Some(0x3_0000));
+        // Spanning into the next leaf, or unmapped.
+        mem.store_word(0x4_0000, 4).unwrap();
+        assert_eq!(mem.read_with_leaf_base(0x3_ffff, 2), (vec![0, 4], None));
+        assert_eq!(mem.read_with_leaf_base(0x5_0000, 2), (vec![0, 0], None));
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn check_log_events() {
+        use std::sync::Mutex;
+        struct Capture(Mutex<Vec<String>>);
+        impl log::Log for Capture {
+            fn enabled(&self, meta: &log::Metadata) -> bool {
+                meta.target() == "revproc1::memory"
+            }
+            fn log(&self, record: &log::Record) {
+                if self.enabled(record.metadata()) {
+                    self.0.lock().unwrap().push(record.args().to_string());
+                }
+            }
+            fn flush(&self) {}
+        }
+        static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+        log::set_logger(&CAPTURE).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+
+        // Other tests run alongside, so look for our addresses.
+        let mut mem = memory::TreeMemory::new();
+        mem.sync_write(0x7777_0000_ffff, &[1, 2]);
+        mem.free_range(0x7777_0000_0000, 0x1_0000);
+        let events = CAPTURE.0.lock().unwrap().clone();
+        let has = |event: &str| events.iter().any(|e| e == event);
+        assert!(has("split addr=0x77770000ffff len=2 op=Write"));
+        assert!(has("fault-in table addr=0x77770000ffff level=1 op=Write"));
+        assert!(has("fault-in leaf addr=0x77770000ffff level=5 op=Write"));
+        assert!(has("fault-in leaf addr=0x777700010000 level=5 op=Write"));
+        assert!(has("free addr=0x777700000000 level=5"));
+    }
+
+    #[test]
+    fn check_ct_eq_region() {
+        use core::sync::atomic::{AtomicU32, Ordering};
+        let mut mem = memory::TreeMemory::new();
+        let key: Vec<u64> = (0..0x2_0004).collect();
+        mem.sync_write(0xfffe, &key);
+        assert!(mem.ct_eq_region(0xfffe, &key));
+        assert!(mem.ct_eq_region(0xfffe, &[]));
+
+        // Mismatching first or last word still reads every leaf.
+        let leaves = Arc::new(AtomicU32::new(0));
+        let counter = leaves.clone();
+        let max_depth = mem.config.max_depth;
+        mem.set_level_hook(Box::new(move |level| {
+            if level == max_depth {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        }));
+        for at in [0, key.len() - 1] {
+            let mut wrong = key.clone();
+            wrong[at] ^= 1 << 40;
+            leaves.store(0, Ordering::Relaxed);
+            assert!(!mem.ct_eq_region(0xfffe, &wrong));
+            assert_eq!(leaves.load(Ordering::Relaxed), 4);
+        }
+        mem.clear_level_hook();
+
+        mem.set_permissions(0x3_0000, 1, Perms::NONE);
+        assert!(!mem.ct_eq_region(0xfffe, &key));
+    }
+
+    #[test]
+    fn check_dma_transfer() {
+        let mut mem = memory::TreeMemory::new();
+        let data: Vec<u64> = (1..=10).collect();
+        mem.sync_write(0xfffa, &data);
+        let mut bursts = Vec::new();
+        mem.dma_transfer_with_progress(0xfffa, 0x5_0000, 10, 4, &mut |done| bursts.push(done))
+            .unwrap();
+        assert_eq!(bursts, vec![4, 8, 10]);
+        assert_eq!(mem.sync_read(0x5_0000, 10), data);
+
+        // Overlapping, both ways.
+        mem.dma_transfer(0x5_0000, 0x5_0002, 10, 3).unwrap();
+        assert_eq!(mem.sync_read(0x5_0000, 12), [&[1, 2], &data[..]].concat());
+        mem.dma_transfer(0x5_0002, 0x5_0000, 10, 3).unwrap();
+        assert_eq!(mem.sync_read(0x5_0000, 10), data);
+
+        // One burst, and none at all.
+        let mut calls = 0;
+        mem.dma_transfer_with_progress(0, 0x100, 10, 64, &mut |_| calls += 1)
+            .unwrap();
+        mem.dma_transfer_with_progress(0, 0x100, 0, 64, &mut |_| calls += 1)
+            .unwrap();
+        assert_eq!(calls, 1);
+
+        // A fault part way leaves the bursts before it done.
+        mem.set_permissions(0x6_0004, 1, Perms::READ_ONLY);
+        let mut bursts = 0;
+        assert_eq!(
+            mem.dma_transfer_with_progress(0x5_0000, 0x6_0000, 10, 4, &mut |_| bursts += 1),
+            Err(MemError::PermissionDenied)
+        );
+        assert_eq!(bursts, 1);
+        assert_eq!(mem.sync_read(0x6_0000, 5), vec![1, 2, 3, 4, 0]);
+    }
+
+    /// The simplest store there is, which should be no different to the
+    /// memory's own leaves.
+    #[derive(Clone)]
+    struct PlainLeaf(Vec<u64>);
+
+    impl LeafStore for PlainLeaf {
+        fn words(&self) -> &[u64] {
+            &self.0
+        }
+        fn write_words(&mut self, offset: usize, src: &[u64]) {
+            self.0[offset..offset + src.len()].copy_from_slice(src);
+        }
+        fn clone_store(&self) -> Box<dyn LeafStore> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn check_leaf_store() {
+        let mut plain = memory::TreeMemory::new();
+        let mut stored = memory::TreeMemory::new();
+        stored.set_leaf_factory(Box::new(|len, fill| Box::new(PlainLeaf(vec![fill; len]))));
+        let mut snaps = Vec::new();
+        for mem in [&mut plain, &mut stored] {
+            mem.sync_write(0xfffe, &[1, 2, 3, 4]);
+            mem.store_word(1 << 40, 5).unwrap();
+            let snap = mem.snapshot();
+            let mut old = [0; 2];
+            mem.iop(
+                0xffff,
+                &mut old,
+                MemOp::ReadModifyWrite(Box::new(|w| w * 10)),
+            )
+            .unwrap();
+            assert_eq!(old, [2, 0]);
+            mem.free_range(1 << 40, 1 << 16);
+            snaps.push((snap, mem.snapshot()));
+            assert_eq!(mem.validate(), Ok(()));
+        }
+        assert_eq!(plain.fingerprint(), stored.fingerprint());
+        assert_eq!(plain.sync_read(0xfffe, 4), stored.sync_read(0xfffe, 4));
+        assert_eq!(stored.sync_read(0xfffe, 4), vec![1, 20, 3, 4]);
+        assert_eq!(snaps[0], snaps[1]);
+        // The snapshot's leaves weren't touched by the writes after it.
+        stored.restore(&snaps[1].0).unwrap();
+        assert_eq!(stored.sync_read(0xfffe, 4), vec![1, 2, 3, 4]);
+        assert_eq!(stored.sync_read_64(1 << 40), 5);
+        assert_eq!(stored.read_ref(0xffff, 1), Some(&[2][..]));
+    }
+
+    #[test]
+    fn check_map_leaf_store() {
+        use core::sync::atomic::{AtomicU32, Ordering};
+        /// Counts the writes it sees.
+        struct Watched(Vec<u64>, Arc<AtomicU32>);
+        impl LeafStore for Watched {
+            fn words(&self) -> &[u64] {
+                &self.0
+            }
+            fn write_words(&mut self, offset: usize, src: &[u64]) {
+                self.1.fetch_add(1, Ordering::Relaxed);
+                self.0[offset..offset + src.len()].copy_from_slice(src);
+            }
+            fn clone_store(&self) -> Box<dyn LeafStore> {
+                Box::new(Watched(self.0.clone(), self.1.clone()))
+            }
+        }
+        let mut mem = memory::TreeMemory::new();
+        let writes = Arc::new(AtomicU32::new(0));
+        let words = 1usize << mem.config.mem_bits;
+        let page = Watched(vec![7; words], writes.clone());
+        mem.map_leaf_store(0x3_0000, Box::new(page)).unwrap();
+        assert_eq!(mem.resident_words(), words as u64);
+        assert_eq!(mem.sync_read(0x2_ffff, 2), vec![0, 7]);
+        mem.sync_write(0x3_fffe, &[1, 2, 3]);
+        assert_eq!(writes.load(Ordering::Relaxed), 1);
+        assert_eq!(mem.sync_read(0x3_fffe, 3), vec![1, 2, 3]);
+        assert_eq!(mem.validate(), Ok(()));
+
+        let bad = |len| Box::new(PlainLeaf(vec![0; len]));
+        assert_eq!(
+            mem.map_leaf_store(0x3_0001, bad(words)),
+            Err(MemError::BadGeometry)
+        );
+        assert_eq!(
+            mem.map_leaf_store(0x5_0000, bad(3)),
+            Err(MemError::BadGeometry)
+        );
+        // Replacing a resident leaf doesn't count it twice.
+        mem.map_leaf_store(0x3_0000, bad(words)).unwrap();
+        assert_eq!(mem.resident_words(), 2 * words as u64);
+        assert_eq!(mem.sync_read_64(0x3_0000), 0);
+    }
+
+    #[test]
+    fn check_sparse_words() {
+        let mut mem = memory::TreeMemory::new();
+        assert_eq!(mem.sparse_words(0), vec![]);
+        mem.store_word(0x1_2345, 6).unwrap();
+        assert_eq!(mem.sparse_words(0), vec![(0x1_2345, 6)]);
+        mem.store_word(1 << 63, 7).unwrap();
+        mem.store_word(0x1_0000, 8).unwrap();
+        assert_eq!(
+            mem.sparse_words(0),
+            vec![(0x1_0000, 8), (0x1_2345, 6), (1 << 63, 7)]
+        );
+        // Against some other default, the zeroes count.
+        let leaf_words = 1usize << mem.config.mem_bits;
+        assert_eq!(mem.sparse_words(6).len(), 2 * leaf_words - 1);
+    }
+
+    #[test]
+    fn check_heatmap() {
+        let mut mem = memory::TreeMemory::new();
+        assert_eq!(mem.heatmap(), vec![]);
+        mem.enable_heatmap(0x1000, 0x1000, 4);
+        // A hot loop over one small table...
+        for i in 0..100 {
+            let word = mem.load_word(0x1810 + i % 8).unwrap();
+            mem.store_word(0x1810 + i % 8, word + 1).unwrap();
+        }
+        // ...a little elsewhere, and some outside what we're watching.
+        mem.sync_write(0x10fe, &[1, 2, 3, 4]);
+        // (Straddling two bins.)
+        mem.sync_read(0x1bff, 2);
+        mem.sync_write(0x5000, &[1; 100]);
+        assert_eq!(
+            mem.heatmap(),
+            vec![
+                (0x1000, 0, 4),
+                (0x1400, 0, 0),
+                (0x1800, 101, 100),
+                (0x1c00, 1, 0),
+            ]
+        );
+        // The bin with the loop in is the hottest by far.
+        let heat = mem.heatmap();
+        let hottest = heat.iter().max_by_key(|(_, r, w)| r + w).unwrap();
+        assert_eq!(hottest.0, 0x1800);
+
+        // Ragged bins, and counts not carried over.
+        mem.enable_heatmap(0, 10, 3);
+        mem.sync_write(0, &[0; 10]);
+        assert_eq!(mem.heatmap(), vec![(0, 0, 4), (4, 0, 4), (8, 0, 2)]);
+        mem.disable_heatmap();
+        assert_eq!(mem.heatmap(), vec![]);
+    }
+
+    #[test]
+    fn check_max_span() {
+        let mut mem = memory::TreeMemory::new();
+        mem.set_max_span(16);
+        assert_eq!(mem.try_read(0x10, 16), Ok(vec![0; 16]));
+        assert_eq!(mem.try_read(0x10, u32::MAX), Err(MemError::SpanTooLarge));
+        assert_eq!(mem.write_words(0, &[1; 17]), Err(MemError::SpanTooLarge));
+        assert_eq!(mem.write_words(0, &[1; 16]), Ok(16));
+        let mut big = [0; 17];
+        assert_eq!(
+            mem.iop(0, &mut big, MemOp::Read),
+            Err(MemError::SpanTooLarge)
+        );
+        assert_eq!(mem.fill(0, 1 << 20, 5), Ok(()));
+        mem.clear_max_span();
+        assert_eq!(mem.try_read(0x10, 17).map(|w| w.len()), Ok(17));
+    }
+
+    #[test]
+    #[should_panic(expected = "SpanTooLarge")]
+    fn check_max_span_read_panics() {
+        let mut mem = memory::TreeMemory::new();
+        mem.set_max_span(4);
+        mem.sync_read(0, 5);
+    }
+
+    #[test]
+    fn check_debug_bounded() {
+        // A deep tree of small tables, with lots of leaves.
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![4; 12])
+            .mem_bits(4)
+            .build()
+            .unwrap();
+        // Scattered all over, so every level fans out.
+        for i in 0..2000u64 {
+            let loc = i.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 12;
+            mem.store_word(loc, i).unwrap();
+        }
+        let out = format!("{mem:?}");
+        assert!(out.len() < 4096, "{} bytes of debug", out.len());
+        assert!(out.contains("populated"));
+        assert!(out.contains("more"));
+        let pretty = format!("{mem:#?}");
+        assert!(pretty.len() < 16384, "{} bytes of debug", pretty.len());
+
+        let empty = memory::TreeMemory::new();
+        assert!(format!("{empty:?}").contains("Nothing"));
+    }
+
+    #[test]
+    fn check_snapshot_range() {
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![8, 8])
+            .mem_bits(8)
+            .build()
+            .unwrap();
+        // A stack straddling a leaf boundary, and something either side.
+        let stack: Vec<u64> = (1..=0x40).collect();
+        mem.write_words(0x1e0, &stack).unwrap();
+        mem.store_word(0x1df, 0xaa).unwrap();
+        mem.store_word(0x220, 0xbb).unwrap();
+        let snap = mem.snapshot_range(0x1e0, 0x40);
+        assert_eq!((snap.loc(), snap.span()), (0x1e0, 0x40));
+
+        mem.fill(0x1d0, 0x80, 0xdead).unwrap();
+        mem.store_word(0x3000, 7).unwrap();
+        mem.restore_range(&snap).unwrap();
+        assert_eq!(mem.try_read(0x1e0, 0x40), Ok(stack));
+        assert_eq!(mem.load_word(0x1df), Ok(0xdead));
+        assert_eq!(mem.load_word(0x220), Ok(0xdead));
+        assert_eq!(mem.load_word(0x3000), Ok(7));
+
+        // Unmapped when snapshotted means default fill when restored, and
+        // restoring doesn't fault in leaves outside the window.
+        let empty = mem.snapshot_range(0x8000, 0x200);
+        mem.store_word(0x8100, 1).unwrap();
+        let leaves = mem.footprint_report().leaves();
+        mem.restore_range(&empty).unwrap();
+        assert_eq!(mem.load_word(0x8100), Ok(0));
+        assert!(mem.footprint_report().leaves() < leaves);
+        assert_eq!(
+            mem.restore_range(&memory::TreeMemory::new().snapshot_range(1 << 40, 1)),
+            Err(MemError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn check_fault_handler() {
+        use std::sync::{Arc, Mutex};
+        let mut mem = memory::TreeMemory::new();
+        // Nothing above 0x1000 is mapped until it's touched.
+        mem.set_permissions(0x1000, 0x1000, Perms::NONE);
+        mem.set_permissions(0x10, 1, Perms::READ_ONLY);
+        assert_eq!(mem.load_word(0x1234), Err(MemError::PermissionDenied));
+        let faults = Arc::new(Mutex::new(Vec::new()));
+        let seen = faults.clone();
+        mem.set_fault_handler(Box::new(move |loc, op, perms| {
+            seen.lock().unwrap().push((loc, format!("{op:?}"), perms));
+            if loc < 0x1000 {
+                return FaultAction::Deny;
+            }
+            FaultAction::Remap {
+                loc: loc & !0xff,
+                count: 0x100,
+                perms: Perms::READ_WRITE,
+            }
+        }));
+        mem.store_word(0x1234, 5).unwrap();
+        assert_eq!(mem.load_word(0x1234), Ok(5));
+        // Straddles two unmapped pages, so faults twice.
+        mem.write_words(0x13fe, &[1, 2, 3]).unwrap();
+        assert_eq!(mem.store_word(0x10, 1), Err(MemError::PermissionDenied));
+        assert_eq!(
+            *faults.lock().unwrap(),
+            vec![
+                (0x1234, "Write".to_string(), Perms::NONE),
+                (0x13fe, "Write".to_string(), Perms::NONE),
+                (0x1400, "Write".to_string(), Perms::NONE),
+                (0x10, "Write".to_string(), Perms::READ_ONLY),
+            ]
+        );
+        assert_eq!(mem.permissions(0x1300), Perms::READ_WRITE);
+        assert_eq!(mem.permissions(0x1500), Perms::NONE);
+
+        // A handler which never fixes anything doesn't loop forever.
+        mem.set_fault_handler(Box::new(|loc, _, perms| FaultAction::Remap {
+            loc,
+            count: 1,
+            perms,
+        }));
+        assert_eq!(mem.load_word(0x1800), Err(MemError::PermissionDenied));
+        mem.clear_fault_handler();
+        assert_eq!(mem.load_word(0x1900), Err(MemError::PermissionDenied));
+    }
+
+    #[test]
+    fn check_strided() {
+        let mut mem = memory::TreeMemory::new();
+        let data: Vec<u64> = (0..0x200).collect();
+        mem.write_words(0xff00, &data).unwrap();
+        let every_8th: Vec<u64> = (0..0x40).map(|i| i * 8).collect();
+        assert_eq!(mem.read_strided(0xff00, 8, 0x40), Ok(every_8th));
+        assert_eq!(mem.read_strided(0xff01, 0, 2), Ok(vec![1, 1]));
+
+        // A column of a 4x4 matrix.
+        mem.write_strided(0x40, 4, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(mem.try_read(0x40, 5), Ok(vec![1, 0, 0, 0, 2]));
+        assert_eq!(mem.read_strided(0x40, 4, 4), Ok(vec![1, 2, 3, 4]));
+        assert_eq!(mem.read_strided(0, u64::MAX, 3), Err(MemError::OutOfRange));
+        mem.set_permissions(0x48, 1, Perms::READ_ONLY);
+        assert_eq!(
+            mem.write_strided(0x40, 4, &[5, 6, 7]),
+            Err(MemError::PermissionDenied)
+        );
+        assert_eq!(mem.read_strided(0x40, 4, 3), Ok(vec![5, 6, 3]));
+    }
+
+    #[test]
+    fn check_diff_against_snapshot() {
+        let mut mem = memory::TreeMemory::new();
+        mem.write_words(0x100, &[1, 2, 3]).unwrap();
+        mem.store_word(1 << 30, 4).unwrap();
+        let snap = mem.snapshot();
+        assert_eq!(mem.diff_against_snapshot(&snap), Ok(vec![]));
+
+        mem.store_word(0x101, 5).unwrap();
+        // Same as it was, so not a change.
+        mem.store_word(0x102, 3).unwrap();
+        mem.store_word(0x5_0000, 6).unwrap();
+        mem.free_range(1 << 30, 0x1_0000);
+        assert_eq!(
+            mem.diff_against_snapshot(&snap),
+            Ok(vec![(0x101, 2, 5), (0x5_0000, 0, 6), (1 << 30, 4, 0)])
+        );
+
+        let other = memory::TreeMemory::builder()
+            .level_bits(vec![8])
+            .mem_bits(8)
+            .build()
+            .unwrap();
+        assert_eq!(
+            other.diff_against_snapshot(&snap),
+            Err(MemError::BadGeometry)
+        );
+    }
+
+    #[test]
+    fn check_scan_masked() {
+        let mut mem = memory::TreeMemory::new();
+        // A signature's top half, planted around and across leaves.
+        let planted = [0x10, 0xffff, 0x1_0000, 0x5_1234, 1 << 30];
+        for loc in planted {
+            mem.store_word(loc, 0xcafe_0000_0000_0000 | loc).unwrap();
+        }
+        mem.store_word(0x20, 0xcafe).unwrap();
+        let mask = 0xffff_0000_0000_0000;
+        let pattern = 0xcafe_0000_0000_0000;
+        assert_eq!(mem.scan_masked(0, 1 << 31, pattern, mask), planted);
+        assert_eq!(
+            mem.scan_masked(0x11, 0x5_1234 - 0x11, pattern, mask),
+            vec![0xffff, 0x1_0000]
+        );
+        assert_eq!(mem.scan_masked(0, 0, pattern, mask), vec![]);
+
+        // Looking for zero finds unmapped words too, but only in range.
+        let zeros = mem.scan_masked(0xfffe, 4, 0, u64::MAX);
+        assert_eq!(zeros, vec![0xfffe, 0x1_0001]);
+        assert_eq!(
+            mem.scan_masked(0x8_0000, 3, 0, u64::MAX),
+            vec![0x8_0000, 0x8_0001, 0x8_0002]
+        );
+        mem.set_unmapped_fill(0xcafe_0000_0000_0000);
+        assert_eq!(
+            mem.scan_masked(0x8_0000, 2, pattern, mask),
+            vec![0x8_0000, 0x8_0001]
+        );
+    }
+
+    #[test]
+    fn check_is_zero() {
+        let mut mem = memory::TreeMemory::new();
+        mem.write_words(0xfff0, &[0; 0x20]).unwrap();
+        assert!(mem.is_zero(0xfff0, 0x20));
+        // Unmapped, and not faulted in by asking.
+        assert!(mem.is_zero(1 << 40, 1 << 30));
+        assert!(!mem.is_mapped(1 << 40));
+        // Partly resident, partly not.
+        assert!(mem.is_zero(0xff00, 0x200));
+        mem.store_word(0x1_0005, 1).unwrap();
+        assert!(!mem.is_zero(0xff00, 0x200));
+        assert!(mem.is_zero(0xff00, 0x105));
+        assert!(mem.is_zero(0x1_0006, 0x100));
+        assert!(mem.is_zero(5, 0));
+
+        // With a non-zero fill, unmapped memory isn't zero.
+        mem.set_default_fill(7);
+        assert!(!mem.is_zero(1 << 40, 1));
+        // Resident words are what they are.
+        assert!(mem.is_zero(0xfff0, 0x10));
+
+        // Unmapped words are whatever reads of them give.
+        let mut mem = memory::TreeMemory::new();
+        mem.set_unmapped_fill(0xdead);
+        assert!(!mem.is_zero(0x5000, 4));
+        mem.write_words(0x5000, &[0; 4]).unwrap();
+        assert!(mem.is_zero(0x5000, 4));
+        let mem = memory::TreeMemory::builder()
+            .unmapped_policy(UnmappedPolicy::Fault)
+            .build()
+            .unwrap();
+        assert!(!mem.is_zero(0x5000, 4));
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_is_zero_default() {
+        let mut mem = VectorMemory::new();
+        mem.write(0x10, &[0, 0, 3]).await;
+        assert!(Access::is_zero(&mut mem, 0, 0x12).await);
+        assert!(!Access::is_zero(&mut mem, 0, 0x13).await);
+        assert!(Access::is_zero(&mut mem, 0x13, 10_000).await);
+        let mut tree = memory::TreeMemory::new();
+        tree.store_word(0x2_0000, 1).unwrap();
+        assert!(!Access::is_zero(&mut tree, 0, 1 << 20).await);
+    }
+
+    #[test]
+    fn check_write_hook() {
+        use std::sync::{Arc, Mutex};
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![8])
+            .mem_bits(4)
+            .build()
+            .unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let snoop = seen.clone();
+        mem.set_write_hook(Box::new(move |loc, words| {
+            snoop.lock().unwrap().push((loc, words.to_vec()))
+        }));
+        mem.store_word(0x20, 5).unwrap();
+        mem.write_words(0x1e, &[1, 2, 3]).unwrap();
+        mem.write_masked(0x20, 0xf0, 0xf0).unwrap();
+        mem.load_word(0x20).unwrap();
+        mem.set_permissions(0x40, 1, Perms::READ_ONLY);
+        assert!(mem.store_word(0x40, 1).is_err());
+        mem.free_range(0x10, 0x10);
+        mem.free_range(0x50, 0x10);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                (0x20, vec![5]),
+                (0x1e, vec![1, 2]),
+                (0x20, vec![3]),
+                (0x20, vec![0xf3]),
+                (0x10, vec![0; 0x10]),
+            ]
+        );
+        mem.clear_write_hook();
+        mem.store_word(0x30, 1).unwrap();
+        assert_eq!(seen.lock().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn check_address_remap() {
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![4, 4])
+            .mem_bits(4)
+            .build()
+            .unwrap();
+        // The top half of the 4k words mirrors the bottom half.
+        mem.set_address_remap(|addr| addr & 0x7ff);
+        mem.store_word(0x805, 1).unwrap();
+        assert_eq!(mem.load_word(0x5), Ok(1));
+        mem.write_words(0x7fe, &[2, 3, 4]).unwrap();
+        assert_eq!(mem.try_read(0xffe, 3), Ok(vec![2, 3, 4]));
+        assert_eq!(mem.load_word(0x800), Ok(4));
+        assert_eq!(mem.gather(&[0x805, 0x5, 0xfff]), Ok(vec![1, 1, 3]));
+        assert!(mem.is_mapped(0x805) && mem.is_mapped(0x5));
+        assert!(!mem.is_mapped(0x900) && !mem.is_mapped(0x100));
+        mem.write_masked(0xc05, 0xf2, 0x2).unwrap();
+        assert_eq!(mem.load_word(0x405), Ok(2));
+        // Only the bottom half is ever really touched.
+        assert!(mem.mapped_regions().iter().all(|(base, _)| *base < 0x800));
+        mem.set_permissions(0x10, 1, Perms::READ_ONLY);
+        assert_eq!(mem.store_word(0x810, 5), Err(MemError::PermissionDenied));
+
+        mem.clear_address_remap();
+        assert_eq!(mem.load_word(0x805), Ok(0));
+        assert_eq!(mem.gather(&[0x5, 0x405]), Ok(vec![1, 2]));
+    }
+
+    #[test]
+    fn check_fill_through_mirror() {
+        let mut mem = memory::TreeMemory::new();
+        mem.set_address_remap(|addr| addr & 0xffff);
+        mem.store_word(0x5, 1).unwrap();
+        // Filling the mirror fills the mirror's own words, not what it
+        // mirrors, whether the fill's the default or not.
+        mem.fill(0x10000, 0x10000, 0).unwrap();
+        assert_eq!(mem.load_word(0x10005), Ok(1));
+        mem.fill(0x10006, 1, 7).unwrap();
+        assert_eq!(mem.load_word(0x6), Ok(0));
+        mem.load_blob(0x10008 * 8, &[9]).unwrap();
+        assert_eq!(mem.load_word(0x8), Ok(0));
+        let snap = mem.snapshot_range(0x10000, 0x10);
+        mem.fill(0x10000, 0x10, 0).unwrap();
+        mem.restore_range(&snap).unwrap();
+        mem.relocate(0x10006, 0x10010, 1).unwrap();
+        mem.clear_address_remap();
+        assert_eq!(
+            mem.gather(&[0x10010, 0x10008, 0x5, 0x6, 0x10]),
+            Ok(vec![7, 9, 1, 0, 0])
+        );
+
+        mem.set_address_remap(|addr| addr & 0xffff);
+        mem.fill(0, 0x10000, 0).unwrap();
+        assert_eq!(mem.load_word(0x10005), Ok(0));
+    }
+
+    #[test]
+    fn check_snapshot_view() {
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![8, 8])
+            .mem_bits(8)
+            .default_fill(0xff)
+            .build()
+            .unwrap();
+        mem.write_words(0x1fe, &[1, 2, 3]).unwrap();
+        mem.set_permissions(0x300, 0x10, Perms::NONE);
+        let segments = mem.segment_count();
+        let (view, other) = (mem.snapshot_view(), mem.snapshot_view());
+        assert_eq!(view.peek(0x1fd, 5), Ok(vec![0xff, 1, 2, 3, 0xff]));
+        assert_eq!(other.peek_64(0x200), Ok(3));
+        // Unmapped reads are the fill, and stay unmapped.
+        assert_eq!(view.peek(0x8000, 2), Ok(vec![0xff, 0xff]));
+        assert!(!view.is_mapped(0x8000));
+        assert_eq!(view.peek(0xff_fffe, 3), Err(MemError::OutOfRange));
+        assert_eq!(view.peek_64(0x308), Err(MemError::PermissionDenied));
+        assert_eq!(view.peek(0, 0), Ok(vec![]));
+        assert_eq!(mem.segment_count(), segments);
+
+        let mut mem = memory::TreeMemory::builder()
+            .unmapped_policy(UnmappedPolicy::Fault)
+            .build()
+            .unwrap();
+        mem.store_word(0x10, 4).unwrap();
+        let view = mem.snapshot_view();
+        assert_eq!(view.peek_64(0x10), Ok(4));
+        assert_eq!(view.peek_64(1 << 40), Err(MemError::Unmapped));
+    }
+
+    #[test]
+    fn check_set_max_depth() {
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![8])
+            .mem_bits(8)
+            .build()
+            .unwrap();
+        mem.write_words(0xfffe, &[1, 2]).unwrap();
+        assert_eq!(mem.store_word(0x1_0000, 3), Err(MemError::OutOfRange));
+        mem.set_max_depth(4).unwrap();
+        assert_eq!(mem.config().level_bits, vec![8, 8, 8]);
+        assert_eq!(mem.config().max_depth, 4);
+        // What was there still is, and higher addresses work now.
+        assert_eq!(mem.try_read(0xfffe, 2), Ok(vec![1, 2]));
+        mem.store_word(0xdead_beef, 3).unwrap();
+        assert_eq!(mem.load_word(0xdead_beef), Ok(3));
+        assert_eq!(
+            mem.mapped_regions(),
+            vec![(0xff00, 0x100), (0xdead_be00, 0x100)]
+        );
+        mem.validate().unwrap();
+
+        // An empty one, and one with no tables at all.
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![])
+            .mem_bits(12)
+            .build()
+            .unwrap();
+        mem.store_word(0x123, 4).unwrap();
+        mem.set_max_depth(3).unwrap();
+        assert_eq!(mem.config().level_bits, vec![12, 12]);
+        assert_eq!(mem.load_word(0x123), Ok(4));
+        mem.store_word(0xf_ffff_ffff, 5).unwrap();
+        mem.validate().unwrap();
+        let mut empty = memory::TreeMemory::builder()
+            .level_bits(vec![16])
+            .mem_bits(16)
+            .build()
+            .unwrap();
+        // Only 32 bits left, so the second new level is trimmed to fit.
+        empty.set_max_depth(4).unwrap();
+        assert_eq!(empty.config().level_bits, vec![16, 16, 16]);
+        empty.set_max_depth(4).unwrap();
+        assert_eq!(empty.set_max_depth(5), Err(MemError::BadGeometry));
+        assert_eq!(empty.set_max_depth(3), Err(MemError::BadGeometry));
+        empty.store_word(u64::MAX, 6).unwrap();
+        assert_eq!(empty.load_word(u64::MAX), Ok(6));
+    }
+
+    #[test]
+    fn check_fragmentation() {
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![8, 8])
+            .mem_bits(8)
+            .build()
+            .unwrap();
+        assert_eq!(mem.fragmentation(), FragReport::default());
+        assert_eq!(mem.fragmentation().density(), 1.0);
+        mem.write_words(0x1000, &[1; 0x200]).unwrap();
+        assert_eq!(mem.fragmentation().runs, 1);
+        assert_eq!(mem.fragmentation().density(), 1.0);
+        // Every other leaf, from 0x1000 to 0x1800.
+        mem.free_range(0x1100, 0x100);
+        for leaf in [0x1400, 0x1600] {
+            mem.store_word(leaf, 1).unwrap();
+        }
+        assert_eq!(
+            mem.fragmentation(),
+            FragReport {
+                runs: 3,
+                mapped_words: 0x300,
+                spanned_words: 0x700,
+            }
+        );
+        assert_eq!(mem.fragmentation().density(), 3.0 / 7.0);
+    }
+
+    #[test]
+    fn check_read_64_tagged() {
+        let mut mem = memory::TreeMemory::builder()
+            .unmapped_fill(POISON)
+            .build()
+            .unwrap();
+        mem.store_word(0x1000, 5).unwrap();
+        assert_eq!(mem.read_64_tagged(0x1000), Ok((5, false)));
+        // In the same leaf, so mapped, though never written.
+        assert_eq!(mem.read_64_tagged(0x1001), Ok((0, false)));
+        assert_eq!(mem.read_64_tagged(0x100_0000), Ok((POISON, true)));
+        mem.set_permissions(0x1000, 1, Perms::NONE);
+        assert_eq!(mem.read_64_tagged(0x1000), Err(MemError::PermissionDenied));
+    }
+
+    #[test]
+    fn check_zero_all() {
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![8, 8])
+            .mem_bits(8)
+            .default_fill(0xff)
+            .build()
+            .unwrap();
+        mem.write_words(0x10fe, &[1, 2, 3, 4]).unwrap();
+        mem.store_word(0x80_0000, 5).unwrap();
+        mem.set_permissions(0x10fe, 4, Perms::READ_ONLY);
+        let (segments, resident) = (mem.segment_count(), mem.resident_words());
+        let snap = mem.snapshot();
+        mem.zero_all();
+        assert_eq!(mem.try_read(0x10fe, 4), Ok(vec![0xff; 4]));
+        assert_eq!(mem.load_word(0x80_0000), Ok(0xff));
+        assert_eq!(mem.segment_count(), segments);
+        assert_eq!(mem.resident_words(), resident);
+        assert!(mem.is_mapped(0x1100));
+        // The snapshot kept its own copy.
+        mem.restore(&snap).unwrap();
+        assert_eq!(mem.load_word(0x10ff), Ok(2));
+        mem.validate().unwrap();
+    }
+
+    #[test]
+    fn check_allocate() {
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![8, 8])
+            .mem_bits(8)
+            .build()
+            .unwrap();
+        mem.store_word(0x50, 1).unwrap();
+        let first = mem.allocate(0x180, 0x200).unwrap();
+        let second = mem.allocate(0x10, 0x40).unwrap();
+        // The first leaf was taken; the second fits in the gap below the
+        // first allocation.
+        assert_eq!(first, 0x200);
+        assert_eq!(second, 0x100);
+        assert!(second + 0x10 <= first);
+        assert!(first.is_multiple_of(0x200) && second.is_multiple_of(0x40));
+        assert!(mem.is_mapped(first) && mem.is_mapped(first + 0x17f));
+        assert!(mem.is_mapped(second));
+        assert_eq!(mem.load_word(first), Ok(0));
+        // Freed, but still allocated, so still avoided.
+        mem.free_range(first, 0x200);
+        assert_eq!(mem.allocate(0x100, 1), Some(0x380));
+
+        assert!(mem.deallocate(first));
+        assert!(!mem.deallocate(first));
+        assert!(!mem.is_mapped(first));
+        assert_eq!(mem.allocate(0x100, 0x100), Some(0x200));
+        assert!(mem.is_mapped(0x50));
+
+        assert_eq!(mem.allocate(0, 1), None);
+        assert_eq!(mem.allocate(0x100_0000, 1), None);
+        mem.set_resident_quota(mem.resident_words() + 0x100);
+        let before = mem.resident_words();
+        assert_eq!(mem.allocate(0x200, 0x100), None);
+        assert_eq!(mem.resident_words(), before);
+        mem.validate().unwrap();
+    }
+
+    #[test]
+    fn check_taint() {
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![8, 8])
+            .mem_bits(8)
+            .build()
+            .unwrap();
+        let packet: Vec<u64> = (1..=0x20).collect();
+        mem.write_words(0x10f0, &packet).unwrap();
+        mem.set_taint(0x10f0, 0x20);
+        mem.store_word(0x5000, 7).unwrap();
+        assert!(mem.is_tainted(0x10f0) && mem.is_tainted(0x110f));
+        assert!(!mem.is_tainted(0x10ef) && !mem.is_tainted(0x1110));
+
+        mem.copy_within(0x10f8, 0x4ffe, 8).unwrap();
+        assert_eq!(mem.try_read(0x4ffe, 8), Ok(packet[8..16].to_vec()));
+        assert!((0x4ffe..0x5006).all(|loc| mem.is_tainted(loc)));
+        assert!(!mem.is_tainted(0x4ffd) && !mem.is_tainted(0x5006));
+        // Copying untainted words over tainted ones clears them.
+        mem.dma_transfer(0x5006, 0x5004, 2, 1).unwrap();
+        assert!(mem.is_tainted(0x5003) && !mem.is_tainted(0x5004));
+        // As does filling, whatever the value.
+        mem.fill(0x10f0, 4, 0).unwrap();
+        mem.fill(0x1100, 4, 9).unwrap();
+        assert!(!mem.is_tainted(0x10f0) && !mem.is_tainted(0x1100));
+        assert!(mem.is_tainted(0x10f4) && mem.is_tainted(0x1104));
+
+        // Moving a region takes its taint along.
+        mem.relocate(0x1000, 0x8000, 0x200).unwrap();
+        assert!(mem.is_tainted(0x80f4) && !mem.is_tainted(0x10f4));
+        mem.clear_taint(0, u64::MAX);
+        assert!(!mem.is_tainted(0x80f4) && !mem.is_tainted(0x4ffe));
+        assert!(mem.taint.is_empty());
+    }
+
+    #[test]
+    fn check_next_mapped() {
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![8, 8])
+            .mem_bits(8)
+            .table_strategy(TableStrategy::Sparse)
+            .build()
+            .unwrap();
+        assert_eq!(mem.next_mapped(0), None);
+        for loc in [0x1_0005, 0x1_0100, 0x80_0000, 0xff_ff00] {
+            mem.store_word(loc, 1).unwrap();
+        }
+        let mut cursor = 0;
+        let mut seen = Vec::new();
+        while let Some(loc) = mem.next_mapped(cursor) {
+            seen.push(loc);
+            cursor = leaf_base(loc, 8) + 0x100;
+        }
+        assert_eq!(seen, vec![0x1_0000, 0x1_0100, 0x80_0000, 0xff_ff00]);
+        // Part way into a leaf is already mapped.
+        assert_eq!(mem.next_mapped(0x1_0142), Some(0x1_0142));
+        assert_eq!(mem.next_mapped(0x1_0200), Some(0x80_0000));
+        assert_eq!(mem.next_mapped(0xff_ffff), Some(0xff_ffff));
+        assert_eq!(mem.next_mapped(0x100_0000), None);
+        assert_eq!(mem.next_mapped(u64::MAX), None);
+
+        // The whole space in one leaf.
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![])
+            .mem_bits(8)
+            .build()
+            .unwrap();
+        mem.store_word(0x42, 1).unwrap();
+        assert_eq!(mem.next_mapped(0x10), Some(0x10));
+    }
+
+    #[test]
+    fn check_read_miss_policy() {
+        for policy in [ReadMissPolicy::NoAllocate, ReadMissPolicy::Allocate] {
+            let mut mem = memory::TreeMemory::builder()
+                .level_bits(vec![8, 8])
+                .mem_bits(8)
+                .read_miss_policy(policy)
+                .unmapped_fill(POISON)
+                .build()
+                .unwrap();
+            mem.store_word(0x10, 1).unwrap();
+            let before = mem.segment_count();
+            let snap = mem.snapshot();
+            let words = mem.try_read(0x1_00ff, 2).unwrap();
+            assert_eq!(mem.load_word(0x10), Ok(1));
+            if policy == ReadMissPolicy::Allocate {
+                // Two leaves and a table.
+                assert_eq!(words, vec![0, 0]);
+                assert_eq!(mem.segment_count(), before + 3);
+                assert!(mem.is_mapped(0x1_0100));
+                // The snapshot didn't see any of it.
+                assert_eq!(snap.leaf_count(), 1);
+            } else {
+                assert_eq!(words, vec![POISON, POISON]);
+                assert_eq!(mem.segment_count(), before);
+            }
+            mem.validate().unwrap();
+        }
+    }
+
+    #[test]
+    fn check_fault_events() {
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![8, 8])
+            .mem_bits(8)
+            .build()
+            .unwrap();
+        mem.store_word(0x10, 1).unwrap();
+        // Not on yet.
+        assert_eq!(mem.drain_fault_events(), vec![]);
+        mem.enable_fault_events();
+        mem.write_words(0x1_00fe, &[1, 2, 3, 4]).unwrap();
+        mem.store_word(0x11, 2).unwrap();
+        mem.try_read(0x5000, 4).unwrap();
+        assert_eq!(mem.drain_fault_events(), vec![(0x1_0000, 3), (0x1_0100, 3)]);
+        assert_eq!(mem.drain_fault_events(), vec![]);
+        mem.store_word(0x20_0000, 1).unwrap();
+        mem.disable_fault_events();
+        assert_eq!(mem.drain_fault_events(), vec![]);
+    }
+
+    #[test]
+    fn check_gather_scatter() {
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![8, 8])
+            .mem_bits(8)
+            .build()
+            .unwrap();
+        let mut addrs: Vec<u64> = (0..500u64).map(|i| (i * 0x9e37_79b9) & 0xff_ffff).collect();
+        addrs.extend([0x10, 0x11, 0x12, 0x10, 0x1ff, 0x200]);
+        let pairs: Vec<(u64, u64)> = addrs
+            .iter()
+            .enumerate()
+            .map(|(i, a)| (*a, i as u64))
+            .collect();
+        mem.scatter(&pairs).unwrap();
+        // 0x10 came up twice; the second one stuck.
+        assert_eq!(mem.load_word(0x10), Ok(503));
+        for (loc, val) in &pairs {
+            if *loc != 0x10 {
+                assert_eq!(mem.load_word(*loc), Ok(*val));
+            }
+        }
+
+        // Unmapped addresses, and ones in both branches of gather.
+        addrs.extend([0x55_5555, 0xab_cdef]);
+        let expected = |mem: &mut TreeMemory| -> Vec<u64> {
+            addrs
+                .iter()
+                .map(|loc| mem.load_word(*loc).unwrap())
+                .collect()
+        };
+        let want = expected(&mut mem);
+        assert_eq!(mem.gather(&addrs), Ok(want.clone()));
+        mem.set_permissions(0x55_0000, 0x100, Perms::READ_ONLY);
+        assert_eq!(mem.gather(&addrs), Ok(want));
+        mem.set_permissions(0x10, 1, Perms::NONE);
+        assert_eq!(mem.gather(&addrs), Err(MemError::PermissionDenied));
+        assert_eq!(
+            mem.scatter(&[(0x20, 1), (0x10, 2)]),
+            Err(MemError::PermissionDenied)
+        );
+        assert_eq!(mem.gather(&[]), Ok(vec![]));
+
+        // Out of range isn't some resident leaf's word, in either policy.
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![8])
+            .mem_bits(8)
+            .build()
+            .unwrap();
+        mem.store_word(0x5, 42).unwrap();
+        assert_eq!(mem.gather(&[0x5, 0x1_0005]), Err(MemError::OutOfRange));
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![8])
+            .mem_bits(8)
+            .out_of_range_policy(OutOfRangePolicy::Ignore)
+            .build()
+            .unwrap();
+        mem.store_word(0x5, 42).unwrap();
+        assert_eq!(mem.gather(&[0x5, 0x1_0005]), Ok(vec![42, 0]));
+
+        // Runs can end at the very top of the address space.
+        let mut mem = memory::TreeMemory::new();
+        mem.scatter(&[(u64::MAX, 1), (u64::MAX - 1, 2), (0, 3)])
+            .unwrap();
+        assert_eq!(mem.gather(&[u64::MAX - 1, u64::MAX, 0]), Ok(vec![2, 1, 3]));
+    }
+
+    #[test]
+    fn check_alias() {
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![8, 8])
+            .mem_bits(8)
+            .build()
+            .unwrap();
+        mem.write_words(0x1000, &[1, 2, 3]).unwrap();
+        mem.store_word(0x8000, 9).unwrap();
+        mem.alias(0x1000, 0x8000, 0x200).unwrap();
+        // What was at the window is gone; it shows the original now.
+        assert_eq!(mem.load_word(0x8000), Ok(1));
+        assert!(mem.is_mapped(0x8000));
+        assert!(!mem.is_mapped(0x8100));
+        mem.store_word(0x8002, 30).unwrap();
+        mem.write_words(0x80ff, &[4, 5]).unwrap();
+        assert_eq!(mem.try_read(0x1000, 3), Ok(vec![1, 2, 30]));
+        assert_eq!(mem.try_read(0x10ff, 2), Ok(vec![4, 5]));
+        assert_eq!(mem.read_ref(0x8100, 1), Some(&[5][..]));
+        mem.store_word(0x1001, 20).unwrap();
+        assert_eq!(mem.load_word(0x8001), Ok(20));
+        assert_eq!(mem.resident_words(), 0x200);
+
+        // Snapshots still copy on write, aliases or not.
+        let before = mem.snapshot();
+        mem.store_word(0x8000, 10).unwrap();
+        assert_eq!(mem.load_word(0x1000), Ok(10));
+        mem.restore(&before).unwrap();
+        assert_eq!(mem.load_word(0x8000), Ok(1));
+
+        // Each view keeps its own permissions.
+        mem.set_permissions(0x8000, 0x100, Perms::READ_ONLY);
+        assert_eq!(mem.store_word(0x8000, 0), Err(MemError::PermissionDenied));
+        mem.store_word(0x1000, 0).unwrap();
+        assert_eq!(mem.load_word(0x8000), Ok(0));
+
+        assert_eq!(mem.alias(0x1000, 0x8000, 0x100), Err(MemError::Conflict));
+        assert_eq!(mem.alias(0x8000, 0x1000, 0x100), Err(MemError::Conflict));
+        assert_eq!(mem.alias(0x1000, 0x1100, 0x100), Err(MemError::Conflict));
+        assert_eq!(mem.alias(0x1000, 0x9010, 0x100), Err(MemError::BadGeometry));
+        // An alias of an alias shows the original.
+        mem.alias(0x8100, 0x9000, 0x100).unwrap();
+        assert_eq!(mem.load_word(0x9000), Ok(5));
+
+        mem.unalias(0x8000, 0x200).unwrap();
+        assert_eq!(mem.load_word(0x8100), Ok(0));
+        assert_eq!(mem.load_word(0x9000), Ok(5));
+        assert!(!mem.is_mapped(0x8000));
+        mem.validate().unwrap();
+    }
+
+    #[test]
+    fn check_relocate() {
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![8, 8])
+            .mem_bits(8)
+            .build()
+            .unwrap();
+        let module: Vec<u64> = (1..=0x180).collect();
+        mem.write_words(0x1000, &module).unwrap();
+        // Its code is read-only, its data isn't.
+        mem.set_permissions(0x1000, 0x100, Perms::READ_ONLY);
+        mem.store_word(0x4000, 9).unwrap();
+        mem.relocate(0x1000, 0x3f80, 0x200).unwrap();
+
+        assert_eq!(mem.try_read(0x3f80, 0x180), Ok(module.clone()));
+        assert_eq!(mem.try_read(0x4100, 0x80), Ok(vec![0; 0x80]));
+        assert_eq!(mem.try_read(0x1000, 0x200), Ok(vec![0; 0x200]));
+        assert!(!mem.is_mapped(0x1000));
+        assert_eq!(mem.permissions(0x3f80), Perms::READ_ONLY);
+        assert_eq!(mem.permissions(0x407f), Perms::READ_ONLY);
+        assert_eq!(mem.permissions(0x4080), Perms::READ_WRITE);
+        assert_eq!(mem.permissions(0x1000), Perms::READ_WRITE);
+        mem.validate().unwrap();
+
+        // Overlapping moves work either way.
+        mem.relocate(0x3f80, 0x3f00, 0x180).unwrap();
+        assert_eq!(mem.try_read(0x3f00, 0x180), Ok(module.clone()));
+        mem.relocate(0x3f00, 0x3f40, 0x180).unwrap();
+        assert_eq!(mem.try_read(0x3f40, 0x180), Ok(module.clone()));
+        assert_eq!(mem.try_read(0x3f00, 0x40), Ok(vec![0; 0x40]));
+
+        // A failed move changes nothing.
+        let before = mem.fingerprint();
+        assert_eq!(
+            mem.relocate(0x3f40, 0xff_ff00, 0x180),
+            Err(MemError::OutOfRange)
+        );
+        mem.set_resident_quota(0x300);
+        assert_eq!(
+            mem.relocate(0x3f40, 0x8020, 0x180),
+            Err(MemError::QuotaExceeded)
+        );
+        assert_eq!(mem.fingerprint(), before);
+        assert_eq!(mem.permissions(0x3f40), Perms::READ_ONLY);
+        mem.validate().unwrap();
+    }
+
+    #[test]
+    fn check_word_ref() {
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![8])
+            .mem_bits(8)
+            .build()
+            .unwrap();
+        mem.at(0x100).set(5).unwrap();
+        assert_eq!(mem.at(0x100).get(), Ok(5));
+        // Read, then write depending on it, through the one ref.
+        let mut word = mem.at(0x100);
+        if word.get().unwrap() == 5 {
+            word.set(6).unwrap();
+        }
+        assert_eq!(word.loc(), 0x100);
+        assert_eq!(mem.load_word(0x100), Ok(6));
+        assert_eq!(mem.at(0x1_0000).get(), Err(MemError::OutOfRange));
+        assert_eq!(mem.at(0x1_0000).set(1), Err(MemError::OutOfRange));
+    }
+
+    #[test]
+    fn check_write_masked() {
+        let mut mem = memory::TreeMemory::new();
+        mem.store_word(0x10, 0x1122_3344_5566_7788).unwrap();
+        mem.write_masked(0x10, 0xff, 0xff).unwrap();
+        assert_eq!(mem.load_word(0x10), Ok(0x1122_3344_5566_77ff));
+        // Only the masked bits of val count.
+        mem.write_masked(0x10, u64::MAX, 0xa0a0_0000_0000_0001)
+            .unwrap();
+        assert_eq!(mem.load_word(0x10), Ok(0xb1a2_3344_5566_77ff));
+        mem.write_masked(0x10, 0, 0x0000_ffff_0000_0000).unwrap();
+        assert_eq!(mem.load_word(0x10), Ok(0xb1a2_0000_5566_77ff));
+        // Unmapped memory starts from the fill.
+        mem.write_masked(1 << 40, 0xabcd, 0xff00).unwrap();
+        assert_eq!(mem.load_word(1 << 40), Ok(0xab00));
+        mem.set_permissions(0x10, 1, Perms::READ_ONLY);
+        assert_eq!(
+            mem.write_masked(0x10, 1, 1),
+            Err(MemError::PermissionDenied)
+        );
+    }
+
+    #[test]
+    fn check_sparse_tables() {
+        let mut dense = memory::TreeMemory::new();
+        let mut sparse = memory::TreeMemory::builder()
+            .table_strategy(TableStrategy::Sparse)
+            .build()
+            .unwrap();
+        for mem in [&mut dense, &mut sparse] {
+            mem.store_word(0x1234_5678_9abc, 7).unwrap();
+        }
+        // One child per table instead of 4096.
+        assert_eq!(dense.footprint_report().slots(), 4 << 12);
+        assert_eq!(sparse.footprint_report().slots(), 4);
+        assert!(sparse.footprint_report().bytes() < dense.footprint_report().bytes());
+        assert_eq!(sparse.footprint_report().tables(), 4);
+
+        // Otherwise they behave the same.
+        for mem in [&mut dense, &mut sparse] {
+            mem.sync_write(0xffff, &[1, 2]);
+            mem.store_word(1 << 63, 3).unwrap();
+            assert_eq!(mem.load_word(0x1234_5678_9abc), Ok(7));
+            assert_eq!(mem.sync_read(0xfffe, 3), vec![0, 1, 2]);
+            assert!(mem.is_mapped(1 << 63));
+            assert!(!mem.is_mapped(1 << 62));
+            mem.free_range(0, 0x2_0000);
+            assert_eq!(mem.validate(), Ok(()));
+        }
+        assert_eq!(dense.mapped_regions(), sparse.mapped_regions());
+        assert_eq!(dense.fingerprint(), sparse.fingerprint());
+        assert_eq!(
+            dense.snapshot().leaves().len(),
+            sparse.snapshot().leaves().len()
+        );
+        // Freeing the two leaves dropped their entries (12 before), though
+        // the tables above them stay.
+        assert_eq!(sparse.footprint_report().slots(), 10);
+
+        // A write dropped by NoAllocate leaves nothing behind.
+        let mut mem = memory::TreeMemory::builder()
+            .table_strategy(TableStrategy::Sparse)
+            .write_miss_policy(WriteMissPolicy::NoAllocate)
+            .build()
+            .unwrap();
+        mem.store_word(0, 1).unwrap();
+        mem.store_word(0x1_0000, 0).unwrap();
+        assert_eq!(mem.footprint_report().slots(), 4);
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_write_bytes_le() {
+        let mut mem = VectorMemory::new();
+        mem.write(0x10, &[u64::MAX; 3]).await;
+        let bytes: Vec<u8> = (1..=19).collect();
+        memory::write_bytes_le(&mut mem, 0x10, &bytes).await;
+        assert_eq!(
+            mem.read(0x10, 3).await,
+            vec![
+                0x0807_0605_0403_0201,
+                0x100f_0e0d_0c0b_0a09,
+                0xffff_ffff_ff13_1211
+            ]
+        );
+        // Just a partial word, and nothing at all.
+        memory::write_bytes_le(&mut mem, 0x20, &[0xaa, 0xbb]).await;
+        memory::write_bytes_le(&mut mem, 0x21, &[]).await;
+        assert_eq!(mem.read(0x20, 2).await, vec![0xbbaa, 0]);
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_bits() {
+        let mut mem = memory::TreeMemory::new();
+        for bit in [0, 1, 31, 63] {
+            assert!(!mem.test_bit(0x40, bit).await);
+            mem.set_bit(0x40, bit).await;
+            assert!(mem.test_bit(0x40, bit).await);
+        }
+        assert_eq!(mem.read_64(0x40).await, 0x8000_0000_8000_0003);
+        mem.clear_bit(0x40, 63).await;
+        mem.clear_bit(0x40, 0).await;
+        assert!(!mem.test_bit(0x40, 63).await);
+        assert_eq!(mem.read_64(0x40).await, 0x8000_0002);
+    }
+
+    #[test]
+    fn check_write_words() {
+        let mut mem = memory::TreeMemory::new();
+        let leaf = 1u64 << mem.config.mem_bits;
+        mem.set_resident_quota(leaf);
+        // Straddles into a second leaf the quota won't give us.
+        assert_eq!(mem.write_words(leaf - 2, &[1, 2, 3, 4]), Ok(2));
+        assert_eq!(mem.read_resident(leaf - 2, 4), (vec![1, 2, 0, 0], 2));
+        assert_eq!(mem.write_words(leaf, &[1]), Err(MemError::QuotaExceeded));
+        assert_eq!(mem.write_words(leaf, &[]), Ok(0));
+        assert_eq!(mem.write_words(0, &[5, 6]), Ok(2));
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_try_write() {
+        let mut mem = memory::TreeMemory::new();
+        let leaf = 1u64 << mem.config.mem_bits;
+        mem.set_permissions(leaf, leaf, Perms::READ_ONLY);
+        assert_eq!(mem.try_write(leaf - 1, &[1, 2, 3]).await, Ok(1));
+        assert_eq!(mem.read(leaf - 1, 2).await, vec![1, 0]);
+        let mut flat = FlatMemory::<4>::new();
+        assert_eq!(flat.try_write(2, &[1, 2, 3]).await, Ok(2));
+        assert_eq!(flat.try_write(4, &[1]).await, Err(MemError::Unmapped));
+        let mut vector = VectorMemory::new();
+        assert_eq!(vector.try_write(2, &[1, 2, 3]).await, Ok(3));
+    }
+
+    #[test]
+    fn check_lazy_snapshots() {
+        let mut mem = memory::TreeMemory::new();
+        let mut snaps = Vec::new();
+        for i in 0..100u64 {
+            mem.store_word(0x10, i).unwrap();
+            mem.store_word(i << 20, i).unwrap();
+            snaps.push(mem.snapshot());
+        }
+        mem.store_word(0x10, 1000).unwrap();
+        mem.free_range(0, 1 << 16);
+        for (i, snap) in snaps.iter().enumerate() {
+            let i = i as u64;
+            assert_eq!(snap.leaf_count() as u64, i + 1);
+            mem.restore(snap).unwrap();
+            assert_eq!(mem.load_word(0x10), Ok(i));
+            assert_eq!(mem.load_word(i << 20), Ok(i));
+            assert_eq!(mem.load_word((i + 1) << 20), Ok(0));
+            assert_eq!(mem.validate(), Ok(()));
+        }
+        // Writing after a restore doesn't leak into the snapshot.
+        mem.restore(&snaps[5]).unwrap();
+        mem.store_word(0x10, 77).unwrap();
+        assert_eq!(snaps[5].leaves()[0].1[0x10], 5);
+
+        let mut other = memory::TreeMemory::builder()
+            .uniform_levels(16, 3)
+            .build()
+            .unwrap();
+        assert_eq!(other.restore(&snaps[0]), Err(MemError::BadGeometry));
+    }
+
+    #[test]
+    fn check_read_ref() {
+        let mut mem = memory::TreeMemory::new();
+        let leaf = 1u64 << mem.config.mem_bits;
+        assert_eq!(mem.read_ref(0x10, 2), None);
+        mem.sync_write(leaf - 3, &[1, 2, 3, 4]);
+        assert_eq!(mem.read_ref(leaf - 3, 3), Some(&[1u64, 2, 3][..]));
+        assert_eq!(mem.read_ref(leaf, 1), Some(&[4u64][..]));
+        // Spanning two leaves.
+        assert_eq!(mem.read_ref(leaf - 1, 2), None);
+        mem.set_permissions(leaf, 1, Perms::WRITE_ONLY);
+        assert_eq!(mem.read_ref(leaf, 1), None);
+
+        // Only reads it did are counted, so falling back to read doesn't
+        // count twice.
+        mem.enable_heatmap(0, 4 * leaf, 4);
+        assert_eq!(mem.read_ref(3 * leaf, 1), None);
+        mem.sync_read(3 * leaf, 1);
+        assert_eq!(mem.read_ref(leaf - 1, 1), Some(&[3u64][..]));
+        assert_eq!(
+            mem.heatmap(),
+            vec![(0, 1, 0), (leaf, 0, 0), (2 * leaf, 0, 0), (3 * leaf, 1, 0)]
+        );
+    }
+
+    #[test]
+    fn check_write_miss_policy() {
+        let mut mem = memory::TreeMemory::builder()
+            .write_miss_policy(WriteMissPolicy::NoAllocate)
+            .build()
+            .unwrap();
+        mem.sync_write(0x100, &[0; 64]);
+        mem.store_word(1 << 40, 0).unwrap();
+        assert_eq!(mem.resident_words(), 0);
+        assert_eq!(mem.segment_count(), 0);
+        // Anything else still allocates, and zeros to resident leaves land.
+        mem.sync_write(0x100, &[0, 5]);
+        assert_eq!(mem.resident_words(), 1 << mem.config.mem_bits);
+        mem.store_word(0x101, 0).unwrap();
+        assert_eq!(mem.load_word(0x101), Ok(0));
+
+        let mut mem = memory::TreeMemory::new();
+        mem.store_word(0x100, 0).unwrap();
+        assert_eq!(mem.resident_words(), 1 << mem.config.mem_bits);
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_content_equal() {
+        let mut tree = memory::TreeMemory::new();
+        let mut vector = VectorMemory::new();
+        for (loc, val) in [(3, 1), (5000, 2), (9999, 3)] {
+            tree.write_64(loc, val).await;
+            vector.write_64(loc, val).await;
+        }
+        let regions = [(0, 10_000), (1 << 20, 16)];
+        assert!(content_equal(&mut tree, &mut vector, &regions).await);
+        assert!(content_equal(&mut tree, &mut vector, &[]).await);
+        vector.write_64(9998, 4).await;
+        assert!(!content_equal(&mut tree, &mut vector, &regions).await);
+        // Outside the regions compared doesn't matter.
+        assert!(content_equal(&mut tree, &mut vector, &[(0, 9998)]).await);
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_read_modify_write() {
+        use std::sync::{Arc, Mutex};
+        let mut mem = memory::TreeMemory::new();
+        mem.sync_write(0x40, &[1, 2]);
+        let visits = Arc::new(Mutex::new(Vec::new()));
+        let seen = visits.clone();
+        mem.set_level_hook(Box::new(move |level| seen.lock().unwrap().push(level)));
+        let mut old = [0; 2];
+        mem.iop(0x40, &mut old, MemOp::ReadModifyWrite(Box::new(|w| w + 10)))
+            .unwrap();
+        assert_eq!(old, [1, 2]);
+        // One walk down, visiting the leaf once.
+        assert_eq!(*visits.lock().unwrap(), vec![1, 2, 3, 4, 5]);
+        mem.clear_level_hook();
+        assert_eq!(mem.sync_read(0x40, 2), vec![11, 12]);
+
+        // Through Access too, and on memories without a single-walk path.
+        let mut old = [0];
+        Access::iop(
+            &mut mem,
+            0x41,
+            &mut old,
+            MemOp::ReadModifyWrite(Box::new(|w| w * 2)),
+        )
+        .await;
+        assert_eq!((old[0], mem.read_64(0x41).await), (12, 24));
+        let mut flat = FlatMemory::<4>::new();
+        flat.write_64(1, 5).await;
+        flat.iop(1, &mut old, MemOp::ReadModifyWrite(Box::new(|w| w | 2)))
+            .await;
+        assert_eq!((old[0], flat.read_64(1).await), (5, 7));
+
+        mem.set_permissions(0x40, 1, Perms::WRITE_ONLY);
+        assert_eq!(
+            mem.iop(0x40, &mut old, MemOp::ReadModifyWrite(Box::new(|w| w))),
+            Err(MemError::PermissionDenied)
+        );
+        assert_eq!(
+            format!("{:?}", MemOp::ReadModifyWrite(Box::new(|w| w))),
+            "ReadModifyWrite(..)"
+        );
+    }
+
+    #[test]
+    fn check_degenerate_geometry() {
+        // Only a leaf: the root is it.
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![])
+            .mem_bits(8)
+            .build()
+            .unwrap();
+        assert_eq!(mem.config.max_depth, 1);
+        mem.store_word(0x42, 7).unwrap();
+        mem.sync_write(0xfe, &[1, 2]);
+        assert_eq!(mem.load_word(0x42), Ok(7));
+        assert_eq!(mem.sync_read(0xfe, 2), vec![1, 2]);
+        assert_eq!(mem.resident_words(), 0x100);
+        assert_eq!(mem.mapped_regions(), vec![(0, 0x100)]);
+        assert_eq!(mem.read_ref(0x42, 1), Some(&[7u64][..]));
+        assert_eq!(mem.validate(), Ok(()));
+        // Beyond the space is an error rather than wrapping round.
+        assert_eq!(mem.store_word(0x100, 1), Err(MemError::OutOfRange));
+        // Or a short write, if some of it fits.
+        assert_eq!(mem.write_words(0xff, &[1, 2]), Ok(1));
+        assert_eq!(mem.write_words(0x100, &[1]), Err(MemError::OutOfRange));
+        assert_eq!(mem.fill(0x100, 4, 0), Err(MemError::OutOfRange));
+        let snap = mem.snapshot();
+        mem.reset();
+        mem.restore(&snap).unwrap();
+        assert_eq!(mem.load_word(0xff), Ok(1));
+
+        // A small two level space.
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![4])
+            .mem_bits(4)
+            .build()
+            .unwrap();
+        mem.store_word(0xff, 3).unwrap();
+        mem.store_word(0x0f, 4).unwrap();
+        assert_eq!(mem.load_word(0xff), Ok(3));
+        assert_eq!(mem.mapped_regions(), vec![(0, 0x10), (0xf0, 0x10)]);
+        assert_eq!(mem.load_word(0x1ff), Err(MemError::OutOfRange));
+    }
+
+    #[test]
+    fn check_out_of_range_policy() {
+        let build = |policy| {
+            memory::TreeMemory::builder()
+                .level_bits(vec![4])
+                .mem_bits(4)
+                .out_of_range_policy(policy)
+                .build()
+                .unwrap()
+        };
+        let mut mem = build(OutOfRangePolicy::Fault);
+        assert_eq!(mem.load_word(0x100), Err(MemError::OutOfRange));
+        assert_eq!(mem.load_word(u64::MAX), Err(MemError::OutOfRange));
+        assert_eq!(mem.store_word(1 << 40, 1), Err(MemError::OutOfRange));
+        assert_eq!(mem.try_read(0xff, 2), Err(MemError::OutOfRange));
+
+        let mut mem = build(OutOfRangePolicy::Ignore);
+        mem.set_unmapped_fill(POISON);
+        mem.store_word(0xff, 3).unwrap();
+        assert_eq!(mem.load_word(0x100), Ok(POISON));
+        assert_eq!(mem.load_word(u64::MAX), Ok(POISON));
+        mem.store_word(1 << 40, 1).unwrap();
+        assert_eq!(mem.try_read(0xff, 2), Ok(vec![3, POISON]));
+        assert_eq!(mem.write_words(0xff, &[4, 5]), Ok(2));
+        assert_eq!(mem.load_word(0xff), Ok(4));
+        // The plain accessors don't panic either.
+        assert_eq!(mem.sync_read(0x1_0000, 1), vec![POISON]);
+        mem.sync_write(0x1_0000, &[1]);
+        assert_eq!(mem.resident_words(), 0x10);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn check_as_iovecs() {
+        use std::io::Write;
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![8, 8])
+            .mem_bits(8)
+            .build()
+            .unwrap();
+        let words: Vec<u64> = (1..=0x180).collect();
+        mem.write_words(0x10c0, &words[..0x40]).unwrap();
+        mem.write_words(0x1200, &words[0x140..]).unwrap();
+        // The middle leaf isn't resident; it's faulted in as zeros.
+        let iovecs = mem.as_iovecs(0x10c0, 0x180).unwrap();
+        let lens: Vec<usize> = iovecs.iter().map(|iov| iov.len()).collect();
+        assert_eq!(lens, vec![0x40 * 8, 0x100 * 8, 0x40 * 8]);
+        let mut out = Vec::new();
+        assert_eq!(out.write_vectored(&iovecs).unwrap(), 0x180 * 8);
+        let mut want = Vec::new();
+        for (i, word) in words.iter().enumerate() {
+            let word = if (0x40..0x140).contains(&i) { 0 } else { *word };
+            want.extend_from_slice(&word.to_ne_bytes());
+        }
+        assert_eq!(out, want);
+        assert!(mem.is_mapped(0x1100));
+
+        assert_eq!(mem.as_iovecs(0x10c0, 0).unwrap().len(), 0);
+        mem.set_permissions(0x1250, 1, Perms::NONE);
+        assert_eq!(
+            mem.as_iovecs(0x1200, 0x100).err(),
+            Some(MemError::PermissionDenied)
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn check_load_reader() {
+        let mut mem = memory::TreeMemory::new();
+        // Several chunks' worth, ending in a partial word.
+        let len = 2 * LOAD_CHUNK_BYTES + 13;
+        let bytes: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let mut cursor = std::io::Cursor::new(bytes.clone());
+        mem.store_word(0x1000 + len as u64 / 8, u64::MAX).unwrap();
+        assert_eq!(mem.load_reader(0x8000, &mut cursor).unwrap(), len as u64);
+        for (i, chunk) in bytes.chunks(8).enumerate() {
+            let word = mem.load_word(0x1000 + i as u64).unwrap();
+            let mut expected = [0xffu8; 8];
+            expected[..chunk.len()].copy_from_slice(chunk);
+            assert_eq!(word, u64::from_le_bytes(expected));
+        }
+
+        let mut mem = memory::TreeMemory::builder()
+            .level_bits(vec![])
+            .mem_bits(4)
+            .build()
+            .unwrap();
+        let mut cursor = std::io::Cursor::new(vec![0u8; 200]);
+        assert!(mem.load_reader(0, &mut cursor).is_err());
+    }
+
+    #[test]
+    fn check_fingerprint() {
+        let writes = [(0x10, 1), (1 << 40, 2), (0x11, 3), (1 << 63, 4)];
+        let mut forwards = memory::TreeMemory::new();
+        for (loc, val) in writes.iter() {
+            forwards.store_word(*loc, *val).unwrap();
+        }
+        let mut backwards = memory::TreeMemory::new();
+        // A leaf faulted in and then zeroed again doesn't count either.
+        backwards.store_word(1 << 50, 9).unwrap();
+        backwards.store_word(1 << 50, 0).unwrap();
+        for (loc, val) in writes.iter().rev() {
+            backwards.store_word(*loc, *val).unwrap();
+        }
+        assert_eq!(forwards.fingerprint(), backwards.fingerprint());
+        backwards.store_word(0x12, 1).unwrap();
+        assert_ne!(forwards.fingerprint(), backwards.fingerprint());
+        // Same (empty) contents, different geometry.
+        let other = memory::TreeMemory::builder()
+            .uniform_levels(16, 3)
+            .build()
+            .unwrap();
+        assert_ne!(memory::TreeMemory::new().fingerprint(), other.fingerprint());
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_is_mapped() {
+        let mut mem = memory::TreeMemory::new();
+        assert!(!mem.is_mapped(0x1234));
+        assert_eq!(mem.read_64(0x1234).await, 0);
+        assert!(!mem.is_mapped(0x1234));
+        mem.write_64(0x1234, 1).await;
+        assert!(mem.is_mapped(0x1234));
+        // The whole leaf is, but not the next one.
+        assert!(Access::is_mapped(&mem, 0));
+        assert!(!Access::is_mapped(&mem, 1 << mem.config.mem_bits));
+        assert_eq!(mem.segment_count(), 5);
+        let flat = FlatMemory::<4>::new();
+        assert!(flat.is_mapped(3) && !flat.is_mapped(4));
+    }
+
+    #[test]
+    fn check_unmapped_fill() {
+        let mut mem = memory::TreeMemory::new();
+        let leaf = 1u64 << mem.config.mem_bits;
+        mem.set_unmapped_fill(POISON);
+        assert_eq!(mem.load_word(0x10), Ok(POISON));
+        mem.store_word(0x10, 1).unwrap();
+        // The rest of the new leaf is the default fill, not poison.
+        assert_eq!(mem.sync_read(0x10, 2), vec![1, 0]);
+        assert_eq!(mem.sync_read(leaf - 1, 2), vec![0, POISON]);
+        // Partial word loads into unmapped memory don't pick poison up.
+        mem.load_blob(8 * leaf, &[0xaa]).unwrap();
+        assert_eq!(mem.load_word(leaf), Ok(0xaa));
+        mem.free_range(0, leaf);
+        assert_eq!(mem.load_word(0x10), Ok(POISON));
+        mem.clear_unmapped_fill();
+        assert_eq!(mem.load_word(0x10), Ok(0));
     }
 }
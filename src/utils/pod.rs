@@ -0,0 +1,81 @@
+use alloc::{vec, vec::Vec};
+use core::mem::{size_of, MaybeUninit};
+
+/// Plain old data: any bit pattern is a valid value and there's no
+/// padding, so it's fine to copy one to and from guest words byte for byte.
+/// (bytemuck's `Pod`, near enough, without the dependency.)
+///
+/// # Safety
+/// Implement this only for types with no padding, no pointers/references,
+/// and no invalid bit patterns - typically `#[repr(C)]` structs of other
+/// Pod types with the fields laid out so there aren't any gaps.
+pub unsafe trait Pod: Copy + Send + Sync + 'static {}
+
+macro_rules! impl_pod {
+    ($($t:ty),*) => {
+        $(unsafe impl Pod for $t {})*
+    };
+}
+
+impl_pod!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64, usize, isize);
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// How many words a T takes up, rounding up.
+pub fn pod_words<T: Pod>() -> usize {
+    size_of::<T>().div_ceil(8)
+}
+
+/// Copy val's bytes over the start of words (in host byte order, as if
+/// the words were the struct in memory), leaving any trailing bytes of the
+/// last word alone.
+pub fn pod_into_words<T: Pod>(val: &T, words: &mut [u64]) {
+    assert!(words.len() >= pod_words::<T>(), "words too short for value");
+    // Safety: T is Pod so all its bytes are initialised, and words has room.
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            val as *const T as *const u8,
+            words.as_mut_ptr() as *mut u8,
+            size_of::<T>(),
+        );
+    }
+}
+
+/// The T held in the first bytes of words.
+pub fn pod_from_words<T: Pod>(words: &[u64]) -> T {
+    assert!(words.len() >= pod_words::<T>(), "words too short for value");
+    let mut val = MaybeUninit::<T>::uninit();
+    // Safety: we fill every byte of val, and any bit pattern is a valid T.
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            words.as_ptr() as *const u8,
+            val.as_mut_ptr() as *mut u8,
+            size_of::<T>(),
+        );
+        val.assume_init()
+    }
+}
+
+/// val as freshly zeroed words.
+pub fn pod_to_words<T: Pod>(val: &T) -> Vec<u64> {
+    let mut words = vec![0; pod_words::<T>()];
+    pod_into_words(val, &mut words);
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_round_trip() {
+        assert_eq!(pod_words::<u8>(), 1);
+        assert_eq!(pod_words::<[u32; 3]>(), 2);
+        let words = pod_to_words(&[1u32, 2, 3]);
+        assert_eq!(words.len(), 2);
+        assert_eq!(pod_from_words::<[u32; 3]>(&words), [1, 2, 3]);
+        let mut words = vec![u64::MAX; 2];
+        pod_into_words(&[0u32; 3], &mut words);
+        assert_eq!(words[1] & 0xffff_ffff, 0);
+        assert_ne!(words[1], 0);
+    }
+}
@@ -0,0 +1,308 @@
+use crate::utils::memory::Access;
+use async_trait::async_trait;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+/// How a `PrefetchCache` has been doing, counted in lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrefetchStats {
+    /// Lines found in the cache, or already on their way from a prefetch.
+    pub hits: u64,
+    /// Lines we had to wait for the backend to read.
+    pub misses: u64,
+    /// Prefetches started.
+    pub prefetches: u64,
+    /// Prefetched lines which were then read - the predictions which came
+    /// true.
+    pub useful_prefetches: u64,
+}
+
+impl PrefetchStats {
+    pub fn hit_rate(&self) -> f64 {
+        ratio(self.hits, self.hits + self.misses)
+    }
+
+    /// The fraction of prefetches which were worth it.
+    pub fn accuracy(&self) -> f64 {
+        ratio(self.useful_prefetches, self.prefetches)
+    }
+}
+
+fn ratio(num: u64, den: u64) -> f64 {
+    if den == 0 {
+        0.0
+    } else {
+        num as f64 / den as f64
+    }
+}
+
+/// A read-through, write-through cache of lines in front of a slow memory,
+/// with a hardware-style prefetcher: once reads look sequential, the next
+/// line is fetched in the background, so by the time it's wanted it's
+/// already here (or on its way). Only worth it when the backend's reads
+/// really do take a while.
+///
+/// Prefetches run as tokio tasks, so this has to be used inside a runtime.
+/// Writes straight to the memory underneath (through `inner`) aren't seen.
+pub struct PrefetchCache<T: Access + Send + 'static> {
+    inner: Arc<Mutex<T>>,
+    line_words: u32,
+    capacity: usize,
+    /// line number => words.
+    lines: BTreeMap<u64, Vec<u64>>,
+    /// Cached lines, oldest first, for eviction.
+    order: VecDeque<u64>,
+    /// The line being prefetched, if any.
+    pending: Option<(u64, JoinHandle<Vec<u64>>)>,
+    /// The last line the previous read touched.
+    last_line: Option<u64>,
+    prefetch: bool,
+    stats: PrefetchStats,
+}
+
+impl<T: Access + Send + 'static> PrefetchCache<T> {
+    /// Cache up to capacity lines of line_words words each.
+    pub fn new(inner: T, line_words: u32, capacity: usize) -> PrefetchCache<T> {
+        assert!(line_words > 0 && capacity > 0, "the cache needs some room");
+        PrefetchCache {
+            inner: Arc::new(Mutex::new(inner)),
+            line_words,
+            capacity,
+            lines: BTreeMap::new(),
+            order: VecDeque::new(),
+            pending: None,
+            last_line: None,
+            prefetch: true,
+            stats: PrefetchStats::default(),
+        }
+    }
+
+    /// Turn the prefetcher on (the default) or off.
+    pub fn set_prefetch(&mut self, prefetch: bool) {
+        self.prefetch = prefetch;
+        if !prefetch {
+            self.cancel_prefetch();
+        }
+    }
+
+    pub fn stats(&self) -> PrefetchStats {
+        self.stats
+    }
+
+    /// The memory underneath, once any prefetch has let go of it.
+    pub async fn into_inner(mut self) -> T {
+        if let Some((_, task)) = self.pending.take() {
+            task.abort();
+            // Done (or cancelled) means the task has dropped its handle.
+            let _ = task.await;
+        }
+        let inner = self.inner.clone();
+        drop(self);
+        match Arc::try_unwrap(inner) {
+            Ok(mutex) => mutex.into_inner(),
+            Err(_) => unreachable!("nothing else holds the memory"),
+        }
+    }
+
+    fn line_of(&self, loc: u64) -> u64 {
+        loc / self.line_words as u64
+    }
+
+    fn cancel_prefetch(&mut self) {
+        if let Some((_, task)) = self.pending.take() {
+            task.abort();
+        }
+    }
+
+    fn insert(&mut self, line: u64, words: Vec<u64>) {
+        if self.lines.insert(line, words).is_none() {
+            self.order.push_back(line);
+            while self.order.len() > self.capacity {
+                if let Some(old) = self.order.pop_front() {
+                    self.lines.remove(&old);
+                }
+            }
+        }
+    }
+
+    /// Bring line into the cache if it isn't there, counting a hit or miss.
+    async fn fetch(&mut self, line: u64) {
+        if self.lines.contains_key(&line) {
+            self.stats.hits += 1;
+            return;
+        }
+        if let Some((_, task)) = self.pending.take_if(|(pending, _)| *pending == line) {
+            if let Ok(words) = task.await {
+                self.stats.hits += 1;
+                self.stats.useful_prefetches += 1;
+                self.insert(line, words);
+                return;
+            }
+        }
+        self.stats.misses += 1;
+        let loc = line * self.line_words as u64;
+        let words = self.inner.lock().await.read(loc, self.line_words).await;
+        self.insert(line, words);
+    }
+
+    /// Start fetching line in the background, replacing any prefetch
+    /// still outstanding.
+    fn start_prefetch(&mut self, line: u64) {
+        let already = self.lines.contains_key(&line)
+            || matches!(self.pending, Some((pending, _)) if pending == line);
+        if already {
+            return;
+        }
+        self.cancel_prefetch();
+        let inner = self.inner.clone();
+        let (loc, span) = (line * self.line_words as u64, self.line_words);
+        self.stats.prefetches += 1;
+        let task = tokio::spawn(async move { inner.lock().await.read(loc, span).await });
+        self.pending = Some((line, task));
+    }
+}
+
+#[async_trait]
+impl<T: Access + Send + 'static> Access for PrefetchCache<T> {
+    async fn read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        let mut words = Vec::with_capacity(span as usize);
+        if span == 0 {
+            return words;
+        }
+        let (first, last) = (self.line_of(loc), self.line_of(loc + (span as u64 - 1)));
+        let sequential = self
+            .last_line
+            .is_some_and(|prev| first == prev || first == prev + 1);
+        for line in first..=last {
+            self.fetch(line).await;
+            let base = line * self.line_words as u64;
+            let from = loc.max(base) - base;
+            let to = (loc + span as u64).min(base + self.line_words as u64) - base;
+            words.extend_from_slice(&self.lines[&line][from as usize..to as usize]);
+        }
+        self.last_line = Some(last);
+        if self.prefetch && sequential {
+            self.start_prefetch(last + 1);
+        }
+        words
+    }
+
+    async fn write(&mut self, loc: u64, contents: &[u64]) {
+        if contents.is_empty() {
+            return;
+        }
+        let (first, last) = (
+            self.line_of(loc),
+            self.line_of(loc + (contents.len() as u64 - 1)),
+        );
+        // A prefetch of a line we're writing could read it from before or
+        // after the write, so don't trust it.
+        if matches!(self.pending, Some((line, _)) if (first..=last).contains(&line)) {
+            self.cancel_prefetch();
+        }
+        self.inner.lock().await.write(loc, contents).await;
+        for line in first..=last {
+            let base = line * self.line_words as u64;
+            if let Some(cached) = self.lines.get_mut(&line) {
+                for (i, word) in cached.iter_mut().enumerate() {
+                    let addr = base + i as u64;
+                    if addr >= loc && addr - loc < contents.len() as u64 {
+                        *word = contents[(addr - loc) as usize];
+                    }
+                }
+            }
+        }
+    }
+
+    async fn read_64(&mut self, loc: u64) -> u64 {
+        self.read(loc, 1).await[0]
+    }
+
+    async fn write_64(&mut self, loc: u64, val: u64) {
+        self.write(loc, &[val]).await
+    }
+}
+
+impl<T: Access + Send + 'static> Drop for PrefetchCache<T> {
+    fn drop(&mut self) {
+        self.cancel_prefetch();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::memory::TreeMemory;
+    use std::time::Duration;
+
+    /// A memory which takes its time over reads, like a file or a bus.
+    struct SlowMemory(TreeMemory);
+
+    #[async_trait]
+    impl Access for SlowMemory {
+        async fn read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            self.0.read(loc, span).await
+        }
+        async fn write(&mut self, loc: u64, contents: &[u64]) {
+            self.0.write(loc, contents).await
+        }
+        async fn read_64(&mut self, loc: u64) -> u64 {
+            self.read(loc, 1).await[0]
+        }
+        async fn write_64(&mut self, loc: u64, val: u64) {
+            self.0.write_64(loc, val).await
+        }
+    }
+
+    async fn sequential(prefetch: bool) -> PrefetchStats {
+        let mut backend = TreeMemory::new();
+        let data: Vec<u64> = (0..256).collect();
+        backend.write(0, &data).await;
+        let mut cache = PrefetchCache::new(SlowMemory(backend), 8, 4);
+        cache.set_prefetch(prefetch);
+        for loc in (0..256).step_by(4) {
+            assert_eq!(cache.read(loc, 4).await, data[loc as usize..][..4]);
+            // The core does something with the words meanwhile.
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        cache.stats()
+    }
+
+    #[tokio::test]
+    async fn check_prefetch() {
+        let without = sequential(false).await;
+        let with = sequential(true).await;
+        // 32 lines, each read twice; without a prefetcher the first read
+        // of each misses.
+        assert_eq!((without.hits, without.misses), (32, 32));
+        assert_eq!(without.prefetches, 0);
+        // With one, only the first line (before the reads looked
+        // sequential) misses.
+        assert_eq!((with.hits, with.misses), (63, 1));
+        assert!(with.hit_rate() > without.hit_rate());
+        // Every prediction but the one past the end came true.
+        assert_eq!(with.prefetches, 32);
+        assert_eq!(with.useful_prefetches, 31);
+        assert!(with.accuracy() > 0.95);
+    }
+
+    #[tokio::test]
+    async fn check_prefetch_writes() {
+        let mut cache = PrefetchCache::new(TreeMemory::new(), 4, 2);
+        cache.read(0, 4).await;
+        cache.read(4, 4).await;
+        // Line 2 is being prefetched; write into it and line 1 (cached).
+        cache.write(6, &[1, 2, 3]).await;
+        assert_eq!(cache.read(4, 8).await, vec![0, 0, 1, 2, 3, 0, 0, 0]);
+        // Random reads don't set the prefetcher off.
+        let before = cache.stats().prefetches;
+        cache.read(0x100, 1).await;
+        cache.read(0x40, 1).await;
+        assert_eq!(cache.stats().prefetches, before);
+        let mut mem = cache.into_inner().await;
+        assert_eq!(mem.read(6, 3).await, vec![1, 2, 3]);
+    }
+}
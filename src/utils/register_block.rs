@@ -0,0 +1,140 @@
+use crate::utils::memory::{MemError, TreeMemory};
+use alloc::vec::Vec;
+
+/// One field of a `RegisterBlock`: width bits, shift bits up in the word
+/// offset words from the block's base.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterField {
+    pub name: &'static str,
+    pub offset: u64,
+    pub shift: u32,
+    pub width: u32,
+}
+
+impl RegisterField {
+    pub const fn new(name: &'static str, offset: u64, shift: u32, width: u32) -> RegisterField {
+        RegisterField {
+            name,
+            offset,
+            shift,
+            width,
+        }
+    }
+
+    /// The field's bits, in place in its word.
+    fn mask(&self) -> u64 {
+        let bits = if self.width >= 64 {
+            u64::MAX
+        } else {
+            (1 << self.width) - 1
+        };
+        bits << self.shift
+    }
+}
+
+/// A device's register bank laid out over a memory, so it can be got at
+/// by field name rather than by working out words and masks by hand, eg.
+/// `regs.set("enable", 1)?`. Fields narrower than a word are stored with
+/// `write_masked`, leaving their neighbours alone.
+pub struct RegisterBlock<'a> {
+    mem: &'a mut TreeMemory,
+    base: u64,
+    fields: Vec<RegisterField>,
+}
+
+impl<'a> RegisterBlock<'a> {
+    /// Panics if a field doesn't fit in a word, or two share a name.
+    pub fn new(
+        mem: &'a mut TreeMemory,
+        base: u64,
+        fields: Vec<RegisterField>,
+    ) -> RegisterBlock<'a> {
+        for (i, field) in fields.iter().enumerate() {
+            assert!(
+                field.width > 0 && field.shift + field.width <= 64,
+                "register field {} doesn't fit in a word",
+                field.name
+            );
+            assert!(
+                fields[..i].iter().all(|other| other.name != field.name),
+                "register field {} defined twice",
+                field.name
+            );
+        }
+        RegisterBlock { mem, base, fields }
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    pub fn fields(&self) -> &[RegisterField] {
+        &self.fields
+    }
+
+    /// The field's value, shifted down. Panics if there's no such field.
+    pub fn get(&mut self, name: &str) -> Result<u64, MemError> {
+        let field = self.field(name);
+        let word = self.mem.load_word(self.base.wrapping_add(field.offset))?;
+        Ok((word & field.mask()) >> field.shift)
+    }
+
+    /// Set the field to val, which is cut down to the field's width. Panics
+    /// if there's no such field.
+    pub fn set(&mut self, name: &str, val: u64) -> Result<(), MemError> {
+        let field = self.field(name);
+        let loc = self.base.wrapping_add(field.offset);
+        if field.width == 64 {
+            return self.mem.store_word(loc, val);
+        }
+        self.mem.write_masked(loc, val << field.shift, field.mask())
+    }
+
+    fn field(&self, name: &str) -> RegisterField {
+        match self.fields.iter().find(|field| field.name == name) {
+            Some(field) => *field,
+            None => panic!("no register field {name}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn check_register_block() {
+        let mut mem = TreeMemory::new();
+        mem.store_word(0x1001, 0xffff_0000_0000_ffff).unwrap();
+        let mut regs = RegisterBlock::new(
+            &mut mem,
+            0x1000,
+            vec![
+                RegisterField::new("data", 0, 0, 64),
+                RegisterField::new("enable", 1, 16, 1),
+                RegisterField::new("mode", 1, 20, 4),
+            ],
+        );
+        regs.set("data", 0x1234_5678_9abc_def0).unwrap();
+        regs.set("enable", 1).unwrap();
+        // Too wide; only the field's 4 bits go in.
+        regs.set("mode", 0x1a).unwrap();
+        assert_eq!(regs.get("data"), Ok(0x1234_5678_9abc_def0));
+        assert_eq!(regs.get("enable"), Ok(1));
+        assert_eq!(regs.get("mode"), Ok(0xa));
+        regs.set("enable", 0).unwrap();
+        assert_eq!(regs.get("enable"), Ok(0));
+        assert_eq!(regs.get("mode"), Ok(0xa));
+        // The bits around the fields are untouched.
+        assert_eq!(mem.load_word(0x1001), Ok(0xffff_0000_00a0_ffff));
+    }
+
+    #[test]
+    #[should_panic(expected = "no register field status")]
+    fn check_unknown_field() {
+        let mut mem = TreeMemory::new();
+        let mut regs = RegisterBlock::new(&mut mem, 0, vec![RegisterField::new("ctrl", 0, 0, 8)]);
+        let _ = regs.get("status");
+    }
+}
@@ -0,0 +1,223 @@
+use crate::utils::memory::TreeMemory;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Starts a `revmem1` file - a sparse memory on disk. After it comes a
+/// header of little-endian u32s (the format version, the number of levels,
+/// each level's bits, the leaf bits), then the default fill and the record
+/// count as u64s. Each record is (address, length, words), all u64s, in
+/// ascending address order, one per run of resident leaves.
+pub const REVMEM_MAGIC: &[u8; 8] = b"revmem1\0";
+pub const REVMEM_VERSION: u32 = 1;
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("revmem1: {what}"))
+}
+
+/// Pulls little-endian numbers off the front of a file's bytes.
+struct Cursor<'a>(&'a [u8]);
+
+impl Cursor<'_> {
+    fn take(&mut self, len: usize) -> io::Result<&[u8]> {
+        if self.0.len() < len {
+            return Err(invalid("file is truncated"));
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+impl TreeMemory {
+    /// Write our contents to path in the `revmem1` format. Only resident
+    /// leaves are stored, so this is about as big as the memory is.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let config = self.config();
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(REVMEM_MAGIC)?;
+        out.write_all(&REVMEM_VERSION.to_le_bytes())?;
+        out.write_all(&(config.level_bits.len() as u32).to_le_bytes())?;
+        for bits in &config.level_bits {
+            out.write_all(&bits.to_le_bytes())?;
+        }
+        out.write_all(&config.mem_bits.to_le_bytes())?;
+        out.write_all(&config.default_fill.to_le_bytes())?;
+        // Runs of adjacent leaves, as (start, leaves).
+        let snap = self.snapshot();
+        let mut runs: Vec<(u64, Vec<&[u64]>)> = Vec::new();
+        for (base, words) in snap.leaves() {
+            match runs.last_mut() {
+                Some((start, leaves))
+                    if leaves.iter().map(|l| l.len() as u64).sum::<u64>() == base - *start =>
+                {
+                    leaves.push(words)
+                }
+                _ => runs.push((base, vec![words])),
+            }
+        }
+        out.write_all(&(runs.len() as u64).to_le_bytes())?;
+        for (start, leaves) in runs {
+            let len: u64 = leaves.iter().map(|l| l.len() as u64).sum();
+            out.write_all(&start.to_le_bytes())?;
+            out.write_all(&len.to_le_bytes())?;
+            for word in leaves.into_iter().flatten() {
+                out.write_all(&word.to_le_bytes())?;
+            }
+        }
+        out.flush()
+    }
+
+    /// Read a memory back from a `revmem1` file, with the geometry and
+    /// default fill it was saved with (and default policies). Anything
+    /// which isn't a version 1 `revmem1` file is an `InvalidData` error.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<TreeMemory> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        let mut cursor = Cursor(&bytes);
+        if cursor.take(8).ok() != Some(&REVMEM_MAGIC[..]) {
+            return Err(invalid("bad magic"));
+        }
+        let version = cursor.u32()?;
+        if version != REVMEM_VERSION {
+            return Err(invalid(&format!("unsupported version {version}")));
+        }
+        let levels = cursor.u32()?;
+        if levels > 64 {
+            return Err(invalid("too many levels"));
+        }
+        let level_bits = (0..levels)
+            .map(|_| cursor.u32())
+            .collect::<io::Result<Vec<u32>>>()?;
+        let mem_bits = cursor.u32()?;
+        let default_fill = cursor.u64()?;
+        let mut mem = TreeMemory::builder()
+            .level_bits(level_bits)
+            .mem_bits(mem_bits)
+            .default_fill(default_fill)
+            .build()
+            .map_err(|_| invalid("bad geometry"))?;
+        let records = cursor.u64()?;
+        let mut last_end = None;
+        for _ in 0..records {
+            let (start, len) = (cursor.u64()?, cursor.u64()?);
+            // Check the length against what's left before allocating.
+            let bytes = len
+                .checked_mul(8)
+                .filter(|bytes| *bytes <= cursor.0.len() as u64)
+                .ok_or_else(|| invalid("file is truncated"))?;
+            if last_end.is_some_and(|end| start < end) {
+                return Err(invalid("records out of order"));
+            }
+            last_end = Some(start.saturating_add(len));
+            let words: Vec<u64> = cursor
+                .take(bytes as usize)?
+                .chunks_exact(8)
+                .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+                .collect();
+            let stored = mem
+                .write_words(start, &words)
+                .map_err(|err| invalid(&format!("record at {start:#x}: {err:?}")))?;
+            // A short count means it ran off the top of the geometry.
+            if stored != words.len() {
+                return Err(invalid("record out of range"));
+            }
+        }
+        Ok(mem)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::temp_file::TempFile;
+
+    #[test]
+    fn check_round_trip() {
+        let file = TempFile::new("round-trip.revmem");
+        let mut mem = TreeMemory::builder()
+            .level_bits(vec![8, 8])
+            .mem_bits(8)
+            .default_fill(0xff)
+            .build()
+            .unwrap();
+        mem.write_words(0x1fe, &[1, 2, 3, 4]).unwrap();
+        mem.store_word(0xab_cdef, 5).unwrap();
+        mem.save(file.path()).unwrap();
+
+        let loaded = TreeMemory::load(file.path()).unwrap();
+        assert_eq!(loaded.config().level_bits, vec![8, 8]);
+        assert_eq!(loaded.config().mem_bits, 8);
+        assert_eq!(loaded.mapped_regions(), mem.mapped_regions());
+        assert_eq!(loaded.fingerprint(), mem.fingerprint());
+        assert_eq!(
+            loaded.sparse_words(0xff),
+            vec![
+                (0x1fe, 1),
+                (0x1ff, 2),
+                (0x200, 3),
+                (0x201, 4),
+                (0xab_cdef, 5)
+            ]
+        );
+        loaded.validate().unwrap();
+
+        // Empty memories round trip too.
+        TreeMemory::new().save(file.path()).unwrap();
+        assert_eq!(
+            TreeMemory::load(file.path()).unwrap().mapped_regions(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn check_bad_header() {
+        let file = TempFile::new("bad-header.revmem");
+        let mut mem = TreeMemory::new();
+        mem.store_word(0x10, 1).unwrap();
+        mem.save(file.path()).unwrap();
+        let good = std::fs::read(file.path()).unwrap();
+        let rejected = |bytes: &[u8]| {
+            std::fs::write(file.path(), bytes).unwrap();
+            let err = TreeMemory::load(file.path()).expect_err("loaded a bad file");
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            err.to_string()
+        };
+
+        let mut bytes = good.clone();
+        bytes[0] = b'R';
+        assert!(rejected(&bytes).contains("magic"));
+        let mut bytes = good.clone();
+        bytes[8] = 2;
+        assert!(rejected(&bytes).contains("version 2"));
+        // Leaf bits making the address wider than 64.
+        let mut bytes = good.clone();
+        bytes[16 + 4 * 4] = 60;
+        assert!(rejected(&bytes).contains("geometry"));
+        assert!(rejected(&good[..good.len() - 3]).contains("truncated"));
+        // A record running off the top of a 16-bit memory.
+        TreeMemory::builder()
+            .level_bits(vec![8])
+            .mem_bits(8)
+            .build()
+            .unwrap()
+            .save(file.path())
+            .unwrap();
+        let mut bytes = std::fs::read(file.path()).unwrap();
+        let count_at = bytes.len() - 8;
+        bytes.truncate(count_at);
+        for word in [1, 0xfffe, 4, 1, 2, 3, 4] {
+            bytes.extend_from_slice(&u64::to_le_bytes(word));
+        }
+        assert!(rejected(&bytes).contains("record out of range"));
+        assert!(rejected(b"revmem").contains("magic"));
+    }
+}
@@ -0,0 +1,525 @@
+use crate::utils::memory::{bit_position, Access, MemError, MemOp};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{Mutex, MutexGuard, Notify};
+
+/// A memory several tasks can use at once: clones share the same
+/// underlying memory, and each operation holds the lock for its duration.
+/// Read-modify-write operations (bits and bit fields) happen under a
+/// single hold of the lock, so they're atomic with respect to each other.
+/// `Access::is_mapped` can't wait for the lock, so it just says yes; ask
+/// the memory itself with `lock().await.is_mapped(..)`.
+///
+/// Each clone is a separate accessor as far as `load_linked` and
+/// `store_conditional` go, so give each emulated core its own clone.
+pub struct SharedMemory<T: Access> {
+    inner: Arc<Mutex<T>>,
+    regions: Arc<RegionLocks>,
+    reservations: Arc<Reservations>,
+    /// Which accessor we are, for reservations.
+    id: u64,
+    /// Bumped by every write through any clone, under the lock.
+    writes: Arc<AtomicU64>,
+    /// Our own read cache, if on.
+    cache: Option<ReadCache>,
+}
+
+impl<T: Access> Clone for SharedMemory<T> {
+    /// The clone starts without a read cache.
+    fn clone(&self) -> Self {
+        SharedMemory {
+            inner: self.inner.clone(),
+            regions: self.regions.clone(),
+            reservations: self.reservations.clone(),
+            id: self.reservations.next_id.fetch_add(1, Ordering::Relaxed),
+            writes: self.writes.clone(),
+            cache: None,
+        }
+    }
+}
+
+/// Lines this accessor has read, good until anyone writes anything.
+struct ReadCache {
+    line_words: u32,
+    capacity: usize,
+    /// The write count the lines were read at.
+    as_of: u64,
+    /// line number => words.
+    lines: BTreeMap<u64, Vec<u64>>,
+    hits: u64,
+}
+
+impl<T: Access> Drop for SharedMemory<T> {
+    fn drop(&mut self) {
+        self.reservations.linked.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// The words linked with `load_linked`, one (at most) per accessor.
+#[derive(Default)]
+struct Reservations {
+    next_id: AtomicU64,
+    /// accessor id => linked word.
+    linked: std::sync::Mutex<BTreeMap<u64, u64>>,
+}
+
+impl Reservations {
+    /// Someone wrote len words from loc, so any links into them are off.
+    fn break_range(&self, loc: u64, len: u64) {
+        let last = loc.saturating_add(len.saturating_sub(1));
+        if len > 0 {
+            self.linked
+                .lock()
+                .unwrap()
+                .retain(|_, word| !(loc..=last).contains(word));
+        }
+    }
+}
+
+/// Address ranges locked with `lock_region`.
+#[derive(Default)]
+struct RegionLocks {
+    /// start => last word; never overlapping.
+    held: std::sync::Mutex<BTreeMap<u64, u64>>,
+    /// Poked whenever a region is released.
+    released: Notify,
+}
+
+impl RegionLocks {
+    /// Take [loc, last] if nothing held overlaps it.
+    fn try_take(&self, loc: u64, last: u64) -> bool {
+        let mut held = self.held.lock().unwrap();
+        let clash = held
+            .range(..=last)
+            .next_back()
+            .is_some_and(|(_, r_last)| *r_last >= loc);
+        if !clash {
+            held.insert(loc, last);
+        }
+        !clash
+    }
+}
+
+/// An advisory lock over a range of a `SharedMemory`, released on drop.
+pub struct RegionGuard {
+    regions: Arc<RegionLocks>,
+    loc: u64,
+}
+
+impl Drop for RegionGuard {
+    fn drop(&mut self) {
+        self.regions.held.lock().unwrap().remove(&self.loc);
+        self.regions.released.notify_waiters();
+    }
+}
+
+impl<T: Access + Send> SharedMemory<T> {
+    pub fn new(inner: T) -> SharedMemory<T> {
+        let reservations = Reservations::default();
+        SharedMemory {
+            inner: Arc::new(Mutex::new(inner)),
+            regions: Arc::new(RegionLocks::default()),
+            id: reservations.next_id.fetch_add(1, Ordering::Relaxed),
+            reservations: Arc::new(reservations),
+            writes: Arc::new(AtomicU64::new(0)),
+            cache: None,
+        }
+    }
+
+    /// Keep copies of up to capacity lines of line_words words which this
+    /// accessor has read, so reading them again needn't take the lock -
+    /// for read-heavy cores. Any write through any clone drops the lot;
+    /// writes made directly through `lock()` don't, since we can't see
+    /// them. A line is read whole, so only use this on memory where reads
+    /// don't fault.
+    pub fn enable_read_cache(&mut self, line_words: u32, capacity: usize) {
+        assert!(line_words > 0 && capacity > 0, "the cache needs some room");
+        self.cache = Some(ReadCache {
+            line_words,
+            capacity,
+            as_of: 0,
+            lines: BTreeMap::new(),
+            hits: 0,
+        });
+    }
+
+    pub fn disable_read_cache(&mut self) {
+        self.cache = None;
+    }
+
+    /// How many lines were found in the read cache rather than read.
+    pub fn read_cache_hits(&self) -> u64 {
+        self.cache.as_ref().map_or(0, |cache| cache.hits)
+    }
+
+    /// Note a write of len words from loc. Call with the lock held.
+    fn wrote(&self, loc: u64, len: u64) {
+        self.writes.fetch_add(1, Ordering::AcqRel);
+        self.reservations.break_range(loc, len);
+    }
+
+    /// Read through the cache, which must be on.
+    async fn cached_read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        let mut words = Vec::with_capacity(span as usize);
+        if span == 0 {
+            return words;
+        }
+        let cache = self.cache.as_mut().unwrap();
+        let line_words = cache.line_words as u64;
+        let writes = self.writes.load(Ordering::Acquire);
+        if cache.as_of != writes {
+            cache.lines.clear();
+            cache.as_of = writes;
+        }
+        let (first, last) = (loc / line_words, (loc + (span as u64 - 1)) / line_words);
+        for line in first..=last {
+            if cache.lines.contains_key(&line) {
+                cache.hits += 1;
+            } else {
+                let mut mem = self.inner.lock().await;
+                // Under the lock nobody can write, so this is the count
+                // the line is good for.
+                let writes = self.writes.load(Ordering::Acquire);
+                let got = mem.read(line * line_words, cache.line_words).await;
+                drop(mem);
+                if cache.as_of != writes {
+                    cache.lines.clear();
+                    cache.as_of = writes;
+                }
+                if cache.lines.len() >= cache.capacity {
+                    cache.lines.pop_first();
+                }
+                cache.lines.insert(line, got);
+            }
+            let base = line * line_words;
+            let from = loc.max(base) - base;
+            let to = (loc + span as u64).min(base + line_words) - base;
+            words.extend_from_slice(&cache.lines[&line][from as usize..to as usize]);
+        }
+        words
+    }
+
+    /// Read the word at loc and reserve it for this accessor, replacing
+    /// any reservation we had. Any write to it through a `SharedMemory`
+    /// (ours included) breaks the reservation; writes made directly
+    /// through `lock()` don't, since we can't see them.
+    pub async fn load_linked(&mut self, loc: u64) -> u64 {
+        let mut mem = self.inner.lock().await;
+        let val = mem.read_64(loc).await;
+        self.reservations
+            .linked
+            .lock()
+            .unwrap()
+            .insert(self.id, loc);
+        val
+    }
+
+    /// Store val at loc if we still hold a reservation on it from
+    /// `load_linked`, returning whether we did. Either way the
+    /// reservation is used up.
+    pub async fn store_conditional(&mut self, loc: u64, val: u64) -> bool {
+        let mut mem = self.inner.lock().await;
+        let held = self.reservations.linked.lock().unwrap().remove(&self.id) == Some(loc);
+        if held {
+            self.wrote(loc, 1);
+            mem.write_64(loc, val).await;
+        }
+        held
+    }
+
+    /// Wait until nobody holds a region overlapping span words from loc,
+    /// then hold it until the guard is dropped - for emulated cores
+    /// wanting exclusive use of a range (LL/SC, say). This is advisory:
+    /// it only excludes other lock_region callers, not plain accesses.
+    pub async fn lock_region(&self, loc: u64, span: u64) -> RegionGuard {
+        assert!(span > 0, "can't lock an empty region");
+        let last = loc.saturating_add(span - 1);
+        loop {
+            // Get the wakeup before looking, or we could miss a release
+            // between looking and waiting.
+            let released = self.regions.released.notified();
+            if self.regions.try_take(loc, last) {
+                return RegionGuard {
+                    regions: self.regions.clone(),
+                    loc,
+                };
+            }
+            released.await;
+        }
+    }
+
+    /// Hold the lock to do several things at once.
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        self.inner.lock().await
+    }
+}
+
+#[async_trait]
+impl<T: Access + Send> Access for SharedMemory<T> {
+    async fn read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        if self.cache.is_some() {
+            return self.cached_read(loc, span).await;
+        }
+        self.inner.lock().await.read(loc, span).await
+    }
+
+    async fn write(&mut self, loc: u64, contents: &[u64]) {
+        let mut mem = self.inner.lock().await;
+        self.wrote(loc, contents.len() as u64);
+        mem.write(loc, contents).await
+    }
+
+    async fn read_64(&mut self, loc: u64) -> u64 {
+        if self.cache.is_some() {
+            return self.cached_read(loc, 1).await[0];
+        }
+        self.inner.lock().await.read_64(loc).await
+    }
+
+    async fn write_64(&mut self, loc: u64, val: u64) {
+        let mut mem = self.inner.lock().await;
+        self.wrote(loc, 1);
+        mem.write_64(loc, val).await
+    }
+
+    async fn try_read_64(&mut self, loc: u64) -> Result<u64, MemError> {
+        self.inner.lock().await.try_read_64(loc).await
+    }
+
+    // Writes which fault still break reservations; that's allowed (SC
+    // may fail spuriously) and saves us caring how much was stored.
+    async fn try_write_64(&mut self, loc: u64, val: u64) -> Result<(), MemError> {
+        let mut mem = self.inner.lock().await;
+        self.wrote(loc, 1);
+        mem.try_write_64(loc, val).await
+    }
+
+    async fn try_write(&mut self, loc: u64, contents: &[u64]) -> Result<usize, MemError> {
+        let mut mem = self.inner.lock().await;
+        self.wrote(loc, contents.len() as u64);
+        mem.try_write(loc, contents).await
+    }
+
+    async fn iop(&mut self, loc: u64, iovec: &mut [u64], op: MemOp) {
+        let mut mem = self.inner.lock().await;
+        if !matches!(op, MemOp::Read) {
+            self.wrote(loc, iovec.len() as u64);
+        }
+        mem.iop(loc, iovec, op).await
+    }
+
+    async fn write_bits(&mut self, loc: u64, bit_offset: u32, bit_len: u32, val: u64) {
+        let mut mem = self.inner.lock().await;
+        let (first, offset) = bit_position(loc, bit_offset, bit_len);
+        self.wrote(first, if offset + bit_len > 64 { 2 } else { 1 });
+        mem.write_bits(loc, bit_offset, bit_len, val).await
+    }
+
+    async fn set_bit(&mut self, loc: u64, bit: u32) {
+        let mut mem = self.inner.lock().await;
+        self.wrote(loc, 1);
+        mem.set_bit(loc, bit).await
+    }
+
+    async fn clear_bit(&mut self, loc: u64, bit: u32) {
+        let mut mem = self.inner.lock().await;
+        self.wrote(loc, 1);
+        mem.clear_bit(loc, bit).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::memory::TreeMemory;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn check_concurrent_bits() {
+        let mem = SharedMemory::new(TreeMemory::new());
+        let mut tasks = Vec::new();
+        for bit in 0..64 {
+            let mut mem = mem.clone();
+            tasks.push(tokio::spawn(async move {
+                for _ in 0..50 {
+                    mem.set_bit(0x10, bit).await;
+                    mem.clear_bit(0x10, bit).await;
+                    mem.set_bit(0x10, bit).await;
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        // Any lost update would have left a bit clear.
+        let mut mem = mem;
+        assert_eq!(mem.read_64(0x10).await, u64::MAX);
+        assert!(mem.test_bit(0x10, 63).await);
+        mem.clear_bit(0x10, 63).await;
+        assert_eq!(mem.lock().await.read_64(0x10).await, u64::MAX >> 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn check_concurrent_swap() {
+        let mem = SharedMemory::new(TreeMemory::new());
+        let mut tasks = Vec::new();
+        for task in 0..8u64 {
+            let mut mem = mem.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut olds = Vec::new();
+                for i in 1..=100 {
+                    olds.push(mem.swap(0x10, task * 1000 + i).await);
+                }
+                olds
+            }));
+        }
+        let mut seen = Vec::new();
+        for task in tasks {
+            seen.extend(task.await.unwrap());
+        }
+        // Each value stored comes back out exactly once (bar the last,
+        // still stored), as it would if every swap were atomic.
+        let mut mem = mem;
+        seen.push(mem.read_64(0x10).await);
+        seen.sort_unstable();
+        let mut want: Vec<u64> = (0..8u64)
+            .flat_map(|task| (1..=100).map(move |i| task * 1000 + i))
+            .collect();
+        want.push(0);
+        want.sort_unstable();
+        assert_eq!(seen, want);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn check_region_locks() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        let mem = SharedMemory::new(TreeMemory::new());
+        let inside = Arc::new(AtomicU32::new(0));
+        let mut tasks = Vec::new();
+        for (loc, span) in [(0x100, 0x10), (0x108, 0x10), (0x10f, 1)] {
+            let (mem, inside) = (mem.clone(), inside.clone());
+            tasks.push(tokio::spawn(async move {
+                for _ in 0..20 {
+                    let _guard = mem.lock_region(loc, span).await;
+                    assert_eq!(inside.fetch_add(1, Ordering::SeqCst), 0);
+                    tokio::time::sleep(std::time::Duration::from_micros(100)).await;
+                    inside.fetch_sub(1, Ordering::SeqCst);
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        // Disjoint regions don't wait for each other.
+        let _a = mem.lock_region(0, 0x10).await;
+        let _b = mem.lock_region(0x10, 0x10).await;
+    }
+
+    #[tokio::test]
+    async fn check_read_cache() {
+        let mut a = SharedMemory::new(TreeMemory::new());
+        let mut b = a.clone();
+        a.enable_read_cache(8, 4);
+        a.write(0x10, &[1, 2, 3]).await;
+        assert_eq!(a.read(0x10, 3).await, vec![1, 2, 3]);
+        assert_eq!(a.read_64(0x11).await, 2);
+        assert_eq!(a.read_cache_hits(), 1);
+
+        // Someone else writes the line; we see it.
+        b.write_64(0x11, 5).await;
+        assert_eq!(a.read_64(0x11).await, 5);
+        assert_eq!(a.read_64(0x11).await, 5);
+        assert_eq!(a.read_cache_hits(), 2);
+        // And so do our own writes.
+        a.write_64(0x12, 6).await;
+        assert_eq!(a.read(0x0f, 4).await, vec![0, 1, 5, 6]);
+
+        // Lines in a row, more than fit.
+        let data: Vec<u64> = (0..0x40).collect();
+        b.write(0x100, &data).await;
+        assert_eq!(a.read(0x100, 0x40).await, data);
+        assert_eq!(a.read(0x138, 8).await, data[0x38..]);
+        assert_eq!(b.read_cache_hits(), 0);
+        a.disable_read_cache();
+        assert_eq!(a.read_64(0x100).await, 0);
+    }
+
+    #[tokio::test]
+    async fn check_ll_sc() {
+        let mut a = SharedMemory::new(TreeMemory::new());
+        let mut b = a.clone();
+        a.write_64(0x10, 5).await;
+
+        // Uninterrupted: fine, and the reservation is used up.
+        assert_eq!(a.load_linked(0x10).await, 5);
+        assert!(a.store_conditional(0x10, 6).await);
+        assert!(!a.store_conditional(0x10, 7).await);
+        assert_eq!(b.read_64(0x10).await, 6);
+
+        // Another accessor writes in between, even the same value.
+        assert_eq!(a.load_linked(0x10).await, 6);
+        b.write_64(0x10, 6).await;
+        assert!(!a.store_conditional(0x10, 8).await);
+        assert_eq!(b.read_64(0x10).await, 6);
+
+        // Both link; the first SC wins and breaks the other's link.
+        a.load_linked(0x10).await;
+        b.load_linked(0x10).await;
+        assert!(b.store_conditional(0x10, 9).await);
+        assert!(!a.store_conditional(0x10, 10).await);
+
+        // Writes elsewhere, reads, and SC to another word don't count.
+        a.load_linked(0x10).await;
+        b.write(0x11, &[1, 2]).await;
+        b.read(0x0, 0x20).await;
+        assert!(!a.store_conditional(0x11, 3).await);
+        a.load_linked(0x10).await;
+        b.set_bit(0x0f, 3).await;
+        assert!(a.store_conditional(0x10, 11).await);
+
+        // Bit operations and multi-word writes covering it do, though.
+        a.load_linked(0x10).await;
+        b.set_bit(0x10, 3).await;
+        assert!(!a.store_conditional(0x10, 12).await);
+        a.load_linked(0x10).await;
+        b.write(0x0f, &[0, 0, 0]).await;
+        assert!(!a.store_conditional(0x10, 12).await);
+        assert_eq!(a.read_64(0x10).await, 0);
+
+        // Bit fields break the words they really land in.
+        a.load_linked(0x11).await;
+        b.write_bits(0x10, 64, 8, 0xff).await;
+        assert!(!a.store_conditional(0x11, 13).await);
+        a.load_linked(0x11).await;
+        b.write_bits(0x10, 60, 8, 0xff).await;
+        assert!(!a.store_conditional(0x11, 13).await);
+        a.load_linked(0x10).await;
+        b.write_bits(0x10, 64, 8, 0).await;
+        assert!(a.store_conditional(0x10, 14).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn check_ll_sc_counter() {
+        let mem = SharedMemory::new(TreeMemory::new());
+        let mut tasks = Vec::new();
+        for _ in 0..8 {
+            let mut mem = mem.clone();
+            tasks.push(tokio::spawn(async move {
+                for _ in 0..100 {
+                    loop {
+                        let val = mem.load_linked(0x20).await;
+                        tokio::task::yield_now().await;
+                        if mem.store_conditional(0x20, val + 1).await {
+                            break;
+                        }
+                    }
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(mem.lock().await.read_64(0x20).await, 800);
+    }
+}
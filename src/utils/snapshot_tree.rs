@@ -0,0 +1,152 @@
+use crate::utils::memory::{MemError, MemorySnapshot, TreeMemory};
+use alloc::{vec, vec::Vec};
+
+/// Identifies a node in a `SnapshotTree`.
+pub type SnapshotId = usize;
+
+struct SnapshotNode {
+    parent: Option<SnapshotId>,
+    state: MemorySnapshot,
+}
+
+/// A tree of save-states for reverse debugging: `commit` records the
+/// memory as a child of the current node, and `checkout` jumps to any node
+/// (which is how you branch). Snapshots share structure with each other
+/// and the live memory, so each node only really costs what changed.
+pub struct SnapshotTree {
+    mem: TreeMemory,
+    nodes: Vec<SnapshotNode>,
+    head: SnapshotId,
+}
+
+impl SnapshotTree {
+    /// Start a history whose root is the memory's current state.
+    pub fn new(mem: TreeMemory) -> SnapshotTree {
+        let state = mem.snapshot();
+        SnapshotTree {
+            mem,
+            nodes: vec![SnapshotNode {
+                parent: None,
+                state,
+            }],
+            head: 0,
+        }
+    }
+
+    pub fn memory(&self) -> &TreeMemory {
+        &self.mem
+    }
+
+    /// The live memory; changes are only kept once you `commit`.
+    pub fn memory_mut(&mut self) -> &mut TreeMemory {
+        &mut self.mem
+    }
+
+    /// The node the live memory was last committed to or checked out from.
+    pub fn head(&self) -> SnapshotId {
+        self.head
+    }
+
+    pub fn parent(&self, id: SnapshotId) -> Option<SnapshotId> {
+        self.nodes.get(id).and_then(|node| node.parent)
+    }
+
+    pub fn children(&self, id: SnapshotId) -> Vec<SnapshotId> {
+        (0..self.nodes.len())
+            .filter(|child| self.nodes[*child].parent == Some(id))
+            .collect()
+    }
+
+    /// Record the live memory as a new child of head, and move head to it.
+    pub fn commit(&mut self) -> SnapshotId {
+        self.nodes.push(SnapshotNode {
+            parent: Some(self.head),
+            state: self.mem.snapshot(),
+        });
+        self.head = self.nodes.len() - 1;
+        self.head
+    }
+
+    /// The state of node `id`, or `None` if there's no such node.
+    pub fn state(&self, id: SnapshotId) -> Option<MemorySnapshot> {
+        self.nodes.get(id).map(|node| node.state.clone())
+    }
+
+    /// Restore node `id` into the live memory, discarding anything
+    /// uncommitted, and make it head. Subsequent commits branch from it.
+    pub fn checkout(&mut self, id: SnapshotId) -> Result<(), MemError> {
+        let node = self.nodes.get(id).ok_or(MemError::NoSuchSnapshot)?;
+        self.mem.restore(&node.state)?;
+        self.head = id;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::memory::SyncAccess;
+
+    #[test]
+    fn check_branches() {
+        let mut tree = SnapshotTree::new(TreeMemory::new());
+        let root = tree.head();
+        tree.memory_mut().sync_write(0x10, &[1, 2, 3]);
+        let a = tree.commit();
+        tree.memory_mut().sync_write_64(0x11, 20);
+        tree.memory_mut().sync_write_64(1 << 40, 7);
+        let a1 = tree.commit();
+        // Branch off a.
+        tree.checkout(a).unwrap();
+        assert_eq!(tree.memory_mut().sync_read_64(1 << 40), 0);
+        tree.memory_mut().sync_write_64(0x12, 30);
+        let a2 = tree.commit();
+        // And off the root.
+        tree.checkout(root).unwrap();
+        tree.memory_mut().sync_write_64(1 << 50, 9);
+        let b = tree.commit();
+
+        assert_eq!(tree.parent(a1), Some(a));
+        assert_eq!(tree.parent(a2), Some(a));
+        assert_eq!(tree.children(a), vec![a1, a2]);
+        assert_eq!(tree.children(root), vec![a, b]);
+
+        tree.checkout(a1).unwrap();
+        let mem = tree.memory_mut();
+        assert_eq!(mem.sync_read(0x10, 3), vec![1, 20, 3]);
+        assert_eq!(mem.sync_read_64(1 << 40), 7);
+        assert_eq!(mem.sync_read_64(1 << 50), 0);
+
+        tree.checkout(a2).unwrap();
+        let mem = tree.memory_mut();
+        assert_eq!(mem.sync_read(0x10, 3), vec![1, 2, 30]);
+        assert_eq!(mem.sync_read_64(1 << 40), 0);
+        assert_eq!(mem.resident_words(), 1 << 16);
+
+        tree.checkout(b).unwrap();
+        let mem = tree.memory_mut();
+        assert_eq!(mem.sync_read(0x10, 3), vec![0, 0, 0]);
+        assert_eq!(mem.sync_read_64(1 << 50), 9);
+        assert_eq!(mem.resident_words(), 1 << 16);
+
+        assert_eq!(tree.checkout(100), Err(MemError::NoSuchSnapshot));
+    }
+
+    #[test]
+    fn check_long_history() {
+        let mut tree = SnapshotTree::new(TreeMemory::new());
+        let mut ids = Vec::new();
+        for i in 0..35 {
+            tree.memory_mut().sync_write_64(i, i + 1);
+            ids.push(tree.commit());
+        }
+        for (i, id) in ids.iter().enumerate() {
+            tree.checkout(*id).unwrap();
+            let words = tree.memory_mut().sync_read(0, ids.len() as u32);
+            for (j, word) in words.iter().enumerate() {
+                let expected = if j <= i { j as u64 + 1 } else { 0 };
+                assert_eq!(*word, expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use crate::utils::memory::{Access, MemError};
+use async_trait::async_trait;
+
+/// Sends every write to two memories and reads from the first - for
+/// shadowing a live memory into a logging or snapshot backend without
+/// whoever's using it knowing. With verify on, reads read the secondary
+/// too and note any words where it disagrees, as (address, primary word,
+/// secondary word), for `mismatches`.
+///
+/// Faults come from the primary; the secondary is given whatever the
+/// primary stored.
+pub struct TeeMemory<A: Access, B: Access> {
+    primary: A,
+    secondary: B,
+    verify: bool,
+    mismatches: Vec<(u64, u64, u64)>,
+}
+
+impl<A: Access + Send, B: Access + Send> TeeMemory<A, B> {
+    pub fn new(primary: A, secondary: B) -> TeeMemory<A, B> {
+        TeeMemory {
+            primary,
+            secondary,
+            verify: false,
+            mismatches: Vec::new(),
+        }
+    }
+
+    /// Check reads against the secondary (or stop checking).
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    /// Words reads found differing, oldest first.
+    pub fn mismatches(&self) -> &[(u64, u64, u64)] {
+        &self.mismatches
+    }
+
+    pub fn clear_mismatches(&mut self) {
+        self.mismatches.clear();
+    }
+
+    pub fn primary(&self) -> &A {
+        &self.primary
+    }
+
+    pub fn secondary(&self) -> &B {
+        &self.secondary
+    }
+
+    pub fn secondary_mut(&mut self) -> &mut B {
+        &mut self.secondary
+    }
+
+    pub fn into_inner(self) -> (A, B) {
+        (self.primary, self.secondary)
+    }
+
+    async fn check(&mut self, loc: u64, words: &[u64]) {
+        if !self.verify || words.is_empty() {
+            return;
+        }
+        let shadow = self.secondary.read(loc, words.len() as u32).await;
+        for (i, (word, other)) in words.iter().zip(shadow.iter()).enumerate() {
+            if word != other {
+                self.mismatches
+                    .push((loc.wrapping_add(i as u64), *word, *other));
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<A: Access + Send, B: Access + Send> Access for TeeMemory<A, B> {
+    async fn read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        let words = self.primary.read(loc, span).await;
+        self.check(loc, &words).await;
+        words
+    }
+
+    async fn write(&mut self, loc: u64, contents: &[u64]) {
+        self.primary.write(loc, contents).await;
+        self.secondary.write(loc, contents).await
+    }
+
+    async fn read_64(&mut self, loc: u64) -> u64 {
+        let word = self.primary.read_64(loc).await;
+        self.check(loc, &[word]).await;
+        word
+    }
+
+    async fn write_64(&mut self, loc: u64, val: u64) {
+        self.primary.write_64(loc, val).await;
+        self.secondary.write_64(loc, val).await
+    }
+
+    fn is_mapped(&self, loc: u64) -> bool {
+        self.primary.is_mapped(loc)
+    }
+
+    async fn try_read_64(&mut self, loc: u64) -> Result<u64, MemError> {
+        let word = self.primary.try_read_64(loc).await?;
+        self.check(loc, &[word]).await;
+        Ok(word)
+    }
+
+    async fn try_write_64(&mut self, loc: u64, val: u64) -> Result<(), MemError> {
+        self.primary.try_write_64(loc, val).await?;
+        self.secondary.write_64(loc, val).await;
+        Ok(())
+    }
+
+    /// Only what the primary stored goes to the secondary.
+    async fn try_write(&mut self, loc: u64, contents: &[u64]) -> Result<usize, MemError> {
+        let stored = self.primary.try_write(loc, contents).await?;
+        self.secondary.write(loc, &contents[..stored]).await;
+        Ok(stored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::memory::{Perms, TreeMemory};
+    use crate::utils::vector_memory::VectorMemory;
+
+    #[tokio::test]
+    async fn check_tee() {
+        let mut mem = TeeMemory::new(TreeMemory::new(), VectorMemory::new());
+        mem.write(0x10, &[1, 2, 3]).await;
+        mem.write_64(0x20, 4).await;
+        assert_eq!(mem.read(0x10, 3).await, vec![1, 2, 3]);
+        let (mut tree, mut shadow) = mem.into_inner();
+        assert_eq!(tree.try_read(0x10, 3), Ok(vec![1, 2, 3]));
+        assert_eq!(shadow.read(0x10, 0x11).await[..3], [1, 2, 3]);
+        assert_eq!(shadow.read_64(0x20).await, 4);
+
+        // The secondary only gets what the primary stored.
+        let mut tree = TreeMemory::new();
+        let leaf = 1u64 << tree.config().mem_bits;
+        tree.set_permissions(leaf, 1, Perms::READ_ONLY);
+        let mut mem = TeeMemory::new(tree, VectorMemory::new());
+        assert_eq!(mem.try_write(leaf - 2, &[5, 6, 7]).await, Ok(2));
+        assert_eq!(
+            mem.try_write_64(leaf, 8).await,
+            Err(MemError::PermissionDenied)
+        );
+        assert_eq!(mem.secondary().len() as u64, leaf);
+    }
+
+    #[tokio::test]
+    async fn check_verify() {
+        let mut mem = TeeMemory::new(TreeMemory::new(), VectorMemory::new());
+        mem.write(0x40, &[1, 2, 3, 4]).await;
+        mem.read(0x40, 4).await;
+        // Nothing's checked until verify is on.
+        mem.secondary_mut().write_64(0x42, 9).await;
+        mem.read(0x40, 4).await;
+        assert_eq!(mem.mismatches(), &[]);
+        mem.set_verify(true);
+        assert_eq!(mem.read(0x40, 4).await, vec![1, 2, 3, 4]);
+        assert_eq!(mem.read_64(0x41).await, 2);
+        assert_eq!(mem.try_read_64(0x42).await, Ok(3));
+        assert_eq!(mem.mismatches(), &[(0x42, 3, 9), (0x42, 3, 9)]);
+        mem.clear_mismatches();
+        mem.write_64(0x42, 3).await;
+        mem.read(0x3e, 8).await;
+        assert_eq!(mem.mismatches(), &[]);
+    }
+}
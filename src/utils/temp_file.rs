@@ -0,0 +1,24 @@
+use std::path::{Path, PathBuf};
+
+/// A file in the temp directory for a test to use, gone afterwards (even
+/// if the test fails). Names are per process, so test runs don't collide.
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    /// name is the rest of the file name, extension and all.
+    pub fn new(name: &str) -> TempFile {
+        let path = std::env::temp_dir().join(format!("revproc1-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        TempFile(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
@@ -0,0 +1,165 @@
+#[cfg(feature = "std")]
+use crate::utils::memory::Access;
+use crate::utils::memory::SyncAccess;
+use alloc::{vec, vec::Vec};
+#[cfg(feature = "std")]
+use async_trait::async_trait;
+
+/// How a `VectorMemory` grows when written past its end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GrowthPolicy {
+    /// Just as far as the write needs. No slack, but writing a word at a
+    /// time past the end reallocates every time.
+    Exact,
+    /// At least double the capacity each time, so appending is amortised
+    /// O(1).
+    #[default]
+    Doubling,
+}
+
+/// A memory held in one flat `Vec`, grown on demand by writes.
+/// Fine for small, dense images (e.g. somewhere to build something before
+/// copying it into a `TreeMemory`); hopeless for sparse ones.
+/// Reads past the end return zero.
+#[derive(Debug, Default)]
+pub struct VectorMemory {
+    words: Vec<u64>,
+    growth: GrowthPolicy,
+}
+
+impl VectorMemory {
+    pub fn new() -> VectorMemory {
+        VectorMemory::with_growth(GrowthPolicy::default())
+    }
+
+    pub fn with_growth(growth: GrowthPolicy) -> VectorMemory {
+        VectorMemory {
+            words: Vec::new(),
+            growth,
+        }
+    }
+
+    pub fn growth(&self) -> GrowthPolicy {
+        self.growth
+    }
+
+    /// How many words we could back without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.words.capacity()
+    }
+
+    /// How many words are currently backed?
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+}
+
+impl SyncAccess for VectorMemory {
+    fn sync_read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        let mut result = vec![0; span as usize];
+        if loc < self.words.len() as u64 {
+            let src = &self.words[(loc as usize)..];
+            for (dst_i, src_i) in result.iter_mut().zip(src.iter()) {
+                *dst_i = *src_i;
+            }
+        }
+        result
+    }
+
+    fn sync_write(&mut self, loc: u64, contents: &[u64]) {
+        let end = loc as usize + contents.len();
+        if end > self.words.len() {
+            let more = end - self.words.len();
+            match self.growth {
+                GrowthPolicy::Exact => self.words.reserve_exact(more),
+                GrowthPolicy::Doubling => {
+                    let room = more.max(self.words.capacity());
+                    if end > self.words.capacity() {
+                        self.words.reserve_exact(room);
+                    }
+                }
+            }
+            self.words.resize(end, 0);
+        }
+        self.words[(loc as usize)..end].copy_from_slice(contents);
+    }
+}
+
+#[cfg(feature = "std")]
+#[async_trait]
+impl Access for VectorMemory {
+    async fn read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        self.sync_read(loc, span)
+    }
+
+    async fn write(&mut self, loc: u64, contents: &[u64]) {
+        self.sync_write(loc, contents)
+    }
+
+    async fn read_64(&mut self, loc: u64) -> u64 {
+        self.sync_read_64(loc)
+    }
+
+    async fn write_64(&mut self, loc: u64, val: u64) {
+        self.sync_write_64(loc, val)
+    }
+
+    /// Only up to what's been written; past that reads as zero.
+    fn is_mapped(&self, loc: u64) -> bool {
+        loc < self.words.len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_growth() {
+        let mut mem = VectorMemory::new();
+        assert!(mem.is_empty());
+        mem.sync_write(4, &[1, 2]);
+        assert_eq!(mem.len(), 6);
+        assert_eq!(mem.sync_read(3, 4), vec![0, 1, 2, 0]);
+        mem.sync_write_64(0, 9);
+        assert_eq!(mem.len(), 6);
+        assert_eq!(mem.sync_read_64(0), 9);
+    }
+
+    #[test]
+    fn check_growth_policy() {
+        let reallocations = |growth| {
+            let mut mem = VectorMemory::with_growth(growth);
+            let mut capacity = mem.capacity();
+            let mut count = 0;
+            for loc in 0..4096 {
+                mem.sync_write_64(loc, loc);
+                if mem.capacity() != capacity {
+                    capacity = mem.capacity();
+                    count += 1;
+                }
+            }
+            assert_eq!(mem.sync_read(4094, 2), vec![4094, 4095]);
+            (count, capacity)
+        };
+        assert_eq!(reallocations(GrowthPolicy::Exact), (4096, 4096));
+        // 1, 2, 4, ... 4096.
+        assert_eq!(reallocations(GrowthPolicy::Doubling), (13, 4096));
+        assert_eq!(VectorMemory::new().growth(), GrowthPolicy::Doubling);
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn check_interface() {
+        let mut mem = VectorMemory::new();
+        mem.write_64(0x10, 0x45788).await;
+        assert_eq!(mem.read_64(0x10).await, 0x45788);
+        assert_eq!(mem.read_64(0x1000).await, 0);
+        mem.write(0x0f, &[1, 2, 3]).await;
+        assert_eq!(mem.read(0x0e, 4).await, vec![0, 1, 2, 3]);
+    }
+}
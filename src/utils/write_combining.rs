@@ -0,0 +1,222 @@
+use crate::utils::memory::{Access, MemError};
+use async_trait::async_trait;
+
+/// Wraps a memory and gathers writes which carry on where the last one
+/// stopped into one burst, handed to the inner memory as a single `write`
+/// once the pattern breaks, the burst fills the window, or on `flush` - a
+/// model of a write-combining buffer. Reads which touch the burst flush it
+/// first, so they always see it.
+///
+/// Nothing is written until a flush, and a wrapper can't flush when it's
+/// dropped, so flush (or `into_inner`) when you're done. The `try_` writes
+/// flush and then go straight through, so their faults come back from the
+/// write that caused them.
+pub struct WriteCombiningBuffer<T: Access> {
+    inner: T,
+    /// Start of the burst being gathered, and its words so far.
+    start: u64,
+    burst: Vec<u64>,
+    window: usize,
+    saved: u64,
+}
+
+impl<T: Access + Send> WriteCombiningBuffer<T> {
+    /// Combine writes into bursts of up to window words.
+    pub fn new(inner: T, window: usize) -> WriteCombiningBuffer<T> {
+        assert!(window > 0, "write combining window must be at least a word");
+        WriteCombiningBuffer {
+            inner,
+            start: 0,
+            burst: Vec::with_capacity(window),
+            window,
+            saved: 0,
+        }
+    }
+
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// How many writes to the inner memory combining has saved so far.
+    pub fn writes_saved(&self) -> u64 {
+        self.saved
+    }
+
+    /// Words gathered but not yet written.
+    pub fn pending(&self) -> usize {
+        self.burst.len()
+    }
+
+    /// Write out the burst being gathered, if there is one.
+    pub async fn flush(&mut self) {
+        if !self.burst.is_empty() {
+            self.inner.write(self.start, &self.burst).await;
+            self.burst.clear();
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Flush, and hand back the inner memory.
+    pub async fn into_inner(mut self) -> T {
+        self.flush().await;
+        self.inner
+    }
+
+    fn in_burst(&self, loc: u64, len: u64) -> bool {
+        let end = self.start.saturating_add(self.burst.len() as u64);
+        len > 0 && !self.burst.is_empty() && loc < end && self.start <= loc.saturating_add(len - 1)
+    }
+
+    async fn flush_if_touched(&mut self, loc: u64, len: u64) {
+        if self.in_burst(loc, len) {
+            self.flush().await;
+        }
+    }
+
+    async fn combine(&mut self, loc: u64, contents: &[u64]) {
+        if contents.is_empty() {
+            return;
+        }
+        let follows = !self.burst.is_empty()
+            && self.start.checked_add(self.burst.len() as u64) == Some(loc)
+            && self.burst.len() + contents.len() <= self.window;
+        if follows {
+            self.burst.extend_from_slice(contents);
+            self.saved += 1;
+        } else {
+            self.flush().await;
+            if contents.len() >= self.window {
+                // No room to gather anything after it anyway.
+                self.inner.write(loc, contents).await;
+                return;
+            }
+            self.start = loc;
+            self.burst.extend_from_slice(contents);
+        }
+        if self.burst.len() == self.window {
+            self.flush().await;
+        }
+    }
+}
+
+#[async_trait]
+impl<T: Access + Send> Access for WriteCombiningBuffer<T> {
+    async fn read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        self.flush_if_touched(loc, span as u64).await;
+        self.inner.read(loc, span).await
+    }
+
+    async fn write(&mut self, loc: u64, contents: &[u64]) {
+        self.combine(loc, contents).await
+    }
+
+    async fn read_64(&mut self, loc: u64) -> u64 {
+        self.flush_if_touched(loc, 1).await;
+        self.inner.read_64(loc).await
+    }
+
+    async fn write_64(&mut self, loc: u64, val: u64) {
+        self.combine(loc, &[val]).await
+    }
+
+    /// Words waiting in the burst count, since they'll be written.
+    fn is_mapped(&self, loc: u64) -> bool {
+        self.in_burst(loc, 1) || self.inner.is_mapped(loc)
+    }
+
+    async fn try_read_64(&mut self, loc: u64) -> Result<u64, MemError> {
+        self.flush_if_touched(loc, 1).await;
+        self.inner.try_read_64(loc).await
+    }
+
+    async fn try_write_64(&mut self, loc: u64, val: u64) -> Result<(), MemError> {
+        self.flush().await;
+        self.inner.try_write_64(loc, val).await
+    }
+
+    async fn try_write(&mut self, loc: u64, contents: &[u64]) -> Result<usize, MemError> {
+        self.flush().await;
+        self.inner.try_write(loc, contents).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::memory::TreeMemory;
+
+    /// Remembers the (loc, length) of every write it's given.
+    #[derive(Default)]
+    struct Counting {
+        mem: TreeMemory,
+        writes: Vec<(u64, usize)>,
+    }
+
+    #[async_trait]
+    impl Access for Counting {
+        async fn read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+            self.mem.read(loc, span).await
+        }
+
+        async fn write(&mut self, loc: u64, contents: &[u64]) {
+            self.writes.push((loc, contents.len()));
+            self.mem.write(loc, contents).await
+        }
+
+        async fn read_64(&mut self, loc: u64) -> u64 {
+            self.mem.read_64(loc).await
+        }
+
+        async fn write_64(&mut self, loc: u64, val: u64) {
+            self.write(loc, &[val]).await
+        }
+
+        fn is_mapped(&self, loc: u64) -> bool {
+            self.mem.is_mapped(loc)
+        }
+    }
+
+    #[tokio::test]
+    async fn check_write_combining() {
+        let mut mem = WriteCombiningBuffer::new(Counting::default(), 16);
+        for loc in 0x100..0x140 {
+            mem.write_64(loc, loc).await;
+        }
+        mem.write(0x140, &[1, 2]).await;
+        mem.flush().await;
+        assert_eq!(
+            mem.inner().writes,
+            vec![
+                (0x100, 16),
+                (0x110, 16),
+                (0x120, 16),
+                (0x130, 16),
+                (0x140, 2)
+            ]
+        );
+        assert_eq!(mem.writes_saved(), 60);
+        assert_eq!(mem.read(0x13f, 3).await, vec![0x13f, 1, 2]);
+
+        // Jumping about breaks the burst, and reads see what's gathered.
+        let mut mem = WriteCombiningBuffer::new(Counting::default(), 16);
+        mem.write_64(0x10, 1).await;
+        mem.write_64(0x11, 2).await;
+        mem.write_64(0x2_0000, 3).await;
+        assert_eq!(mem.pending(), 1);
+        assert!(mem.is_mapped(0x2_0000) && !mem.is_mapped(0x2_0001));
+        assert_eq!(mem.read_64(0x12).await, 0);
+        assert_eq!(mem.pending(), 1);
+        assert_eq!(mem.read(0x1_ffff, 2).await, vec![0, 3]);
+        assert_eq!(mem.pending(), 0);
+        mem.write(0x30, &[0; 20]).await;
+        assert_eq!(mem.try_write_64(0x50, 4).await, Ok(()));
+        let inner = mem.into_inner().await;
+        assert_eq!(
+            inner.writes,
+            vec![(0x10, 2), (0x2_0000, 1), (0x30, 20), (0x50, 1)]
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use crate::utils::memory::{Access, MemError};
+use async_trait::async_trait;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// Wraps a memory and appends every write to a log file as it happens, so
+/// `replay_log` can rebuild the memory after a crash without anyone having
+/// taken a snapshot. Each record is the location, the word count, then the
+/// words, all little-endian u64s.
+///
+/// The `Access` methods can't return IO errors, so failing to append
+/// panics, as a failed write to a `TreeMemory` would.
+pub struct WriteLogMemory<T: Access> {
+    inner: T,
+    log: File,
+}
+
+impl<T: Access + Send> WriteLogMemory<T> {
+    /// Log writes to inner onto the end of the file at path, creating it if
+    /// need be.
+    pub fn new<P: AsRef<Path>>(inner: T, path: P) -> io::Result<WriteLogMemory<T>> {
+        let log = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(WriteLogMemory { inner, log })
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Make sure everything logged so far is on disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.log.sync_data()
+    }
+
+    fn append(&mut self, loc: u64, words: &[u64]) {
+        if words.is_empty() {
+            return;
+        }
+        let mut record = Vec::with_capacity(8 * (words.len() + 2));
+        for word in [loc, words.len() as u64].iter().chain(words.iter()) {
+            record.extend_from_slice(&word.to_le_bytes());
+        }
+        // One write per record, so a crash can only tear the last one.
+        self.log.write_all(&record).expect("write log failed");
+    }
+}
+
+/// Apply every write in the log at path to mem, oldest first, returning how
+/// many there were. A torn record at the end (we crashed mid-append) is
+/// ignored.
+pub async fn replay_log<P: AsRef<Path>, A: Access + Send>(
+    path: P,
+    mem: &mut A,
+) -> io::Result<usize> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    let words: Vec<u64> = bytes
+        .chunks_exact(8)
+        .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+        .collect();
+    let mut records = 0;
+    let mut rest = &words[..];
+    while let [loc, count, tail @ ..] = rest {
+        let count = *count as usize;
+        if tail.len() < count {
+            break;
+        }
+        mem.write(*loc, &tail[..count]).await;
+        records += 1;
+        rest = &tail[count..];
+    }
+    Ok(records)
+}
+
+#[async_trait]
+impl<T: Access + Send> Access for WriteLogMemory<T> {
+    async fn read(&mut self, loc: u64, span: u32) -> Vec<u64> {
+        self.inner.read(loc, span).await
+    }
+
+    async fn write(&mut self, loc: u64, contents: &[u64]) {
+        self.inner.write(loc, contents).await;
+        self.append(loc, contents);
+    }
+
+    async fn read_64(&mut self, loc: u64) -> u64 {
+        self.inner.read_64(loc).await
+    }
+
+    async fn write_64(&mut self, loc: u64, val: u64) {
+        self.inner.write_64(loc, val).await;
+        self.append(loc, &[val]);
+    }
+
+    fn is_mapped(&self, loc: u64) -> bool {
+        self.inner.is_mapped(loc)
+    }
+
+    async fn try_read_64(&mut self, loc: u64) -> Result<u64, MemError> {
+        self.inner.try_read_64(loc).await
+    }
+
+    /// Only what was stored is logged.
+    async fn try_write_64(&mut self, loc: u64, val: u64) -> Result<(), MemError> {
+        self.inner.try_write_64(loc, val).await?;
+        self.append(loc, &[val]);
+        Ok(())
+    }
+
+    async fn try_write(&mut self, loc: u64, contents: &[u64]) -> Result<usize, MemError> {
+        let stored = self.inner.try_write(loc, contents).await?;
+        self.append(loc, &contents[..stored]);
+        Ok(stored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::memory::{content_equal, Perms, TreeMemory};
+    use crate::utils::temp_file::TempFile;
+
+    #[tokio::test]
+    async fn check_replay() {
+        let log = TempFile::new("replay.log");
+        let mut tree = TreeMemory::new();
+        tree.set_permissions(0x40, 1, Perms::READ_ONLY);
+        let mut mem = WriteLogMemory::new(tree, log.path()).unwrap();
+        mem.write(0xfffe, &[1, 2, 3]).await;
+        mem.write_64(0xffff, 4).await;
+        mem.set_bit(1 << 40, 5).await;
+        assert!(mem.try_write_64(0x40, 6).await.is_err());
+        mem.write_64(0x41, 7).await;
+        mem.sync().unwrap();
+        let mut original = mem.into_inner();
+
+        let mut replayed = TreeMemory::new();
+        assert_eq!(replay_log(log.path(), &mut replayed).await.unwrap(), 4);
+        let regions = [(0, 0x2_0000), (1 << 40, 1)];
+        assert!(content_equal(&mut original, &mut replayed, &regions).await);
+        assert_eq!(original.fingerprint(), replayed.fingerprint());
+
+        // Reopening carries on at the end of the log.
+        let mut mem = WriteLogMemory::new(replayed, log.path()).unwrap();
+        mem.write_64(0x42, 8).await;
+        let mut again = TreeMemory::new();
+        assert_eq!(replay_log(log.path(), &mut again).await.unwrap(), 5);
+        assert_eq!(again.read(0x41, 2).await, vec![7, 8]);
+    }
+
+    #[tokio::test]
+    async fn check_torn_log() {
+        let log = TempFile::new("torn.log");
+        let mut mem = WriteLogMemory::new(TreeMemory::new(), log.path()).unwrap();
+        mem.write(0x10, &[1, 2]).await;
+        mem.write(0x20, &[3, 4]).await;
+        drop(mem);
+        // Crash half way through appending the second record.
+        let len = std::fs::metadata(log.path()).unwrap().len();
+        let file = OpenOptions::new().write(true).open(log.path()).unwrap();
+        file.set_len(len - 5).unwrap();
+
+        let mut mem = TreeMemory::new();
+        assert_eq!(replay_log(log.path(), &mut mem).await.unwrap(), 1);
+        assert_eq!(mem.read(0x10, 2).await, vec![1, 2]);
+        assert_eq!(mem.read(0x20, 2).await, vec![0, 0]);
+        assert!(replay_log("/nonexistent/revproc1.log", &mut mem)
+            .await
+            .is_err());
+    }
+}